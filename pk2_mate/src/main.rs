@@ -5,6 +5,7 @@ use filetime::FileTime;
 use std::path::{Path, PathBuf};
 
 use pk2::archive;
+use pk2::Key;
 
 fn main() {
     let app = App::new(crate_name!())
@@ -14,22 +15,31 @@ fn main() {
         .subcommand(extract_app())
         .subcommand(repack_app())
         .subcommand(pack_app())
-        .subcommand(list_app());
+        .subcommand(list_app())
+        .subcommand(check_app())
+        .subcommand(info_app())
+        .subcommand(probe_app())
+        .subcommand(rekey_app());
     let matches = app.get_matches();
     match matches.subcommand() {
         ("extract", Some(matches)) => extract(matches),
         ("repack", Some(matches)) => repack(matches),
         ("pack", Some(matches)) => pack(matches),
         ("list", Some(matches)) => list(matches),
+        ("check", Some(matches)) => check(matches),
+        ("info", Some(matches)) => info(matches),
+        ("probe", Some(matches)) => probe(matches),
+        ("rekey", Some(matches)) => rekey(matches),
         _ => println!("{}", matches.usage()),
     }
 }
 
 fn extract_app() -> App<'static, 'static> {
-    SubCommand::with_name("extract")
+    let app = SubCommand::with_name("extract")
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
+        .arg(salt_arg())
         .arg(
             Arg::with_name("archive")
                 .short("a")
@@ -59,33 +69,73 @@ fn extract_app() -> App<'static, 'static> {
                 .long("time")
                 .help("If passed, writes file times to the extracted files"),
         )
+        .arg(
+            Arg::with_name("salvage")
+                .long("salvage")
+                .help("If passed, skips damaged parts of the archive instead of failing"),
+        );
+    if cfg!(feature = "mmap") {
+        app.arg(Arg::with_name("mmap").long("mmap").help(
+            "If passed, reads the archive through a memory map. The archive must not be \
+             modified by other programs while extracting",
+        ))
+    } else {
+        app
+    }
 }
 
 fn extract(matches: &ArgMatches<'static>) {
-    let key = matches.value_of("key").unwrap().as_bytes();
+    let key = salted_key(matches, matches.value_of("key").unwrap());
     let archive_path = matches.value_of_os("archive").map(Path::new).unwrap();
     let out_path = matches
         .value_of_os("out")
         .map(PathBuf::from)
         .unwrap_or_else(|| archive_path.with_extension(""));
     let write_times = matches.is_present("time");
-    let archive = archive::Pk2::open(archive_path, key)
-        .unwrap_or_else(|_| panic!("failed to open archive at {:?}", archive_path));
+    if matches.is_present("salvage") {
+        let (archive, skipped) = archive::Pk2::open_salvage(archive_path, key)
+            .unwrap_or_else(|e| panic!("failed to open archive at {:?}: {}", archive_path, e));
+        for skipped in &skipped {
+            eprintln!("Skipped {:?}", skipped);
+        }
+        let folder = archive.open_directory("/").unwrap();
+        println!("Extracting {:?} to {:?}.", archive_path, out_path);
+        extract_files(folder, &out_path, write_times);
+        return;
+    }
+    #[cfg(feature = "mmap")]
+    if matches.is_present("mmap") {
+        // the user promised that the archive doesn't change while it is mapped
+        let archive = unsafe { archive::Pk2::open_mmap(archive_path, key) }
+            .unwrap_or_else(|e| panic!("failed to open archive at {:?}: {}", archive_path, e));
+        let folder = archive.open_directory("/").unwrap();
+        println!("Extracting {:?} to {:?}.", archive_path, out_path);
+        extract_files(folder, &out_path, write_times);
+        return;
+    }
+    let archive = archive::Pk2::open_read_only(archive_path, key)
+        .unwrap_or_else(|e| panic!("failed to open archive at {:?}: {}", archive_path, e));
     let folder = archive.open_directory("/").unwrap();
     println!("Extracting {:?} to {:?}.", archive_path, out_path);
     extract_files(folder, &out_path, write_times);
 }
 
-fn extract_files(folder: archive::fs::Directory<'_>, out_path: &Path, write_times: bool) {
+fn extract_files<B: pk2::ReadAt, M>(
+    folder: archive::fs::Directory<'_, B, M>,
+    out_path: &Path,
+    write_times: bool,
+) {
     use std::io::Read;
     let _ = std::fs::create_dir(out_path);
     let mut buf = Vec::new();
-    for entry in folder.entries() {
+    for entry in folder.entries().unwrap() {
         match entry {
             archive::fs::DirEntry::File(mut file) => {
-                file.read_to_end(&mut buf).unwrap();
                 let file_path = out_path.join(file.name());
-                if let Err(e) = std::fs::write(&file_path, &buf) {
+                if let Err(e) = file.read_to_end(&mut buf) {
+                    // the data of files in damaged archives may be cut off
+                    eprintln!("Failed reading file {:?}: {}", file_path, e);
+                } else if let Err(e) = std::fs::write(&file_path, &buf) {
                     eprintln!("Failed writing file at {:?}: {}", file_path, e);
                 } else if write_times {
                     if let Some(time) = file.modify_time() {
@@ -113,6 +163,7 @@ fn repack_app() -> App<'static, 'static> {
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
+        .arg(salt_arg())
         .arg(
             Arg::with_name("archive")
                 .short("a")
@@ -146,30 +197,34 @@ fn repack_app() -> App<'static, 'static> {
 }
 
 fn repack(matches: &ArgMatches<'static>) {
-    let key = matches.value_of("key").unwrap().as_bytes();
+    let key = salted_key(matches, matches.value_of("key").unwrap());
     let packkey = matches
         .value_of("packkey")
         .or_else(|| matches.value_of("key"))
-        .unwrap()
-        .as_bytes();
+        .map(|key| salted_key(matches, key))
+        .unwrap();
     let archive_path = matches.value_of_os("archive").map(Path::new).unwrap();
     let out_archive_path = matches
         .value_of_os("out")
         .map(PathBuf::from)
         .unwrap_or_else(|| archive_path.with_extension("repack.pk2"));
-    let in_archive = pk2::archive::Pk2::open(archive_path, key)
-        .unwrap_or_else(|_| panic!("failed to open archive at {:?}", archive_path));
+    let in_archive = pk2::archive::Pk2::open_read_only(archive_path, key)
+        .unwrap_or_else(|e| panic!("failed to open archive at {:?}: {}", archive_path, e));
     let mut out_archive = pk2::archive::Pk2::create_new(&out_archive_path, packkey)
-        .unwrap_or_else(|_| panic!("failed to create archive at {:?}", out_archive_path));
+        .unwrap_or_else(|e| panic!("failed to create archive at {:?}: {}", out_archive_path, e));
     let folder = in_archive.open_directory("/").unwrap();
     println!("Repacking {:?} into {:?}.", archive_path, out_archive_path);
     repack_files(&mut out_archive, folder, "/".as_ref());
 }
 
-fn repack_files(out_archive: &mut archive::Pk2, folder: archive::fs::Directory<'_>, path: &Path) {
+fn repack_files<B: pk2::ReadAt, M>(
+    out_archive: &mut archive::Pk2,
+    folder: archive::fs::Directory<'_, B, M>,
+    path: &Path,
+) {
     use std::io::{Read, Write};
     let mut buf = Vec::new();
-    for entry in folder.entries() {
+    for entry in folder.entries().unwrap() {
         match entry {
             archive::fs::DirEntry::File(mut file) => {
                 file.read_to_end(&mut buf).unwrap();
@@ -180,6 +235,7 @@ fn repack_files(out_archive: &mut archive::Pk2, folder: archive::fs::Directory<'
             }
             archive::fs::DirEntry::Directory(dir) => {
                 let path = path.join(dir.name());
+                out_archive.create_directory(&path).unwrap();
                 repack_files(out_archive, dir, &path);
             }
         }
@@ -191,6 +247,7 @@ fn pack_app() -> App<'static, 'static> {
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
+        .arg(salt_arg())
         .arg(
             Arg::with_name("directory")
                 .short("d")
@@ -217,7 +274,7 @@ fn pack_app() -> App<'static, 'static> {
 }
 
 fn pack(matches: &ArgMatches<'static>) {
-    let key = matches.value_of("key").unwrap().as_bytes();
+    let key = salted_key(matches, matches.value_of("key").unwrap());
     let input_path = matches.value_of_os("directory").map(Path::new).unwrap();
     let out_archive_path = matches
         .value_of_os("archive")
@@ -227,29 +284,28 @@ fn pack(matches: &ArgMatches<'static>) {
         return;
     }
     let mut out_archive = archive::Pk2::create_new(&out_archive_path, key)
-        .unwrap_or_else(|_| panic!("failed to create archive at {:?}", out_archive_path));
+        .unwrap_or_else(|e| panic!("failed to create archive at {:?}: {}", out_archive_path, e));
     println!("Packing {:?} into {:?}.", input_path, out_archive_path);
     pack_files(&mut out_archive, input_path, input_path);
 }
 
 fn pack_files(out_archive: &mut archive::Pk2, dir_path: &Path, base: &Path) {
-    use std::io::{Read, Write};
-    let mut buf = Vec::new();
     for entry in std::fs::read_dir(dir_path).unwrap() {
         let entry = entry.unwrap();
         let ty = entry.file_type().unwrap();
         let path = entry.path();
         if ty.is_dir() {
+            out_archive
+                .create_directory(Path::new("/").join(path.strip_prefix(base).unwrap()))
+                .unwrap();
             pack_files(out_archive, &path, base);
         } else if ty.is_file() {
             let mut file = std::fs::File::open(&path).unwrap();
-            file.read_to_end(&mut buf).unwrap();
-            out_archive
-                .create_file(Path::new("/").join(path.strip_prefix(base).unwrap()))
-                .unwrap()
-                .write_all(&buf)
+            let mut out_file = out_archive
+                .create_file_streaming(Path::new("/").join(path.strip_prefix(base).unwrap()))
                 .unwrap();
-            buf.clear();
+            std::io::copy(&mut file, &mut out_file).unwrap();
+            out_file.finish().unwrap();
         }
     }
 }
@@ -259,6 +315,7 @@ fn list_app() -> App<'static, 'static> {
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
+        .arg(salt_arg())
         .arg(
             Arg::with_name("archive")
                 .short("a")
@@ -284,17 +341,21 @@ fn list_app() -> App<'static, 'static> {
 }
 
 fn list(matches: &ArgMatches<'static>) {
-    let key = matches.value_of("key").unwrap().as_bytes();
+    let key = salted_key(matches, matches.value_of("key").unwrap());
     let archive_path = matches.value_of_os("archive").map(PathBuf::from).unwrap();
-    let archive = archive::Pk2::open(&archive_path, key)
-        .unwrap_or_else(|_| panic!("failed to open archive at {:?}", archive_path));
+    let archive = archive::Pk2::open_read_only(&archive_path, key)
+        .unwrap_or_else(|e| panic!("failed to open archive at {:?}: {}", archive_path, e));
     let folder = archive.open_directory("/").unwrap();
     list_files(folder, "/".as_ref(), 1);
 }
 
-fn list_files(folder: archive::fs::Directory, path: &Path, ident_level: usize) {
+fn list_files<B: pk2::ReadAt, M>(
+    folder: archive::fs::Directory<'_, B, M>,
+    path: &Path,
+    ident_level: usize,
+) {
     println!("{}", path.display());
-    for entry in folder.entries() {
+    for entry in folder.entries().unwrap() {
         match entry {
             archive::fs::DirEntry::File(file) => {
                 println!("{}{}", " ".repeat(ident_level), file.name());
@@ -307,3 +368,279 @@ fn list_files(folder: archive::fs::Directory, path: &Path, ident_level: usize) {
         }
     }
 }
+
+fn check_app() -> App<'static, 'static> {
+    SubCommand::with_name("check")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about(crate_description!())
+        .arg(salt_arg())
+        .arg(
+            Arg::with_name("archive")
+                .short("a")
+                .long("archive")
+                .required(true)
+                .takes_value(true)
+                .help("Sets the archive to check"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .short("k")
+                .long("key")
+                .takes_value(true)
+                .default_value("169841")
+                .help("Sets the blowfish key"),
+        )
+}
+
+fn check(matches: &ArgMatches<'static>) {
+    let key = salted_key(matches, matches.value_of("key").unwrap());
+    let archive_path = matches.value_of_os("archive").map(PathBuf::from).unwrap();
+    // only the root chain has to be intact to open the archive lazily, the
+    // check reads the rest of the index itself
+    let archive = archive::Pk2::open_read_only_with(
+        &archive_path,
+        key,
+        archive::IndexLoading::Lazy {
+            max_resident: Some(0),
+        },
+    )
+    .unwrap_or_else(|e| panic!("failed to open archive at {:?}: {}", archive_path, e));
+    let report = archive
+        .check()
+        .unwrap_or_else(|e| panic!("failed to check archive at {:?}: {}", archive_path, e));
+    if report.is_ok() {
+        println!("No problems found in {:?}.", archive_path);
+        return;
+    }
+    for finding in report.findings() {
+        println!("{}", finding);
+    }
+    println!(
+        "Found {} problem(s) in {:?}.",
+        report.findings().len(),
+        archive_path
+    );
+    std::process::exit(1);
+}
+
+fn info_app() -> App<'static, 'static> {
+    SubCommand::with_name("info")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about(crate_description!())
+        .arg(salt_arg())
+        .arg(
+            Arg::with_name("archive")
+                .short("a")
+                .long("archive")
+                .required(true)
+                .takes_value(true)
+                .help("Sets the archive to open"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .short("k")
+                .long("key")
+                .takes_value(true)
+                .default_value("169841")
+                .help("Sets the blowfish key"),
+        )
+        .arg(
+            Arg::with_name("chains")
+                .short("c")
+                .long("chains")
+                .help("If passed, shows the statistics of every chain"),
+        )
+}
+
+fn info(matches: &ArgMatches<'static>) {
+    let key = salted_key(matches, matches.value_of("key").unwrap());
+    let archive_path = matches.value_of_os("archive").map(PathBuf::from).unwrap();
+    let archive = archive::Pk2::open_read_only(&archive_path, key)
+        .unwrap_or_else(|e| panic!("failed to open archive at {:?}: {}", archive_path, e));
+    let header = archive.header().unwrap();
+    let stats = archive.stats().unwrap();
+    let percent = |bytes: u64| bytes as f64 * 100.0 / stats.stream_len.max(1) as f64;
+
+    let sig_end = header.signature.iter().position(|&b| b == 0).unwrap_or(30);
+    println!(
+        "signature:            {}",
+        String::from_utf8_lossy(&header.signature[..sig_end]).trim_end()
+    );
+    println!("version:              {:#010x}", header.version);
+    println!("encrypted:            {}", header.encrypted);
+    println!("size:                 {} bytes", stats.stream_len);
+    println!(
+        "file data:            {} bytes ({:.1}%) in {} files",
+        stats.file_data,
+        percent(stats.file_data),
+        stats.files
+    );
+    println!(
+        "index:                {} bytes ({:.1}%) in {} chains",
+        stats.index,
+        percent(stats.index),
+        stats.chains.len()
+    );
+    println!(
+        "orphaned:             {} bytes ({:.1}%)",
+        stats.orphaned,
+        percent(stats.orphaned)
+    );
+    println!("empty entries:        {}", stats.empty_entries());
+    println!("discontiguous blocks: {}", stats.discontiguous_blocks());
+    if matches.is_present("chains") {
+        println!();
+        println!(
+            "{:>12} {:>6} {:>6} {:>7}  path",
+            "chain", "blocks", "empty", "discont"
+        );
+        for chain in &stats.chains {
+            println!(
+                "{:>12} {:>6} {:>6} {:>7}  {}",
+                chain.chain.0,
+                chain.blocks,
+                chain.empty_entries,
+                chain.discontiguous_blocks,
+                chain.path.display()
+            );
+        }
+    }
+}
+
+fn probe_app() -> App<'static, 'static> {
+    SubCommand::with_name("probe")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about(crate_description!())
+        .arg(salt_arg())
+        .arg(
+            Arg::with_name("archive")
+                .short("a")
+                .long("archive")
+                .required(true)
+                .takes_value(true)
+                .help("Sets the archive to find the key of"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .short("k")
+                .long("key")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Adds a blowfish key to try before the known ones, needed for regional clients other than ISRO and VSRO"),
+        )
+}
+
+fn probe(matches: &ArgMatches<'static>) {
+    let archive_path = matches.value_of_os("archive").map(PathBuf::from).unwrap();
+    let mut candidates = matches
+        .values_of("key")
+        .into_iter()
+        .flatten()
+        .map(|key| ("given", key.as_bytes()))
+        .collect::<Vec<_>>();
+    candidates.extend_from_slice(pk2::constants::PK2_KNOWN_KEYS);
+    let (archive, key) = match archive::Pk2::open_read_only_with_any_key(
+        &archive_path,
+        candidates.iter().map(|&(_, key)| salted_key(matches, key)),
+    ) {
+        Ok(found) => found,
+        Err(pk2::Error::Key { .. }) => {
+            println!("None of the keys opens {:?}.", archive_path);
+            std::process::exit(1);
+        }
+        Err(e) => panic!("failed to open archive at {:?}: {}", archive_path, e),
+    };
+    if !archive.header().unwrap().encrypted {
+        println!("{:?} is not encrypted, any key opens it.", archive_path);
+        return;
+    }
+    let (clients, key) = candidates
+        .iter()
+        .find(|&&(_, k)| salted_key(matches, k) == key)
+        .unwrap();
+    println!("key: {} ({})", String::from_utf8_lossy(key), clients);
+}
+
+fn rekey_app() -> App<'static, 'static> {
+    SubCommand::with_name("rekey")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about(crate_description!())
+        .arg(salt_arg())
+        .arg(
+            Arg::with_name("archive")
+                .short("a")
+                .long("archive")
+                .required(true)
+                .takes_value(true)
+                .help("Sets the archive to rekey"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .short("k")
+                .long("key")
+                .takes_value(true)
+                .default_value("169841")
+                .help("Sets the current blowfish key"),
+        )
+        .arg(
+            Arg::with_name("new_key")
+                .short("n")
+                .long("new-key")
+                .required(true)
+                .takes_value(true)
+                .empty_values(true)
+                .help("Sets the new blowfish key, an empty key removes the encryption"),
+        )
+}
+
+fn rekey(matches: &ArgMatches<'static>) {
+    let key = salted_key(matches, matches.value_of("key").unwrap());
+    let new_key = matches.value_of("new_key").unwrap();
+    let archive_path = matches.value_of_os("archive").map(PathBuf::from).unwrap();
+    let mut archive = archive::Pk2::open(&archive_path, key)
+        .unwrap_or_else(|e| panic!("failed to open archive at {:?}: {}", archive_path, e));
+    archive
+        .rekey(salted_key(matches, new_key))
+        .unwrap_or_else(|e| panic!("failed to rekey archive at {:?}: {}", archive_path, e));
+    if new_key.is_empty() {
+        println!("Removed the encryption of {:?}.", archive_path);
+    } else {
+        println!("Rekeyed {:?}.", archive_path);
+    }
+}
+
+fn salt_arg() -> Arg<'static, 'static> {
+    Arg::with_name("salt")
+        .long("salt")
+        .takes_value(true)
+        .validator(|salt| parse_hex(&salt).map(drop))
+        .help("Sets the salt the blowfish key is derived with as hex, for clients not using the default one")
+}
+
+/// Derives `key` with the salt given by the salt argument, or the default one.
+fn salted_key(matches: &ArgMatches<'static>, key: impl AsRef<[u8]>) -> Key {
+    match matches.value_of("salt") {
+        Some(salt) => Key::with_salt(key, parse_hex(salt).unwrap()),
+        None => Key::with_salt(key, pk2::constants::PK2_SALT),
+    }
+}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let hex = hex.trim_start_matches("0x");
+    if !hex.len().is_multiple_of(2) || hex.len() > 112 {
+        return Err(String::from("expected at most 56 bytes of hex"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("invalid hex {:?}", hex))
+        })
+        .collect()
+}
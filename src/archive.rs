@@ -1,138 +1,430 @@
-use std::cell::RefCell;
-use std::path::{Component, Path};
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 use std::{fs as stdfs, io};
 
 use crate::constants::{
-    PK2_CHECKSUM, PK2_CURRENT_DIR_IDENT, PK2_PARENT_DIR_IDENT, PK2_ROOT_BLOCK,
+    PK2_CURRENT_DIR_IDENT, PK2_FILE_BLOCK_SIZE, PK2_PARENT_DIR_IDENT, PK2_ROOT_BLOCK,
     PK2_ROOT_BLOCK_VIRTUAL,
 };
-use crate::error::{ChainLookupError, ChainLookupResult, OpenError, OpenResult};
+use crate::error::{ChainLookupError, Error, Result};
 use crate::io::RawIo;
-use crate::Blowfish;
+use crate::{ArchiveKey, Blowfish, IndexCipher, ReadAt, WriteAt};
 
+pub mod check;
+use self::check::CheckReport;
 pub mod fs;
-use self::fs::{DirEntry, Directory, File, FileMut};
+use self::fs::{Directory, File, FileMut, FileOptions, FileWriter, Metadata};
+pub mod glob;
+use self::glob::Glob;
+pub mod stats;
+use self::stats::ArchiveStats;
+pub mod transaction;
+use self::transaction::{Journal, Transaction};
+pub mod walk;
+use self::walk::Walk;
 
-use crate::raw::block_chain::{PackBlock, PackBlockChain};
-use crate::raw::block_manager::BlockManager;
+use crate::raw::block_chain::{EntryHandle, PackBlock, PackBlockChain};
+use crate::raw::block_manager::{BlockManager, ChainSource};
+pub use crate::raw::block_manager::{IndexMemoryUsage, Skipped};
 use crate::raw::entry::*;
+use crate::raw::free_space::FreeSpaceMap;
 use crate::raw::header::PackHeader;
-use crate::raw::{ChainIndex, StreamOffset};
+use crate::raw::{BlockOffset, ChainIndex, StreamOffset};
 
-pub struct Pk2<B = stdfs::File> {
-    stream: RefCell<B>,
-    blowfish: Option<Blowfish>,
+/// Access mode of an archive that only allows reading from it.
+#[derive(Debug)]
+pub enum ReadOnly {}
+
+/// Access mode of an archive that allows reading from and writing to it.
+#[derive(Debug)]
+pub enum ReadWrite {}
+
+/// How the index of an archive is read when opening it.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum IndexLoading {
+    /// Reads and decrypts every chain of the index upfront.
+    #[default]
+    Eager,
+    /// Reads and decrypts chains the first time a lookup touches them, which
+    /// makes opening large archives to access a few files a lot cheaper.
+    ///
+    /// If `max_resident` is set, the least recently used chains are evicted
+    /// whenever loading another chain exceeds that many. Open files and
+    /// directories keep their chain alive even when it is evicted, it is
+    /// simply read again by the next lookup that needs it. Operations that
+    /// need the entire index, like [`Pk2::compact`], load it all regardless
+    /// of the limit, [`Pk2::trim_index_cache`] evicts the excess again.
+    ///
+    /// Writable archives opened lazily don't know about unused regions in the
+    /// stream, so new data is appended until space is freed by deleting or
+    /// shrinking files.
+    Lazy { max_resident: Option<usize> },
+}
+
+/// A pk2 archive backed by the stream `B`. The access mode `M` is either
+/// [`ReadWrite`] or [`ReadOnly`], the latter only exposing the reading parts
+/// of the api. The index is encrypted with the cipher `C`, see
+/// [`IndexCipher`].
+pub struct Pk2<B = stdfs::File, M = ReadWrite, C = Blowfish> {
+    stream: B,
+    cipher: Option<C>,
     block_manager: BlockManager,
+    free_space: FreeSpaceMap,
+    // where the journal of transactions is kept, if the archive was opened
+    // from a path
+    journal: Option<PathBuf>,
+    mode: PhantomData<M>,
 }
 
-impl Pk2<stdfs::File> {
-    pub fn create_new<P: AsRef<Path>, K: AsRef<[u8]>>(path: P, key: K) -> OpenResult<Self> {
+impl<C: IndexCipher> Pk2<stdfs::File, ReadWrite, C> {
+    pub fn create_new<P: AsRef<Path>, K: ArchiveKey<C>>(path: P, key: K) -> Result<Self> {
         let file = stdfs::OpenOptions::new()
             .create_new(true)
             .write(true)
             .read(true)
             .open(path.as_ref())?;
-        Self::_create_impl(file, key)
+        let mut this = Self::_create_impl(file, key)?;
+        this.journal = Some(transaction::journal_path(path.as_ref()));
+        Ok(this)
     }
 
-    pub fn open<P: AsRef<Path>, K: AsRef<[u8]>>(path: P, key: K) -> OpenResult<Self> {
-        let file = stdfs::OpenOptions::new()
+    pub fn open<P: AsRef<Path>, K: ArchiveKey<C>>(path: P, key: K) -> Result<Self> {
+        Self::open_with(path, key, IndexLoading::Eager)
+    }
+
+    pub fn open_with<P: AsRef<Path>, K: ArchiveKey<C>>(
+        path: P,
+        key: K,
+        loading: IndexLoading,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = stdfs::OpenOptions::new()
+            .write(true)
+            .read(true)
+            .open(path)?;
+        let journal = transaction::journal_path(path);
+        transaction::recover(&mut file, &journal)?;
+        let mut this = Self::_open_in_impl(file, &key, loading)?;
+        this.journal = Some(journal);
+        Ok(this)
+    }
+
+    /// Opens the archive at `path` with the first of the `candidates` that
+    /// is the archive's key, returning the key alongside the archive. See
+    /// [`PK2_KNOWN_KEYS`] for the keys of official clients.
+    ///
+    /// [`PK2_KNOWN_KEYS`]: crate::constants::PK2_KNOWN_KEYS
+    pub fn open_with_any_key<P, I, K>(path: P, candidates: I) -> Result<(Self, K)>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = K>,
+        K: ArchiveKey<C>,
+    {
+        let path = path.as_ref();
+        let mut file = stdfs::OpenOptions::new()
             .write(true)
             .read(true)
             .open(path)?;
-        Self::_open_in_impl(file, key)
+        let journal = transaction::journal_path(path);
+        transaction::recover(&mut file, &journal)?;
+        let key = Self::probe_key(&file, candidates)?;
+        let mut this = Self::_open_in_impl(file, &key, IndexLoading::Eager)?;
+        this.journal = Some(journal);
+        Ok((this, key))
+    }
+}
+
+impl<C: IndexCipher> Pk2<stdfs::File, ReadOnly, C> {
+    pub fn open_read_only<P: AsRef<Path>, K: ArchiveKey<C>>(path: P, key: K) -> Result<Self> {
+        Self::open_read_only_with(path, key, IndexLoading::Eager)
+    }
+
+    pub fn open_read_only_with<P: AsRef<Path>, K: ArchiveKey<C>>(
+        path: P,
+        key: K,
+        loading: IndexLoading,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        transaction::ensure_recovered(&transaction::journal_path(path))?;
+        let file = stdfs::OpenOptions::new().read(true).open(path)?;
+        Self::_open_in_impl(file, &key, loading)
     }
 
-    pub fn open_sorted<P: AsRef<Path>, K: AsRef<[u8]>>(path: P, key: K) -> OpenResult<Self> {
+    /// Opens the archive at `path` read-only with the first of the
+    /// `candidates` that is the archive's key, see
+    /// [`Pk2::open_with_any_key`].
+    pub fn open_read_only_with_any_key<P, I, K>(path: P, candidates: I) -> Result<(Self, K)>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = K>,
+        K: ArchiveKey<C>,
+    {
+        let path = path.as_ref();
+        transaction::ensure_recovered(&transaction::journal_path(path))?;
         let file = stdfs::OpenOptions::new().read(true).open(path)?;
-        let mut this = Self::_open_in_impl(file, key)?;
+        let key = Self::probe_key(&file, candidates)?;
+        let this = Self::_open_in_impl(file, &key, IndexLoading::Eager)?;
+        Ok((this, key))
+    }
+
+    /// Opens a damaged archive, like a partially downloaded one, as far as
+    /// possible. Entries of an unknown type are treated as empty and chains
+    /// are cut off at the first block that can't be read, directories whose
+    /// chain can't be read at all are left out. Everything that was skipped is
+    /// returned alongside the archive.
+    ///
+    /// Only the header and the root directory's first block have to be
+    /// intact. Files whose data lies past the end of the archive are still
+    /// listed, but reading them fails.
+    pub fn open_salvage<P: AsRef<Path>, K: ArchiveKey<C>>(
+        path: P,
+        key: K,
+    ) -> Result<(Self, Vec<Skipped>)> {
+        let path = path.as_ref();
+        transaction::ensure_recovered(&transaction::journal_path(path))?;
+        let file = stdfs::OpenOptions::new().read(true).open(path)?;
+        Self::open_in_salvage(file, key)
+    }
+
+    pub fn open_sorted<P: AsRef<Path>, K: ArchiveKey<C>>(path: P, key: K) -> Result<Self> {
+        let mut this = Self::open_read_only(path, key)?;
         this.block_manager.sort();
         Ok(this)
     }
 }
 
-impl Pk2<io::Cursor<Vec<u8>>> {
-    pub fn create_new_in_memory<K: AsRef<[u8]>>(
+#[cfg(feature = "mmap")]
+impl<C: IndexCipher> Pk2<memmap2::Mmap, ReadOnly, C> {
+    /// Opens the archive at `path` as a read-only memory map. File data can
+    /// then be accessed without copying via [`Pk2::read_bytes`].
+    ///
+    /// # Safety
+    ///
+    /// The archive file must not be modified or truncated by this or any other
+    /// process while the returned archive is alive, see [`memmap2::Mmap`].
+    pub unsafe fn open_mmap<P: AsRef<Path>, K: ArchiveKey<C>>(path: P, key: K) -> Result<Self> {
+        let path = path.as_ref();
+        transaction::ensure_recovered(&transaction::journal_path(path))?;
+        let file = stdfs::OpenOptions::new().read(true).open(path)?;
+        let mmap = memmap2::Mmap::map(&file)?;
+        Self::_open_in_impl(mmap, &key, IndexLoading::Eager)
+    }
+}
+
+impl<C: IndexCipher> Pk2<Vec<u8>, ReadWrite, C> {
+    pub fn create_new_in_memory<K: ArchiveKey<C>>(
         key: K,
     ) -> Result<Self, crate::blowfish::InvalidKey> {
-        Self::_create_impl(io::Cursor::new(Vec::with_capacity(4096)), key).map_err(|e| {
-            debug_assert!(matches!(&e, OpenError::InvalidKey));
+        Self::_create_impl(Vec::with_capacity(4096), key).map_err(|e| {
+            debug_assert!(matches!(&e, Error::Key { .. }));
             // the only error that can actually occur here is an InvalidKey error
             crate::blowfish::InvalidKey
         })
     }
 }
 
-impl<B> Pk2<B>
+impl<B, C> Pk2<B, ReadWrite, C>
 where
-    B: io::Read + io::Seek,
+    B: WriteAt,
+    C: IndexCipher,
 {
-    pub fn open_in<K: AsRef<[u8]>>(mut stream: B, key: K) -> OpenResult<Self> {
-        stream.seek(io::SeekFrom::Start(0))?;
-        Self::_open_in_impl(stream, key)
+    pub fn open_in<K: ArchiveKey<C>>(stream: B, key: K) -> Result<Self> {
+        Self::_open_in_impl(stream, &key, IndexLoading::Eager)
     }
 
-    fn _open_in_impl<K: AsRef<[u8]>>(mut stream: B, key: K) -> OpenResult<Self> {
-        let header = PackHeader::from_reader(&mut stream)?;
-        header.validate_sig()?;
-        let blowfish = if header.encrypted {
-            let bf = Blowfish::new(key.as_ref())?;
-            let mut checksum = *PK2_CHECKSUM;
-            let _ = bf.encrypt(&mut checksum);
-            header.verify(checksum)?;
-            Some(bf)
-        } else {
-            None
+    pub fn open_in_with<K: ArchiveKey<C>>(
+        stream: B,
+        key: K,
+        loading: IndexLoading,
+    ) -> Result<Self> {
+        Self::_open_in_impl(stream, &key, loading)
+    }
+
+    /// Turns this archive into a read-only one.
+    pub fn into_read_only(self) -> Pk2<B, ReadOnly, C> {
+        Pk2 {
+            stream: self.stream,
+            cipher: self.cipher,
+            block_manager: self.block_manager,
+            free_space: self.free_space,
+            journal: None,
+            mode: PhantomData,
+        }
+    }
+}
+
+impl<B, C> Pk2<B, ReadOnly, C>
+where
+    B: ReadAt,
+    C: IndexCipher,
+{
+    pub fn open_in_read_only<K: ArchiveKey<C>>(stream: B, key: K) -> Result<Self> {
+        Self::_open_in_impl(stream, &key, IndexLoading::Eager)
+    }
+
+    pub fn open_in_read_only_with<K: ArchiveKey<C>>(
+        stream: B,
+        key: K,
+        loading: IndexLoading,
+    ) -> Result<Self> {
+        Self::_open_in_impl(stream, &key, loading)
+    }
+
+    /// Opens a damaged archive, skipping the parts of the index that can't be
+    /// read instead of failing, see [`Pk2::open_salvage`].
+    pub fn open_in_salvage<K: ArchiveKey<C>>(stream: B, key: K) -> Result<(Self, Vec<Skipped>)> {
+        let cipher = Self::read_header(&stream, &key)?;
+        let (block_manager, skipped) = BlockManager::new_salvaged(cipher.as_ref(), &stream)?;
+        let this = Pk2 {
+            stream,
+            cipher,
+            block_manager,
+            free_space: FreeSpaceMap::new(),
+            journal: None,
+            mode: PhantomData,
         };
-        let block_manager = BlockManager::new(blowfish.as_ref(), &mut stream)?;
+        Ok((this, skipped))
+    }
+}
+
+impl<B, M, C> Pk2<B, M, C>
+where
+    B: ReadAt,
+    C: IndexCipher,
+{
+    fn _open_in_impl<K: ArchiveKey<C> + ?Sized>(
+        stream: B,
+        key: &K,
+        loading: IndexLoading,
+    ) -> Result<Self> {
+        let cipher = Self::read_header(&stream, key)?;
+        let (block_manager, free_space) = Self::load_index(cipher.as_ref(), &stream, loading)?;
 
         Ok(Pk2 {
-            stream: RefCell::new(stream),
-            blowfish,
+            stream,
+            cipher,
             block_manager,
+            free_space,
+            journal: None,
+            mode: PhantomData,
+        })
+    }
+
+    /// Validates the header, returning the cipher of the index.
+    fn read_header<K: ArchiveKey<C> + ?Sized>(stream: &B, key: &K) -> Result<Option<C>> {
+        let mut buf = [0; PK2_ROOT_BLOCK.0 as usize];
+        stream.read_exact_at(&mut buf, 0)?;
+        let header = PackHeader::from_reader(&buf[..])?;
+        header.validate_sig()?;
+        if !header.encrypted {
+            return Ok(None);
+        }
+        let cipher = key.cipher()?.ok_or(Error::Key { source: None })?;
+        if !cipher.verify(&header) {
+            return Err(Error::Key { source: None });
+        }
+        Ok(Some(cipher))
+    }
+
+    /// Returns the first of `candidates` that is the archive's key. The
+    /// header only stores a few bytes of the key's checksum, so wrong keys
+    /// occasionally pass it. A key is therefore only accepted once the root
+    /// block decrypts to a directory whose `.` entry points at itself.
+    fn probe_key<I, K>(stream: &B, candidates: I) -> Result<K>
+    where
+        I: IntoIterator<Item = K>,
+        K: ArchiveKey<C>,
+    {
+        for key in candidates {
+            let cipher = match Self::read_header(stream, &key) {
+                Ok(cipher) => cipher,
+                Err(Error::Key { .. }) => continue,
+                Err(e) => return Err(e),
+            };
+            let root = BlockOffset(PK2_ROOT_BLOCK.0);
+            match crate::io::read_block_at(cipher.as_ref(), stream, root) {
+                Ok(block) => {
+                    let is_root = block.entries().any(|entry| match entry {
+                        PackEntry::Directory(dir) => {
+                            dir.is_current_link() && dir.children_position() == PK2_ROOT_BLOCK
+                        }
+                        _ => false,
+                    });
+                    if is_root {
+                        return Ok(key);
+                    }
+                }
+                Err(Error::Corrupted { .. }) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Err(Error::Key { source: None })
+    }
+
+    fn load_index(
+        cipher: Option<&C>,
+        stream: &B,
+        loading: IndexLoading,
+    ) -> Result<(BlockManager, FreeSpaceMap)> {
+        Ok(match loading {
+            IndexLoading::Eager => {
+                let mut block_manager = BlockManager::new(cipher, stream)?;
+                let free_space = FreeSpaceMap::from_used_ranges(
+                    stream.stream_len()?,
+                    // the header is the only region not covered by the block manager
+                    std::iter::once((StreamOffset(0), PK2_ROOT_BLOCK.0))
+                        .chain(block_manager.used_regions()),
+                );
+                (block_manager, free_space)
+            }
+            // unused regions can't be known without reading the entire index
+            IndexLoading::Lazy { max_resident } => (
+                BlockManager::new_lazy(cipher, stream, max_resident)?,
+                FreeSpaceMap::new(),
+            ),
         })
     }
 }
 
-impl<B> Pk2<B>
+impl<B, C> Pk2<B, ReadWrite, C>
 where
-    B: io::Read + io::Write + io::Seek,
+    B: WriteAt,
+    C: IndexCipher,
 {
-    pub fn create_new_in<K: AsRef<[u8]>>(mut stream: B, key: K) -> OpenResult<Self> {
-        stream.seek(io::SeekFrom::Start(0))?;
+    pub fn create_new_in<K: ArchiveKey<C>>(stream: B, key: K) -> Result<Self> {
         Self::_create_impl(stream, key)
     }
 
-    fn _create_impl<K: AsRef<[u8]>>(stream: B, key: K) -> OpenResult<Self> {
-        let (header, mut stream, blowfish) = if key.as_ref().is_empty() {
-            (PackHeader::default(), stream, None)
-        } else {
-            let bf = Blowfish::new(key.as_ref())?;
-            (PackHeader::new_encrypted(&bf), stream, Some(bf))
-        };
+    fn _create_impl<K: ArchiveKey<C>>(mut stream: B, key: K) -> Result<Self> {
+        let cipher = key.cipher()?;
+        let header = cipher
+            .as_ref()
+            .map_or_else(PackHeader::default, PackHeader::new_encrypted);
 
-        header.to_writer(&mut stream)?;
+        let mut buf = [0; PK2_ROOT_BLOCK.0 as usize];
+        header.to_writer(&mut buf[..])?;
+        stream.write_all_at(&buf, 0)?;
         let mut block = PackBlock::default();
         block[0] = PackEntry::new_directory(PK2_CURRENT_DIR_IDENT, PK2_ROOT_BLOCK, None);
-        crate::io::write_block(
-            blowfish.as_ref(),
-            &mut stream,
-            PK2_ROOT_BLOCK.into(),
-            &block,
-        )?;
+        crate::io::write_block(cipher.as_ref(), &mut stream, PK2_ROOT_BLOCK.into(), &block)?;
 
-        let block_manager = BlockManager::new(blowfish.as_ref(), &mut stream)?;
+        let block_manager = BlockManager::new(cipher.as_ref(), &stream)?;
         Ok(Pk2 {
-            stream: RefCell::new(stream),
-            blowfish,
+            stream,
+            cipher,
             block_manager,
+            free_space: FreeSpaceMap::new(),
+            journal: None,
+            mode: PhantomData,
         })
     }
 }
 
-impl<B> Pk2<B> {
+impl<B, M, C> Pk2<B, M, C> {
     #[inline(always)]
-    fn get_chain(&self, chain: ChainIndex) -> Option<&PackBlockChain> {
+    fn get_chain(&self, chain: ChainIndex) -> Option<Arc<PackBlockChain>> {
         self.block_manager.get(chain)
     }
 
@@ -141,206 +433,759 @@ impl<B> Pk2<B> {
         self.block_manager.get_mut(chain)
     }
 
-    #[inline(always)]
-    fn get_entry(&self, chain: ChainIndex, entry: usize) -> Option<&PackEntry> {
-        self.get_chain(chain).and_then(|chain| chain.get(entry))
+    /// The entry of the root directory, which lives in a virtual chain of
+    /// its own.
+    fn root_entry(&self) -> EntryHandle {
+        self.get_chain(PK2_ROOT_BLOCK_VIRTUAL)
+            .and_then(|chain| EntryHandle::new(chain, 0))
+            .unwrap()
     }
 
+    /// Checks that the entry `path` was resolved to is a file.
+    pub(self) fn is_file(path: &Path, entry: EntryRef<'_>) -> Result<()> {
+        match entry.is_file() {
+            true => Ok(()),
+            false => Err(Error::lookup(
+                path,
+                Some(entry.name()),
+                ChainLookupError::ExpectedFile,
+            )),
+        }
+    }
+
+    /// Checks that the entry `path` was resolved to is a directory.
+    pub(self) fn is_dir(path: &Path, entry: EntryRef<'_>) -> Result<()> {
+        match entry.is_dir() {
+            true => Ok(()),
+            false => Err(Error::lookup(
+                path,
+                Some(entry.name()),
+                ChainLookupError::ExpectedDirectory,
+            )),
+        }
+    }
+
+    /// Evicts the least recently used chains of a lazily loaded index until
+    /// its resident limit is met, see [`IndexLoading::Lazy`].
+    pub fn trim_index_cache(&mut self) {
+        self.block_manager.trim();
+    }
+
+    /// Reports how much memory the currently loaded part of the index takes
+    /// up.
+    pub fn index_memory_usage(&self) -> IndexMemoryUsage {
+        self.block_manager.memory_usage()
+    }
+}
+
+impl<B, M, C> Pk2<B, M, C>
+where
+    B: ReadAt,
+    C: IndexCipher,
+{
     #[inline(always)]
-    fn get_entry_mut(&mut self, chain: ChainIndex, entry: usize) -> Option<&mut PackEntry> {
-        self.get_chain_mut(chain)
-            .and_then(|chain| chain.get_mut(entry))
+    fn chain_source(&self) -> ChainSource<'_> {
+        ChainSource::new(self.cipher.as_ref(), &self.stream)
     }
 
     fn root_resolve_path_to_entry_and_parent<P: AsRef<Path>>(
         &self,
         path: P,
-    ) -> ChainLookupResult<(ChainIndex, usize, &PackEntry)> {
-        self.block_manager
-            .resolve_path_to_entry_and_parent(PK2_ROOT_BLOCK, check_root(path.as_ref())?)
+    ) -> Result<EntryHandle> {
+        let path = path.as_ref();
+        self.resolve_path_to_entry_and_parent(PK2_ROOT_BLOCK, path, check_root(path)?)
     }
 
-    pub(self) fn is_file(entry: &PackEntry) -> ChainLookupResult<()> {
-        match entry.is_file() {
-            true => Ok(()),
-            false => Err(ChainLookupError::ExpectedFile),
-        }
+    /// Resolves `path`, which is relative to `chain`, to its entry and the
+    /// chain containing it. `full_path` is the path reported in errors.
+    pub(self) fn resolve_path_to_entry_and_parent(
+        &self,
+        chain: ChainIndex,
+        full_path: &Path,
+        path: &Path,
+    ) -> Result<EntryHandle> {
+        self.block_manager
+            .resolve_path_to_entry_and_parent(self.chain_source(), chain, path)
+            .map_err(|e| self.lookup_error(chain, full_path, path, e))
     }
 
-    pub(self) fn is_dir(entry: &PackEntry) -> ChainLookupResult<()> {
-        match entry.is_dir() {
-            true => Ok(()),
-            false => Err(ChainLookupError::ExpectedDirectory),
+    /// Creates the error for a failed lookup of `path` relative to `chain`,
+    /// figuring out which component of it the lookup failed at.
+    pub(self) fn lookup_error(
+        &self,
+        mut chain: ChainIndex,
+        full_path: &Path,
+        path: &Path,
+        source: ChainLookupError,
+    ) -> Error {
+        let mut component = None;
+        if source != ChainLookupError::InvalidPath {
+            for name in path.components().map(|c| c.as_os_str().to_str()) {
+                let Some(name) = name else { break };
+                component = Some(name);
+                match self
+                    .block_manager
+                    .load(self.chain_source(), chain)
+                    .and_then(|chain| chain.find_block_chain_index_of(name))
+                {
+                    Ok(next) => chain = next,
+                    Err(_) => break,
+                }
+            }
         }
+        Error::lookup(full_path, component, source)
     }
 }
 
-impl<B> Pk2<B> {
-    pub fn open_file<P: AsRef<Path>>(&self, path: P) -> ChainLookupResult<File<B>> {
-        let (chain, entry_idx, entry) = self.root_resolve_path_to_entry_and_parent(path)?;
-        Self::is_file(entry)?;
-        Ok(File::new(self, chain, entry_idx))
+impl<B, M, C> Pk2<B, M, C>
+where
+    B: ReadAt,
+    C: IndexCipher,
+{
+    pub fn open_file<P: AsRef<Path>>(&self, path: P) -> Result<File<'_, B, M, C>> {
+        let path = path.as_ref();
+        let handle = self.root_resolve_path_to_entry_and_parent(path)?;
+        Self::is_file(path, handle.entry())?;
+        Ok(File::new(self, handle))
     }
 
-    pub fn open_directory<P: AsRef<Path>>(&self, path: P) -> ChainLookupResult<Directory<B>> {
-        let path = check_root(path.as_ref())?;
-        let (chain, entry_idx) = match self
-            .block_manager
-            .resolve_path_to_entry_and_parent(PK2_ROOT_BLOCK, path)
-        {
-            Ok((chain, entry_idx, entry)) => {
-                Self::is_dir(entry)?;
-                (chain, entry_idx)
+    pub fn open_directory<P: AsRef<Path>>(&self, path: P) -> Result<Directory<'_, B, M, C>> {
+        let full_path = path.as_ref();
+        let path = check_root(full_path)?;
+        let handle = match self.block_manager.resolve_path_to_entry_and_parent(
+            self.chain_source(),
+            PK2_ROOT_BLOCK,
+            path,
+        ) {
+            Ok(handle) => {
+                Self::is_dir(full_path, handle.entry())?;
+                handle
             }
             // path was just root
-            Err(ChainLookupError::InvalidPath) => (PK2_ROOT_BLOCK_VIRTUAL, 0),
-            Err(e) => return Err(e),
+            Err(ChainLookupError::InvalidPath) => self.root_entry(),
+            Err(e) => return Err(self.lookup_error(PK2_ROOT_BLOCK, full_path, path, e)),
         };
-        Ok(Directory::new(self, chain, entry_idx))
+        Ok(Directory::new(self, handle))
     }
 
-    /// Invokes cb on every file in the sub directories of `base`, including
-    /// files inside of its subdirectories. Cb gets invoked with its
-    /// relative path to `base` and the file object.
-    // Todo, replace this with a file_paths iterator once generators are stable
-    pub fn for_each_file(
-        &self,
-        base: impl AsRef<Path>,
-        mut cb: impl FnMut(&Path, File<B>) -> io::Result<()>,
-    ) -> io::Result<()> {
-        let mut path = std::path::PathBuf::new();
-        let mut stack = vec![self.open_directory(base)?];
-        let mut first_iteration = true;
-        while let Some(dir) = stack.pop() {
-            if !first_iteration {
-                path.push(dir.name());
-            } else {
-                first_iteration = false;
-            };
-            let mut files_only = true;
-            for entry in dir.entries() {
-                match entry {
-                    DirEntry::Directory(dir) => {
-                        stack.push(dir);
-                        files_only = false;
-                    }
-                    DirEntry::File(file) => {
-                        path.push(file.name());
-                        cb(&path, file)?;
-                        path.pop();
-                    }
-                }
-            }
-            if files_only {
-                path.pop();
+    /// Queries the metadata of the file or directory at `path`.
+    pub fn metadata<P: AsRef<Path>>(&self, path: P) -> Result<Metadata> {
+        let full_path = path.as_ref();
+        let path = check_root(full_path)?;
+        match self.block_manager.resolve_path_to_entry_and_parent(
+            self.chain_source(),
+            PK2_ROOT_BLOCK,
+            path,
+        ) {
+            Ok(handle) => Ok(Metadata::new(handle.chain_index(), handle.entry())),
+            // path was just root
+            Err(ChainLookupError::InvalidPath) => {
+                let root = self.root_entry();
+                Ok(Metadata::new(PK2_ROOT_BLOCK_VIRTUAL, root.entry()))
             }
+            Err(e) => Err(self.lookup_error(PK2_ROOT_BLOCK, full_path, path, e)),
         }
-        Ok(())
+    }
+
+    /// Returns `true` if there is a file or directory at `path`. Errors,
+    /// like failing to load a lazily read part of the index, are treated as
+    /// the entry not existing.
+    pub fn exists<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    /// Returns an iterator that recursively walks the directory at `base`,
+    /// see [`Walk`] for how to configure it.
+    pub fn walk(&self, base: impl AsRef<Path>) -> Result<Walk<'_, B, M, C>> {
+        let base = base.as_ref();
+        let dir = self.open_directory(base)?;
+        let path = Path::new("/").join(check_root(base)?);
+        Ok(Walk::new(dir, path))
+    }
+
+    /// Returns an iterator over all files whose absolute path matches the
+    /// glob `pattern`, like `/res/**/*.ddj`, see [`Glob`] for the supported
+    /// syntax.
+    pub fn glob(&self, pattern: &str) -> Result<Glob<'_, B, M, C>> {
+        let pattern = pattern.strip_prefix('/').ok_or_else(|| {
+            Error::lookup(Path::new(pattern), None, ChainLookupError::InvalidPath)
+        })?;
+        Glob::new(self.open_directory("/")?, "/".into(), pattern)
+    }
+
+    /// Checks the integrity of the archive's index, reporting chains that
+    /// point past the end of the archive or loop back onto themselves,
+    /// directories that are reachable through multiple entries or have
+    /// broken `.` and `..` links, overlapping file data and entries of an
+    /// unknown type.
+    ///
+    /// The index is read from the stream for this, independent of what has
+    /// been loaded already.
+    pub fn check(&self) -> Result<CheckReport> {
+        check::check(self.cipher.as_ref(), &self.stream)
+    }
+
+    /// Reports how the space of the archive is used and how fragmented its
+    /// index is. For lazily loaded archives this loads the entire index.
+    pub fn stats(&self) -> Result<ArchiveStats> {
+        let stream_len = self.stream.stream_len()?;
+        stats::collect(&self.block_manager, self.chain_source(), stream_len)
+    }
+
+    /// Reads the header of the archive.
+    pub fn header(&self) -> Result<PackHeader> {
+        let mut buf = [0; PK2_ROOT_BLOCK.0 as usize];
+        self.stream
+            .read_exact_at(&mut buf, 0)
+            .map_err(|e| Error::from(e).with_offset(0))?;
+        Ok(PackHeader::from_reader(&buf[..])?)
     }
 }
 
-impl<B> Pk2<B>
+impl<B, M, C> Pk2<B, M, C>
 where
-    B: io::Read + io::Seek,
+    B: ReadAt,
+    C: IndexCipher,
 {
-    pub fn read<P: AsRef<Path>>(&self, path: P) -> io::Result<Vec<u8>> {
+    pub fn read<P: AsRef<Path>>(&self, path: P) -> Result<Vec<u8>> {
+        let path = path.as_ref();
         let mut file = self.open_file(path)?;
         let mut buf = Vec::with_capacity(file.size() as usize);
-        std::io::Read::read_to_end(&mut file, &mut buf)?;
+        std::io::Read::read_to_end(&mut file, &mut buf)
+            .map_err(|e| Error::from(e).with_path(path))?;
         Ok(buf)
     }
 }
 
-impl<B> Pk2<B>
+impl<B, M, C> Pk2<B, M, C>
+where
+    B: ReadAt + AsRef<[u8]>,
+    C: IndexCipher,
+{
+    /// Returns the data of the file at `path` as a slice of the backing buffer
+    /// without copying it. File data is never encrypted, so this is a plain
+    /// view into the archive.
+    pub fn read_bytes<P: AsRef<Path>>(&self, path: P) -> Result<&[u8]> {
+        let path = path.as_ref();
+        let handle = self.root_resolve_path_to_entry_and_parent(path)?;
+        let entry = handle.entry();
+        Self::is_file(path, entry)?;
+        let file = entry.as_file().unwrap();
+        let StreamOffset(start) = file.pos_data();
+        self.stream
+            .as_ref()
+            .get(start as usize..)
+            .and_then(|data| data.get(..file.size() as usize))
+            .ok_or_else(|| Error::Io {
+                path: Some(path.to_owned()),
+                offset: Some(start),
+                source: io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "file data lies outside of the archive",
+                ),
+            })
+    }
+}
+
+impl<B, C> Pk2<B, ReadWrite, C>
 where
-    B: io::Read + io::Write + io::Seek,
+    B: WriteAt,
+    C: IndexCipher,
 {
-    pub fn open_file_mut<P: AsRef<Path>>(&mut self, path: P) -> ChainLookupResult<FileMut<B>> {
-        let (chain, entry_idx, entry) = self.root_resolve_path_to_entry_and_parent(path)?;
-        Self::is_file(entry)?;
+    /// Runs `f` as a transaction. Changes `f` makes to the archive are
+    /// either applied completely or not at all: if `f` returns an error or
+    /// panics, everything it did is rolled back, and if the process dies
+    /// midway the archive is recovered the next time it is opened for
+    /// writing, see the [`transaction`] module for details.
+    ///
+    /// Writes inside of a transaction don't reuse the free space the archive
+    /// had before, only space freed by the transaction itself. Whatever is
+    /// left of the latter becomes available to the archive once the
+    /// transaction is committed. Rolling back reloads the index from the
+    /// stream.
+    ///
+    /// Everything the transaction writes to the part of the stream that
+    /// existed when it started is kept in memory until it commits, each byte
+    /// once no matter how often it is overwritten. This is mostly index
+    /// blocks, but rewriting the data of existing files in place is held back
+    /// as well, and a transaction rewriting the whole index like
+    /// [`Pk2::rekey`] holds it in memory twice, once decoded in the index
+    /// cache and once encoded in the pending writes.
+    pub fn transaction<T, E>(
+        &mut self,
+        f: impl FnOnce(&mut Transaction<'_, B, C>) -> Result<T, E>,
+    ) -> Result<T, E>
+    where
+        E: From<io::Error>,
+    {
+        self.block_manager.trim();
+        let journal = Journal::begin(self.journal.as_deref(), self.stream.stream_len()?)?;
+        let mut tx = Pk2 {
+            stream: journal.stream(&mut self.stream),
+            cipher: self.cipher.take(),
+            block_manager: std::mem::take(&mut self.block_manager),
+            free_space: FreeSpaceMap::new(),
+            journal: None,
+            mode: PhantomData,
+        };
+        let res = panic::catch_unwind(AssertUnwindSafe(|| f(&mut tx)));
+        let Pk2 {
+            stream,
+            cipher,
+            block_manager,
+            free_space,
+            ..
+        } = tx;
+        let writes = stream.into_writes();
+        self.cipher = cipher;
+        self.block_manager = block_manager;
+        let res = match res {
+            Ok(res) => res,
+            Err(payload) => {
+                // nothing can be reported while unwinding
+                if journal.rollback(&mut self.stream).is_err() {
+                    self.recover_journal();
+                }
+                let _ = self.reload_index();
+                panic::resume_unwind(payload);
+            }
+        };
+        let res = match res {
+            Ok(value) => journal
+                .commit(&mut self.stream, &writes)
+                .map(|()| Ok(value)),
+            Err(e) => journal.rollback(&mut self.stream).map(|()| Err(e)),
+        };
+        match res {
+            Ok(Ok(value)) => {
+                for (offset, len) in free_space.regions() {
+                    self.free_space.release(offset, len);
+                }
+                Ok(value)
+            }
+            // the index still holds the changes of the transaction
+            Ok(Err(e)) => {
+                self.reload_index().map_err(io::Error::from)?;
+                Err(e)
+            }
+            // the stream may be partially updated, so the journal is given
+            // another chance before the index is read again
+            Err(e) => {
+                self.recover_journal();
+                let _ = self.reload_index();
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Tries to finish the transaction left behind in the journal. If this
+    /// fails as well, it is finished when the archive is opened for writing
+    /// next.
+    fn recover_journal(&mut self) {
+        if let Some(journal) = &self.journal {
+            let _ = transaction::recover(&mut self.stream, journal);
+        }
+    }
+
+    fn reload_index(&mut self) -> Result<()> {
+        let loading = if self.block_manager.is_lazy() {
+            IndexLoading::Lazy {
+                max_resident: self.block_manager.max_resident(),
+            }
+        } else {
+            IndexLoading::Eager
+        };
+        let (block_manager, free_space) =
+            Self::load_index(self.cipher.as_ref(), &self.stream, loading)?;
+        self.block_manager = block_manager;
+        self.free_space = free_space;
+        Ok(())
+    }
+
+    /// Returns a builder for opening files for writing with more control
+    /// than [`Pk2::create_file`] and [`Pk2::open_file_mut`] offer.
+    pub fn file_options(&mut self) -> FileOptions<'_, B, C> {
+        FileOptions::new(self)
+    }
+
+    pub fn open_file_mut<P: AsRef<Path>>(&mut self, path: P) -> Result<FileMut<'_, B, C>> {
+        self.block_manager.trim();
+        let path = path.as_ref();
+        let handle = self.root_resolve_path_to_entry_and_parent(path)?;
+        Self::is_file(path, handle.entry())?;
+        let (chain, entry_idx) = (handle.chain_index(), handle.index());
+        drop(handle);
         Ok(FileMut::new(self, chain, entry_idx))
     }
 
-    /// Currently only replaces the entry with an empty one making the data
-    /// inaccessible by normal means
-    pub fn delete_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
-        let (chain_index, entry_idx, entry) = self
+    /// Replaces the entry with an empty one, the space occupied by the file's
+    /// data will be reused by subsequent writes.
+    pub fn delete_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.block_manager.trim();
+        let path = path.as_ref();
+        let handle = self.root_resolve_path_to_entry_and_parent(path)?;
+        Self::is_file(path, handle.entry())?;
+        let (chain_index, entry_idx) = (handle.chain_index(), handle.index());
+        // release the handle so the chain can be modified in place
+        drop(handle);
+        let chain = self
             .block_manager
-            .resolve_path_to_entry_and_parent_mut(PK2_ROOT_BLOCK, check_root(path.as_ref())?)?;
-        Self::is_file(entry)?;
-        entry.clear();
-
-        crate::io::write_chain_entry(
-            self.blowfish.as_ref(),
-            &mut *self.stream.borrow_mut(),
-            self.get_chain(chain_index).unwrap(),
-            entry_idx,
+            .load_mut(
+                ChainSource::new(self.cipher.as_ref(), &self.stream),
+                chain_index,
+            )
+            .map_err(|e| Error::lookup(path, None, e))?;
+        if let Some(PackEntry::File(file)) = chain.remove(entry_idx) {
+            self.free_space.release(file.pos_data(), file.size() as u64);
+        }
+
+        crate::io::write_chain_entry(self.cipher.as_ref(), &mut self.stream, chain, entry_idx)
+            .map_err(|e| Error::from(e).with_path(path))
+    }
+
+    /// Deletes the directory at `path`. If `recursive` is false the directory
+    /// has to be empty, otherwise all of its children are deleted as well.
+    /// Deleting the root directory or a `.`/`..` link is refused.
+    pub fn delete_directory<P: AsRef<Path>>(&mut self, path: P, recursive: bool) -> Result<()> {
+        self.block_manager.trim();
+        let path = path.as_ref();
+        self.delete_directory_impl(path, recursive)
+            .map_err(|e| e.with_path(path))
+    }
+
+    fn delete_directory_impl(&mut self, path: &Path, recursive: bool) -> Result<()> {
+        let handle = self.root_resolve_path_to_entry_and_parent(path)?;
+        Self::is_dir(path, handle.entry())?;
+        let dir_chain = handle
+            .entry()
+            .as_directory()
+            .and_then(DirectoryRef::normal_link_children_position)
+            .ok_or_else(|| Error::lookup(path, None, ChainLookupError::InvalidPath))?;
+        let (chain_index, entry_idx) = (handle.chain_index(), handle.index());
+        drop(handle);
+        let sub_chains = self
+            .block_manager
+            .collect_sub_chains(self.chain_source(), dir_chain)
+            .map_err(|e| Error::lookup(path, None, e))?;
+        let is_empty = self
+            .block_manager
+            .load(self.chain_source(), dir_chain)
+            .map_err(|e| Error::lookup(path, None, e))?
+            .entries()
+            .all(|entry| entry.as_directory().is_some_and(|d| !d.is_normal_link()));
+        if !recursive && !is_empty {
+            return Err(io::Error::from(io::ErrorKind::DirectoryNotEmpty).into());
+        }
+
+        // loading the subtree may have evicted chains again, so every chain
+        // is loaded once more before it is modified
+        let lookup = |e| Error::lookup(path, None, e);
+        // unlink the directory from its parent first so that the subtree is
+        // unreachable even if clearing the children fails midway
+        let parent = self
+            .block_manager
+            .load_mut(
+                ChainSource::new(self.cipher.as_ref(), &self.stream),
+                chain_index,
+            )
+            .map_err(lookup)?;
+        parent.remove(entry_idx);
+        crate::io::write_chain_entry(self.cipher.as_ref(), &mut self.stream, parent, entry_idx)?;
+
+        for chain_index in sub_chains {
+            self.block_manager
+                .load(self.chain_source(), chain_index)
+                .map_err(lookup)?;
+            if let Some(mut chain) = self.block_manager.remove(chain_index) {
+                for file in chain.entries().filter_map(EntryRef::as_file) {
+                    self.free_space.release(file.pos_data(), file.size() as u64);
+                }
+                chain.clear();
+                crate::io::write_chain(self.cipher.as_ref(), &mut self.stream, &chain)?;
+                for BlockOffset(offset) in chain.block_offsets() {
+                    self.free_space
+                        .release(StreamOffset(offset), PK2_FILE_BLOCK_SIZE as u64);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Renames or moves the file or directory at `from` to `to`. The parent
+    /// directory of `to` has to exist and `to` itself must not exist yet.
+    /// Entries staying in the same directory are renamed in place, moved
+    /// directories get their `..` link updated to point to the new parent.
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> Result<()> {
+        self.block_manager.trim();
+        let (from, to) = (from.as_ref(), to.as_ref());
+        self.rename_impl(from, to).map_err(|e| e.with_path(from))
+    }
+
+    fn rename_impl(&mut self, from_full: &Path, to_full: &Path) -> Result<()> {
+        let src = ChainSource::new(self.cipher.as_ref(), &self.stream);
+        let from = check_root(from_full)?;
+        let to = check_root(to_full)?;
+        let handle = self.resolve_path_to_entry_and_parent(PK2_ROOT_BLOCK, from_full, from)?;
+        let (from_chain, from_idx) = (handle.chain_index(), handle.index());
+        let mut moved_entry = handle.entry().to_pack_entry();
+        let moved_dir = match handle.entry().as_directory() {
+            Some(dir) => Some(
+                dir.normal_link_children_position()
+                    .ok_or_else(|| Error::lookup(from_full, None, ChainLookupError::InvalidPath))?,
+            ),
+            None => None,
+        };
+        drop(handle);
+        let (to_chain, new_name) = self
+            .block_manager
+            .resolve_path_to_parent(src, PK2_ROOT_BLOCK, to)
+            .map_err(|e| self.lookup_error(PK2_ROOT_BLOCK, to_full, to, e))?;
+        if new_name == PK2_CURRENT_DIR_IDENT || new_name == PK2_PARENT_DIR_IDENT {
+            return Err(Error::lookup(to_full, None, ChainLookupError::InvalidPath));
+        }
+        let existing = self
+            .block_manager
+            .load(src, to_chain)
+            .map_err(|e| Error::lookup(to_full, None, e))?
+            .position(new_name);
+        match existing {
+            // allow changing the case of an entry's name
+            Some(idx) if (to_chain, idx) == (from_chain, from_idx) => (),
+            Some(_) => {
+                return Err(
+                    Error::from(io::Error::from(io::ErrorKind::AlreadyExists)).with_path(to_full)
+                )
+            }
+            None => (),
+        }
+        if let Some(dir_chain) = moved_dir {
+            if self
+                .block_manager
+                .collect_sub_chains(src, dir_chain)
+                .map_err(|e| Error::lookup(from_full, None, e))?
+                .contains(&to_chain)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "cannot move a directory into itself",
+                )
+                .into());
+            }
+        }
+
+        let cipher = self.cipher.as_ref();
+        let stream = &mut self.stream;
+        // the lookups above may have evicted the chains involved, so they
+        // are loaded again before being modified
+        let lookup = |e| Error::lookup(from_full, None, e);
+        if from_chain == to_chain {
+            let chain = self
+                .block_manager
+                .load_mut(ChainSource::new(cipher, &*stream), from_chain)
+                .map_err(lookup)?;
+            chain.update_entry(from_idx, |entry| entry.set_name(new_name));
+            return Ok(crate::io::write_chain_entry(
+                cipher, stream, chain, from_idx,
+            )?);
+        }
+
+        moved_entry.set_name(new_name);
+        // write the new entry before clearing the old one, so that an
+        // interrupted move leaves a duplicate rather than losing the entry
+        let chain = self
+            .block_manager
+            .load_mut(ChainSource::new(cipher, &*stream), to_chain)
+            .map_err(lookup)?;
+        let to_idx = crate::io::find_or_allocate_empty_entry(
+            cipher,
+            &mut *stream,
+            &mut self.free_space,
+            chain,
         )?;
+        chain.update_entry(to_idx, |entry| *entry = moved_entry);
+        crate::io::write_chain_entry(cipher, &mut *stream, chain, to_idx)?;
+
+        let chain = self
+            .block_manager
+            .load_mut(ChainSource::new(cipher, &*stream), from_chain)
+            .map_err(lookup)?;
+        chain.remove(from_idx);
+        crate::io::write_chain_entry(cipher, &mut *stream, chain, from_idx)?;
+
+        if let Some(dir_chain) = moved_dir {
+            let chain = self
+                .block_manager
+                .load_mut(ChainSource::new(cipher, &*stream), dir_chain)
+                .map_err(lookup)?;
+            let parent_idx = chain
+                .entries()
+                .find(|entry| {
+                    entry
+                        .as_directory()
+                        .is_some_and(DirectoryRef::is_parent_link)
+                })
+                .map(EntryRef::index);
+            if let Some(parent_idx) = parent_idx {
+                chain.update_entry(parent_idx, |entry| {
+                    if let Some(dir) = entry.as_directory_mut() {
+                        dir.set_children_position(to_chain);
+                    }
+                });
+                crate::io::write_chain_entry(cipher, stream, chain, parent_idx)?;
+            }
+        }
         Ok(())
     }
 
-    pub fn create_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<FileMut<B>> {
-        let path = check_root(path.as_ref())?;
+    pub fn create_file<P: AsRef<Path>>(&mut self, path: P) -> Result<FileMut<'_, B, C>> {
+        let (chain, entry_idx) = self.create_file_entry(path.as_ref())?;
+        Ok(FileMut::new_created(self, chain, entry_idx))
+    }
+
+    /// Creates a new file at `path` whose data is written to the archive as
+    /// it is being written instead of being buffered in memory first. This
+    /// should be preferred for large files, see [`FileWriter`] for details.
+    pub fn create_file_streaming<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<FileWriter<'_, B, C>> {
+        let path = path.as_ref();
+        let (chain, entry_idx) = self.create_file_entry(path)?;
+        FileWriter::new(self, chain, entry_idx).map_err(|e| Error::from(e).with_path(path))
+    }
+
+    fn create_file_entry(&mut self, full_path: &Path) -> Result<(ChainIndex, usize)> {
+        self.block_manager.trim();
+        let path = check_root(full_path)?;
         let file_name = path
             .file_name()
             .and_then(std::ffi::OsStr::to_str)
-            .ok_or(ChainLookupError::InvalidPath)?;
+            .ok_or_else(|| Error::lookup(full_path, None, ChainLookupError::InvalidPath))?;
+        let (chain, entry_idx) = Self::create_entry_at(
+            &mut self.block_manager,
+            self.cipher.as_ref(),
+            &mut self.stream,
+            &mut self.free_space,
+            PK2_ROOT_BLOCK,
+            full_path,
+            path,
+        )
+        .map_err(|e| e.with_path(full_path))?;
+        self.get_chain_mut(chain)
+            .unwrap()
+            .update_entry(entry_idx, |entry| {
+                *entry = PackEntry::new_file(file_name, StreamOffset(0), 0, entry.next_block())
+            });
+        Ok((chain, entry_idx))
+    }
+
+    /// Creates a new, empty directory at `path`. The parent directory has to
+    /// exist already, an [`io::ErrorKind::AlreadyExists`] error is returned if
+    /// `path` already exists.
+    pub fn create_directory<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.block_manager.trim();
+        let full_path = path.as_ref();
+        let path = check_root(full_path)?;
+        if let Some(parent) = path.parent() {
+            self.block_manager
+                .resolve_path_to_block_chain_index_at(self.chain_source(), PK2_ROOT_BLOCK, parent)
+                .map_err(|e| self.lookup_error(PK2_ROOT_BLOCK, full_path, parent, e))?;
+        }
+        self.create_directory_impl(full_path, path)
+            .map_err(|e| e.with_path(full_path))
+    }
+
+    /// Recursively creates a directory and all of its missing parents. This
+    /// does nothing if `path` already is a directory.
+    pub fn create_dir_all<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.block_manager.trim();
+        let full_path = path.as_ref();
+        let path = check_root(full_path)?;
+        if self
+            .block_manager
+            .resolve_path_to_block_chain_index_at(self.chain_source(), PK2_ROOT_BLOCK, path)
+            .is_ok()
+        {
+            return Ok(());
+        }
+        self.create_directory_impl(full_path, path)
+            .map_err(|e| e.with_path(full_path))
+    }
+
+    fn create_directory_impl(&mut self, full_path: &Path, path: &Path) -> Result<()> {
+        let dir_name = path
+            .file_name()
+            .and_then(std::ffi::OsStr::to_str)
+            .ok_or_else(|| Error::lookup(full_path, None, ChainLookupError::InvalidPath))?;
+        let stream = &mut self.stream;
         let (chain, entry_idx) = Self::create_entry_at(
             &mut self.block_manager,
-            self.blowfish.as_ref(),
-            &mut *self.stream.borrow_mut(),
+            self.cipher.as_ref(),
+            stream,
+            &mut self.free_space,
             PK2_ROOT_BLOCK,
+            full_path,
             path,
         )?;
-        let entry = self.get_entry_mut(chain, entry_idx).unwrap();
-        *entry = PackEntry::new_file(file_name, StreamOffset(0), 0, entry.next_block());
-        Ok(FileMut::new(self, chain, entry_idx))
+        let current_chain = self
+            .block_manager
+            .load_mut(ChainSource::new(self.cipher.as_ref(), &*stream), chain)
+            .map_err(|e| Error::lookup(full_path, None, e))?;
+        let block_chain = crate::io::allocate_new_block_chain(
+            self.cipher.as_ref(),
+            stream,
+            &mut self.free_space,
+            current_chain,
+            dir_name,
+            entry_idx,
+        )?;
+        self.block_manager
+            .insert(block_chain.chain_index(), block_chain);
+        Ok(())
     }
 
     /// This function traverses the whole path creating anything that does not
     /// yet exist returning the last created entry. This means using parent and
     /// current dir parts in a path that in the end directs to an already
     /// existing path might still create new directories that arent actually being used.
+    /// `full_path` is the path reported in errors.
     fn create_entry_at(
         block_manager: &mut BlockManager,
-        blowfish: Option<&Blowfish>,
+        cipher: Option<&C>,
         mut stream: &mut B,
+        free_space: &mut FreeSpaceMap,
         chain: ChainIndex,
+        full_path: &Path,
         path: &Path,
-    ) -> io::Result<(ChainIndex, usize)> {
-        use crate::io::{allocate_empty_block, allocate_new_block_chain, write_chain_entry};
+    ) -> Result<(ChainIndex, usize)> {
+        use crate::io::{allocate_new_block_chain, find_or_allocate_empty_entry};
+        let lookup = |e| Error::lookup(full_path, None, e);
         let (mut current_chain_index, mut components) = block_manager
-            .validate_dir_path_until(chain, path)?
+            .validate_dir_path_until(ChainSource::new(cipher, &*stream), chain, path)
+            .map_err(lookup)?
             .ok_or_else(|| io::Error::from(io::ErrorKind::AlreadyExists))?;
         while let Some(component) = components.next() {
             match component {
                 Component::Normal(p) => {
                     let current_chain = block_manager
-                        .get_mut(current_chain_index)
-                        .ok_or(ChainLookupError::InvalidChainIndex)?;
-                    let empty_pos = current_chain.entries().position(PackEntry::is_empty);
-                    let chain_entry_idx = if let Some(idx) = empty_pos {
-                        idx
-                    } else {
-                        // current chain is full so create a new block and append it
-                        let (offset, block) = allocate_empty_block(blowfish, &mut stream)?;
-                        let chain_entry_idx = current_chain.num_entries();
-                        current_chain.push_and_link(offset, block);
-                        write_chain_entry(
-                            blowfish,
-                            &mut stream,
-                            &current_chain,
-                            chain_entry_idx - 1,
-                        )?;
-                        chain_entry_idx
-                    };
+                        .load_mut(ChainSource::new(cipher, &*stream), current_chain_index)
+                        .map_err(lookup)?;
+                    let chain_entry_idx = find_or_allocate_empty_entry(
+                        cipher,
+                        &mut stream,
+                        free_space,
+                        current_chain,
+                    )?;
                     // Are we done after this? if not, create a new blockchain since this is a new
                     // directory
                     if components.peek().is_some() {
-                        let dir_name = p.to_str().ok_or(ChainLookupError::InvalidPath)?;
+                        let dir_name = p
+                            .to_str()
+                            .ok_or_else(|| lookup(ChainLookupError::InvalidPath))?;
                         let block_chain = allocate_new_block_chain(
-                            blowfish,
+                            cipher,
                             &mut stream,
+                            free_space,
                             current_chain,
                             dir_name,
                             chain_entry_idx,
@@ -353,27 +1198,172 @@ where
                 }
                 Component::ParentDir => {
                     current_chain_index = block_manager
-                        .get_mut(current_chain_index)
-                        .ok_or(ChainLookupError::InvalidChainIndex)
-                        .and_then(|entry| entry.find_block_chain_index_of(PK2_PARENT_DIR_IDENT))?
+                        .load(ChainSource::new(cipher, &*stream), current_chain_index)
+                        .and_then(|chain| chain.find_block_chain_index_of(PK2_PARENT_DIR_IDENT))
+                        .map_err(lookup)?
                 }
                 Component::CurDir => (),
                 _ => unreachable!(),
             }
         }
-        Err(io::ErrorKind::AlreadyExists.into())
+        Err(io::Error::from(io::ErrorKind::AlreadyExists).into())
+    }
+}
+
+impl<B, C> Pk2<B, ReadWrite, C>
+where
+    B: WriteAt,
+    C: IndexCipher,
+{
+    /// Compacts the archive in place by moving all blocks and file data to
+    /// the front of the stream, closing the gaps left behind by deleted or
+    /// relocated entries, and truncating the stream afterwards. Returns the
+    /// number of bytes the stream has been shrunk by.
+    ///
+    /// The archive is left in an inconsistent state if this fails midway.
+    pub fn compact(&mut self) -> Result<u64> {
+        self.compact_with_progress(|_, _| ())
+    }
+
+    /// Like [`Pk2::compact`], but invokes `progress` with the number of
+    /// processed bytes and the total amount of bytes to process after every
+    /// moved region.
+    pub fn compact_with_progress<F: FnMut(u64, u64)>(&mut self, mut progress: F) -> Result<u64> {
+        // every chain has to be relocated
+        self.block_manager
+            .load_all(ChainSource::new(self.cipher.as_ref(), &self.stream))
+            .map_err(|e| Error::lookup(Path::new("/"), None, e))?;
+        let stream = &mut self.stream;
+        let stream_len = stream.stream_len()?;
+        let mut regions = self
+            .block_manager
+            .used_regions()
+            .map(|(StreamOffset(offset), len)| (offset, (offset + len).min(stream_len)))
+            .filter(|&(start, end)| start < end)
+            .collect::<Vec<_>>();
+        regions.sort_unstable();
+
+        // (old_start, old_end, new_start), overlapping regions are merged into
+        // one span so that they keep their relative positions
+        let mut spans: Vec<(u64, u64, u64)> = Vec::with_capacity(regions.len());
+        for (start, end) in regions {
+            match spans.last_mut() {
+                Some(last) if start < last.1 => last.1 = last.1.max(end),
+                _ => spans.push((start, end, 0)),
+            }
+        }
+        let mut new_len = PK2_ROOT_BLOCK.0;
+        for span in &mut spans {
+            span.2 = new_len.min(span.0);
+            new_len = span.2 + (span.1 - span.0);
+        }
+
+        // spans only ever move towards the front, so moving them in ascending
+        // order never overwrites data that has yet to be moved
+        let total = spans.iter().map(|span| span.1 - span.0).sum();
+        let mut processed = 0;
+        let mut buf = vec![0; 0x10000];
+        for &(old_start, old_end, new_start) in &spans {
+            let len = old_end - old_start;
+            if old_start != new_start {
+                let mut pos = 0;
+                while pos < len {
+                    let n = buf.len().min((len - pos) as usize);
+                    crate::io::read_exact_at(
+                        &mut *stream,
+                        StreamOffset(old_start + pos),
+                        &mut buf[..n],
+                    )?;
+                    crate::io::write_data_at(
+                        &mut *stream,
+                        StreamOffset(new_start + pos),
+                        &buf[..n],
+                    )?;
+                    pos += n as u64;
+                }
+            }
+            processed += len;
+            progress(processed, total);
+        }
+
+        self.block_manager.relocate(|offset| {
+            let idx = spans.partition_point(|span| span.0 <= offset);
+            match idx.checked_sub(1).map(|idx| spans[idx]) {
+                Some((old_start, old_end, new_start)) if offset < old_end => {
+                    new_start + (offset - old_start)
+                }
+                _ => offset,
+            }
+        });
+        for chain in self.block_manager.chains() {
+            crate::io::write_chain(self.cipher.as_ref(), &mut *stream, chain)?;
+        }
+        stream.set_len(new_len)?;
+        self.free_space = FreeSpaceMap::new();
+        Ok(stream_len.saturating_sub(new_len))
+    }
+
+    /// Encrypts the archive's index with `new_key` instead of its current
+    /// key, an empty key leaves the index unencrypted. File data is never
+    /// encrypted, so only the header's checksum and the index blocks are
+    /// rewritten in place.
+    ///
+    /// This runs as a [transaction](Pk2::transaction), so the archive is
+    /// never left with an index that is only partially re-encrypted.
+    pub fn rekey<K: ArchiveKey<C>>(&mut self, new_key: K) -> Result<()> {
+        let cipher = new_key.cipher()?;
+        let mut header = self.header()?;
+        let new_header = cipher
+            .as_ref()
+            .map_or_else(PackHeader::new, PackHeader::new_encrypted);
+        header.encrypted = new_header.encrypted;
+        header.verify = new_header.verify;
+        let mut buf = [0; PK2_ROOT_BLOCK.0 as usize];
+        header.to_writer(&mut buf[..])?;
+
+        // the transaction keeps the old cipher, as a failed commit rolls the
+        // stream back to the index encrypted with it
+        let res = self.transaction(|tx| {
+            // every chain has to be re-encrypted
+            tx.block_manager
+                .load_all(tx.chain_source())
+                .map_err(|e| Error::lookup(Path::new("/"), None, e))?;
+            tx.stream.write_all_at(&buf, 0)?;
+            for chain in tx.block_manager.chains() {
+                crate::io::write_chain(cipher.as_ref(), &mut tx.stream, chain)?;
+            }
+            Ok(())
+        });
+        match res {
+            Ok(()) => {
+                self.cipher = cipher;
+                Ok(())
+            }
+            Err(e) => {
+                // unless the commit failed after completing the journal, in
+                // which case the transaction was rolled forward
+                let mut current = [0; PK2_ROOT_BLOCK.0 as usize];
+                let rolled_forward =
+                    self.stream.read_exact_at(&mut current, 0).is_ok() && current == buf;
+                if rolled_forward {
+                    self.cipher = cipher;
+                    let _ = self.reload_index();
+                }
+                Err(e)
+            }
+        }
     }
 }
 
 #[inline]
-fn check_root(path: &Path) -> ChainLookupResult<&Path> {
+fn check_root(path: &Path) -> Result<&Path> {
     path.strip_prefix("/")
-        .map_err(|_| ChainLookupError::InvalidPath)
+        .map_err(|_| Error::lookup(path, None, ChainLookupError::InvalidPath))
 }
 
 #[cfg(test)]
 mod test {
-    use std::io;
+    use std::io::{self, Write};
     #[test]
     fn create_already_existing() {
         let mut archive = super::Pk2::create_new_in_memory("").unwrap();
@@ -383,4 +1373,1297 @@ mod test {
             Ok(_) => panic!("file was created twice?"),
         };
     }
+
+    #[test]
+    fn delete_directory_recursive() {
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.create_file("/test/foo/bar.baz").unwrap();
+        archive.create_file("/test/qux.baz").unwrap();
+        let chain = archive
+            .root_resolve_path_to_entry_and_parent("/test/foo")
+            .unwrap()
+            .entry()
+            .as_directory()
+            .unwrap()
+            .children_position();
+        match archive.delete_directory("/test", false) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::DirectoryNotEmpty),
+            Ok(_) => panic!("non-empty directory was deleted non-recursively"),
+        };
+        archive.delete_directory("/test", true).unwrap();
+        assert!(archive.open_directory("/test").is_err());
+        assert!(archive.get_chain(chain).is_none());
+        archive.create_file("/test/qux.baz").unwrap();
+    }
+
+    #[test]
+    fn create_directory() {
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.create_directory("/test").unwrap();
+        assert_eq!(
+            archive
+                .open_directory("/test")
+                .unwrap()
+                .entries()
+                .unwrap()
+                .count(),
+            0
+        );
+        match archive.create_directory("/test") {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::AlreadyExists),
+            Ok(_) => panic!("directory was created twice?"),
+        };
+        match archive.create_directory("/foo/bar") {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+            Ok(_) => panic!("directory was created without its parent"),
+        };
+        archive.create_file("/test/foo.baz").unwrap();
+        assert!(archive.open_file("/test/foo.baz").is_ok());
+    }
+
+    #[test]
+    fn create_dir_all() {
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.create_dir_all("/test/foo/bar").unwrap();
+        archive.create_dir_all("/test/foo/bar").unwrap();
+        archive.create_dir_all("/").unwrap();
+        assert!(archive.open_directory("/test/foo/bar").is_ok());
+        archive.create_file("/test/foo.baz").unwrap();
+        match archive.create_dir_all("/test/foo.baz") {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::AlreadyExists),
+            Ok(_) => panic!("directory was created over a file"),
+        };
+    }
+
+    #[test]
+    fn reuse_freed_space() {
+        use std::io::Write;
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.create_directory("/test").unwrap();
+        archive
+            .create_file("/test/foo.baz")
+            .unwrap()
+            .write_all(&[1; 100])
+            .unwrap();
+        let len = archive.stream.len();
+        archive.delete_file("/test/foo.baz").unwrap();
+        archive
+            .create_file("/test/bar.baz")
+            .unwrap()
+            .write_all(&[2; 60])
+            .unwrap();
+        archive
+            .create_file("/test/qux.baz")
+            .unwrap()
+            .write_all(&[3; 40])
+            .unwrap();
+        assert_eq!(archive.stream.len(), len);
+        assert_eq!(archive.read("/test/bar.baz").unwrap(), [2; 60]);
+        assert_eq!(archive.read("/test/qux.baz").unwrap(), [3; 40]);
+        assert_eq!(archive.free_space.free_bytes(), 0);
+
+        // growing a file releases its old region
+        archive
+            .open_file_mut("/test/bar.baz")
+            .unwrap()
+            .write_all(&[4; 100])
+            .unwrap();
+        assert_eq!(archive.free_space.free_bytes(), 60);
+        assert_eq!(archive.read("/test/bar.baz").unwrap(), [4; 100]);
+
+        // blocks of deleted directories are reused by new ones
+        archive.create_directory("/old").unwrap();
+        let len = archive.stream.len();
+        archive.delete_directory("/old", false).unwrap();
+        archive.create_directory("/new").unwrap();
+        assert_eq!(archive.stream.len(), len);
+        assert!(archive.open_directory("/new").is_ok());
+    }
+
+    #[test]
+    fn compact() {
+        use std::io::Write;
+        let mut archive = super::Pk2::create_new_in_memory("169841").unwrap();
+        for i in 0..30 {
+            archive
+                .create_file(format!("/test/{}/foo{}.baz", i % 3, i))
+                .unwrap()
+                .write_all(&[i as u8; 100])
+                .unwrap();
+        }
+        archive.create_directory("/empty").unwrap();
+        archive.delete_directory("/test/1", true).unwrap();
+        for i in (0..30).step_by(4) {
+            let _ = archive.delete_file(format!("/test/{}/foo{}.baz", i % 3, i));
+        }
+        archive.rename("/test/2", "/empty/2").unwrap();
+        let len = archive.stream.len() as u64;
+
+        let mut last_progress = (0, 0);
+        let shrunk = archive
+            .compact_with_progress(|done, total| last_progress = (done, total))
+            .unwrap();
+        assert!(shrunk > 0);
+        assert_eq!(last_progress.0, last_progress.1);
+        let data = archive.stream;
+        assert_eq!(data.len() as u64, len - shrunk);
+
+        let archive = super::Pk2::open_in(data, "169841").unwrap();
+        assert_eq!(archive.free_space.free_bytes(), 0);
+        for i in 0..30 {
+            let path = match i % 3 {
+                0 => format!("/test/0/foo{}.baz", i),
+                1 => continue,
+                _ => format!("/empty/2/foo{}.baz", i),
+            };
+            match archive.read(&path) {
+                Ok(data) => assert_eq!(data, [i as u8; 100]),
+                Err(_) => assert_eq!(i % 4, 0, "{} is missing", path),
+            }
+        }
+        assert!(archive.open_directory("/test/1").is_err());
+        let other = archive
+            .block_manager
+            .resolve_path_to_block_chain_index_at(
+                archive.chain_source(),
+                super::PK2_ROOT_BLOCK,
+                "empty".as_ref(),
+            )
+            .unwrap();
+        let moved = archive
+            .block_manager
+            .resolve_path_to_block_chain_index_at(
+                archive.chain_source(),
+                super::PK2_ROOT_BLOCK,
+                "empty/2".as_ref(),
+            )
+            .unwrap();
+        assert_eq!(
+            archive
+                .get_chain(moved)
+                .unwrap()
+                .find_block_chain_index_of(super::PK2_PARENT_DIR_IDENT),
+            Ok(other)
+        );
+    }
+
+    #[test]
+    fn read_from_multiple_threads() {
+        use std::io::Write;
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<super::Pk2>();
+
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        for i in 0..4u8 {
+            archive
+                .create_file(format!("/test/foo{}.baz", i))
+                .unwrap()
+                .write_all(&[i; 64])
+                .unwrap();
+        }
+        let archive = &archive;
+        std::thread::scope(|s| {
+            for i in 0..4u8 {
+                s.spawn(move || {
+                    let file = archive.open_file(format!("/test/foo{}.baz", i)).unwrap();
+                    let mut buf = [0; 16];
+                    crate::ReadAt::read_exact_at(&file, &mut buf, 48).unwrap();
+                    assert_eq!(buf, [i; 16]);
+                    assert_eq!(
+                        archive.read(format!("/test/foo{}.baz", i)).unwrap(),
+                        [i; 64]
+                    );
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn read_bytes() {
+        use std::io::Write;
+        let mut archive = super::Pk2::create_new_in_memory("169841").unwrap();
+        archive
+            .create_file("/test/foo.baz")
+            .unwrap()
+            .write_all(b"foobar")
+            .unwrap();
+        assert_eq!(archive.read_bytes("/test/foo.baz").unwrap(), b"foobar");
+        assert!(archive.read_bytes("/test").is_err());
+
+        let data = archive.stream;
+        let archive = super::Pk2::open_in_read_only(&data[..], "169841").unwrap();
+        assert_eq!(archive.read_bytes("/test/foo.baz").unwrap(), b"foobar");
+    }
+
+    #[test]
+    fn lazy_index() {
+        use super::IndexLoading;
+        use std::io::{Read, Write};
+        let mut archive = super::Pk2::create_new_in_memory("169841").unwrap();
+        for path in ["/a/foo.baz", "/b/bar.baz", "/c/d/qux.baz"] {
+            archive
+                .create_file(path)
+                .unwrap()
+                .write_all(path.as_bytes())
+                .unwrap();
+        }
+        let data = archive.stream;
+
+        let loading = IndexLoading::Lazy {
+            max_resident: Some(2),
+        };
+        let mut archive = super::Pk2::open_in_read_only_with(&data[..], "169841", loading).unwrap();
+        assert_eq!(archive.block_manager.resident_chains(), 1);
+        assert_eq!(archive.read("/c/d/qux.baz").unwrap(), b"/c/d/qux.baz");
+        assert_eq!(archive.block_manager.resident_chains(), 2);
+        assert_eq!(archive.read("/a/foo.baz").unwrap(), b"/a/foo.baz");
+        assert_eq!(archive.block_manager.resident_chains(), 2);
+        archive.trim_index_cache();
+        assert_eq!(archive.block_manager.resident_chains(), 2);
+        // the most recently used chain is kept around
+        let entry = archive.root_resolve_path_to_entry_and_parent("/a").unwrap();
+        let chain = entry.entry().as_directory().unwrap().children_position();
+        assert!(archive.block_manager.get(chain).is_some());
+        assert_eq!(archive.read("/c/d/qux.baz").unwrap(), b"/c/d/qux.baz");
+
+        // files and directories outlive the eviction of their chains
+        let dir = archive.open_directory("/c/d").unwrap();
+        let mut file = archive.open_file("/c/d/qux.baz").unwrap();
+        for path in ["/a/foo.baz", "/b/bar.baz"] {
+            assert_eq!(archive.read(path).unwrap(), path.as_bytes());
+            assert_eq!(archive.block_manager.resident_chains(), 2);
+        }
+        assert_eq!(dir.name(), "d");
+        assert_eq!(dir.files().unwrap().count(), 1);
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"/c/d/qux.baz");
+        assert_eq!(archive.block_manager.resident_chains(), 2);
+        drop((dir, file));
+        // reloading evicted chains reuses the names interned before
+        let names = archive.index_memory_usage().names;
+        for path in ["/a/foo.baz", "/b/bar.baz", "/c/d/qux.baz"] {
+            archive.read(path).unwrap();
+        }
+        assert_eq!(archive.index_memory_usage().names, names);
+
+        let mut archive = super::Pk2::open_in_with(data, "169841", loading).unwrap();
+        archive.create_file("/c/d/new.baz").unwrap();
+        archive.delete_file("/b/bar.baz").unwrap();
+        archive.compact().unwrap();
+        assert!(archive.open_file("/c/d/new.baz").is_ok());
+        assert!(archive.open_file("/b/bar.baz").is_err());
+        assert_eq!(archive.read("/a/foo.baz").unwrap(), b"/a/foo.baz");
+    }
+
+    #[test]
+    fn walk() {
+        use super::fs::EntryKind;
+        use std::path::Path;
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        for path in ["/a/b/c/1.txt", "/a/b/2.txt", "/a/d/3.txt", "/4.txt"] {
+            archive.create_file(path).unwrap();
+        }
+        let collect = |walk: super::walk::Walk<'_, _, _>| {
+            walk.map(|entry| {
+                let entry = entry.unwrap();
+                (entry.path().to_owned(), entry.depth(), entry.kind())
+            })
+            .collect::<Vec<_>>()
+        };
+        let paths = |entries: &[(std::path::PathBuf, usize, EntryKind)]| {
+            entries
+                .iter()
+                .map(|(path, ..)| path.to_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let entries = collect(archive.walk("/").unwrap());
+        assert_eq!(
+            paths(&entries),
+            [
+                "/",
+                "/a",
+                "/a/b",
+                "/a/b/c",
+                "/a/b/c/1.txt",
+                "/a/b/2.txt",
+                "/a/d",
+                "/a/d/3.txt",
+                "/4.txt"
+            ]
+        );
+        assert_eq!(entries[4].1, 4);
+        assert_eq!(entries[4].2, EntryKind::File);
+        assert_eq!(entries[3].2, EntryKind::Directory);
+
+        let entries = collect(archive.walk("/a").unwrap().max_depth(1));
+        assert_eq!(paths(&entries), ["/a", "/a/b", "/a/d"]);
+
+        let entries = collect(archive.walk("/a/b").unwrap().contents_first(true));
+        assert_eq!(
+            paths(&entries),
+            ["/a/b/c/1.txt", "/a/b/c", "/a/b/2.txt", "/a/b"]
+        );
+
+        let mut walk = archive.walk("/a").unwrap();
+        let mut visited = Vec::new();
+        while let Some(entry) = walk.next() {
+            let entry = entry.unwrap();
+            if entry.path() == Path::new("/a/b") {
+                walk.skip_current_dir();
+            }
+            visited.push(entry.into_path());
+        }
+        assert_eq!(visited, ["/a", "/a/b", "/a/d", "/a/d/3.txt"].map(Path::new));
+    }
+
+    #[test]
+    fn glob() {
+        use super::IndexLoading;
+        use std::io::Write;
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        for path in [
+            "/res/model/a.ddj",
+            "/res/model/deep/b.DDJ",
+            "/res/c.ddj",
+            "/res/c.txt",
+            "/other/d.ddj",
+        ] {
+            archive
+                .create_file(path)
+                .unwrap()
+                .write_all(path.as_bytes())
+                .unwrap();
+        }
+        fn glob<B: crate::ReadAt, M>(archive: &super::Pk2<B, M>, pattern: &str) -> Vec<String> {
+            let mut paths = archive
+                .glob(pattern)
+                .unwrap()
+                .map(|res| res.unwrap().0.to_str().unwrap().to_owned())
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        }
+        assert_eq!(
+            glob(&archive, "/RES/**/*.ddj"),
+            ["/res/c.ddj", "/res/model/a.ddj", "/res/model/deep/b.DDJ"]
+        );
+        assert_eq!(glob(&archive, "/*/?.ddj"), ["/other/d.ddj", "/res/c.ddj"]);
+        assert_eq!(glob(&archive, "/res/c.[a-t]xt"), ["/res/c.txt"]);
+        assert_eq!(glob(&archive, "/**/deep/**"), ["/res/model/deep/b.DDJ"]);
+        let dir = archive.open_directory("/res").unwrap();
+        let paths = dir
+            .glob("model/*")
+            .unwrap()
+            .map(|res| res.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, [std::path::Path::new("model/a.ddj")]);
+
+        // literal components are looked up without visiting their siblings
+        let data = archive.stream.clone();
+        let loading = IndexLoading::Lazy { max_resident: None };
+        let archive = super::Pk2::open_in_read_only_with(&data[..], "", loading).unwrap();
+        assert_eq!(glob(&archive, "/res/model/*.ddj"), ["/res/model/a.ddj"]);
+        // root, res and model
+        assert_eq!(archive.block_manager.resident_chains(), 3);
+    }
+
+    #[test]
+    fn metadata() {
+        use super::fs::EntryKind;
+        use std::io::Write;
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive
+            .create_file("/test/foo.baz")
+            .unwrap()
+            .write_all(b"12345")
+            .unwrap();
+        let meta = archive.metadata("/TEST/foo.baz").unwrap();
+        assert_eq!(meta.kind(), EntryKind::File);
+        assert_eq!(meta.len(), 5);
+        assert!(meta.modify_time().is_some());
+        let file = archive.open_file("/test/foo.baz").unwrap();
+        assert_eq!(
+            meta.data_offset(),
+            Some(file.metadata().data_offset().unwrap())
+        );
+        let dir = archive.metadata("/test").unwrap();
+        assert!(dir.is_dir());
+        assert_eq!(dir.data_offset(), None);
+        assert_eq!(dir.chain(), crate::constants::PK2_ROOT_BLOCK);
+        assert_ne!(meta.chain(), dir.chain());
+        assert!(archive.metadata("/").unwrap().is_dir());
+        assert!(archive.exists("/test"));
+        assert!(!archive.exists("/test/bar.baz"));
+        assert!(matches!(
+            archive.metadata("/test/bar.baz").unwrap_err(),
+            crate::Error::Lookup {
+                source: crate::ChainLookupError::NotFound,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn error_context() {
+        use crate::{ChainLookupError, Error};
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.create_file("/res/foo.txt").unwrap();
+        match archive.open_file("/res/missing/bar.txt").err().unwrap() {
+            Error::Lookup {
+                path,
+                component,
+                source,
+            } => {
+                assert_eq!(path, std::path::Path::new("/res/missing/bar.txt"));
+                assert_eq!(component.as_deref(), Some("missing"));
+                assert_eq!(source, ChainLookupError::NotFound);
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        match archive.open_file("/res/foo.txt/bar.txt").err().unwrap() {
+            Error::Lookup {
+                component, source, ..
+            } => {
+                assert_eq!(component.as_deref(), Some("foo.txt"));
+                assert_eq!(source, ChainLookupError::ExpectedDirectory);
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        let err = archive.open_directory("/res/foo.txt").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "failed to look up \"/res/foo.txt\": \"foo.txt\" is not a directory"
+        );
+        // the error survives the round trip through io::Error
+        let err = std::io::Error::from(err);
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert!(matches!(Error::from(err), Error::Lookup { .. }));
+
+        let entries = archive
+            .walk("/")
+            .unwrap()
+            .collect::<crate::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(entries.len(), 3);
+    }
+
+    #[test]
+    fn create_file_streaming() {
+        use std::io::Write;
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        let mut writer = archive.create_file_streaming("/test/big.bin").unwrap();
+        for chunk in 0..16u8 {
+            writer.write_all(&[chunk; 1000]).unwrap();
+        }
+        assert_eq!(writer.size(), 16_000);
+        writer.finish().unwrap();
+        // empty files are written out as well
+        archive
+            .create_file_streaming("/test/empty.bin")
+            .unwrap()
+            .finish()
+            .unwrap();
+        // unfinished writers discard their file
+        let len = archive.stream.len();
+        let mut writer = archive.create_file_streaming("/test/partial.bin").unwrap();
+        writer.write_all(&[0xFF; 1000]).unwrap();
+        drop(writer);
+        assert!(!archive.exists("/test/partial.bin"));
+        archive
+            .create_file("/test/small.bin")
+            .unwrap()
+            .write_all(&[1; 1000])
+            .unwrap();
+        assert_eq!(archive.stream.len(), len + 1000);
+
+        let data = archive.stream;
+        let archive = super::Pk2::open_in_read_only(&data[..], "").unwrap();
+        let read = archive.read("/test/big.bin").unwrap();
+        assert_eq!(read.len(), 16_000);
+        assert!(read
+            .chunks(1000)
+            .enumerate()
+            .all(|(i, chunk)| chunk.iter().all(|&b| b == i as u8)));
+        assert_eq!(archive.metadata("/test/empty.bin").unwrap().len(), 0);
+        assert!(!archive.exists("/test/partial.bin"));
+        assert_eq!(archive.read("/test/small.bin").unwrap(), [1; 1000]);
+    }
+
+    #[test]
+    fn file_options() {
+        use std::io::Write;
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        let err = archive.file_options().open("/test/foo.baz").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let mut file = archive
+            .file_options()
+            .create(true)
+            .open("/test/foo.baz")
+            .unwrap();
+        file.write_all(b"hello world").unwrap();
+        file.commit().unwrap();
+        let err = archive
+            .file_options()
+            .create_new(true)
+            .open("/test/foo.baz")
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        // writing shorter data without truncating keeps the old tail
+        let mut file = archive.file_options().open("/test/foo.baz").unwrap();
+        file.write_all(b"HELLO").unwrap();
+        file.commit().unwrap();
+        assert_eq!(archive.read("/test/foo.baz").unwrap(), b"HELLO world");
+
+        let mut file = archive
+            .file_options()
+            .truncate(true)
+            .open("/test/foo.baz")
+            .unwrap();
+        file.write_all(b"bye").unwrap();
+        file.commit().unwrap();
+        assert_eq!(archive.read("/test/foo.baz").unwrap(), b"bye");
+
+        let mut file = archive
+            .file_options()
+            .append(true)
+            .open("/test/foo.baz")
+            .unwrap();
+        file.write_all(b" bye").unwrap();
+        file.commit().unwrap();
+        assert_eq!(archive.read("/test/foo.baz").unwrap(), b"bye bye");
+
+        // truncating to nothing and creating empty files is persisted as well
+        let file = archive
+            .file_options()
+            .truncate(true)
+            .open("/test/foo.baz")
+            .unwrap();
+        file.commit().unwrap();
+        archive
+            .create_file("/test/empty.baz")
+            .unwrap()
+            .commit()
+            .unwrap();
+        let data = archive.stream;
+        let archive = super::Pk2::open_in_read_only(&data[..], "").unwrap();
+        assert_eq!(archive.read("/test/foo.baz").unwrap(), b"");
+        assert!(archive.exists("/test/empty.baz"));
+    }
+
+    #[test]
+    fn index_memory_usage() {
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        let empty = archive.index_memory_usage();
+        // the root chain and the virtual root
+        assert_eq!(empty.chains, 2);
+        for i in 0..50 {
+            archive.create_file(format!("/dir/file{}.txt", i)).unwrap();
+        }
+        let usage = archive.index_memory_usage();
+        assert_eq!(usage.chains, 3);
+        // `.` and `..` of the root and the new directory, plus its 50 files
+        assert_eq!(usage.entries, empty.entries + 1 + 2 + 50);
+        // the root has no `..` link, so that name is new as well
+        assert_eq!(usage.names, empty.names + 2 + 50);
+        assert!(usage.bytes > empty.bytes);
+
+        // names repeating in other directories are stored only once
+        for i in 0..50 {
+            archive
+                .create_file(format!("/other/file{}.txt", i))
+                .unwrap();
+        }
+        let repeated = archive.index_memory_usage();
+        assert_eq!(repeated.entries, usage.entries + 1 + 2 + 50);
+        assert_eq!(repeated.names, usage.names + 1);
+    }
+
+    #[test]
+    fn transaction() {
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive
+            .create_file("/keep.txt")
+            .unwrap()
+            .write_all(b"keep")
+            .unwrap();
+        archive
+            .transaction(|tx| -> io::Result<()> {
+                tx.create_file("/a.txt")?.write_all(b"a")?;
+                tx.create_file("/dir/b.txt")?.write_all(b"b")?;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(archive.read("/a.txt").unwrap(), b"a");
+        assert_eq!(archive.read("/dir/b.txt").unwrap(), b"b");
+
+        let len = archive.stream.len();
+        let res = archive.transaction(|tx| {
+            tx.create_file("/c.txt")?.write_all(b"c")?;
+            tx.delete_file("/keep.txt")?;
+            Err::<(), _>(io::Error::other("abort"))
+        });
+        assert!(res.is_err());
+        assert_eq!(archive.stream.len(), len);
+        assert!(!archive.exists("/c.txt"));
+        assert_eq!(archive.read("/keep.txt").unwrap(), b"keep");
+
+        // space freed by a committed transaction is reused afterwards
+        archive
+            .transaction(|tx| tx.delete_file("/keep.txt"))
+            .unwrap();
+        archive
+            .create_file("/reuse.txt")
+            .unwrap()
+            .write_all(b"four")
+            .unwrap();
+        assert_eq!(archive.stream.len(), len);
+    }
+
+    #[test]
+    fn transaction_on_file() {
+        use std::panic::{self, AssertUnwindSafe};
+        let temp = |name: &str| {
+            std::env::temp_dir().join(format!(
+                "pk2-transaction-{}-{}.pk2",
+                std::process::id(),
+                name
+            ))
+        };
+        let (path, crashed) = (temp("archive"), temp("crashed"));
+        let journal = super::transaction::journal_path(&path);
+        let crashed_journal = super::transaction::journal_path(&crashed);
+        let _ = std::fs::remove_file(&path);
+        let mut archive = super::Pk2::create_new(&path, "").unwrap();
+        archive
+            .create_file("/keep.txt")
+            .unwrap()
+            .write_all(b"keep")
+            .unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        let unchanged = |archive: &super::Pk2| {
+            assert!(!journal.exists());
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+            assert!(!archive.exists("/a.txt"));
+            assert_eq!(archive.read("/keep.txt").unwrap(), b"keep");
+        };
+
+        let res = archive.transaction(|tx| {
+            tx.create_file("/a.txt")?.write_all(b"a")?;
+            tx.delete_file("/keep.txt")?;
+            Err::<(), _>(io::Error::other("abort"))
+        });
+        assert!(res.is_err());
+        unchanged(&archive);
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            archive.transaction(|tx| -> io::Result<()> {
+                tx.create_file("/a.txt")?.write_all(b"a")?;
+                tx.delete_file("/keep.txt")?;
+                panic!("abort")
+            })
+        }));
+        assert!(res.is_err());
+        unchanged(&archive);
+
+        // a copy taken while the transaction runs is what a crash leaves
+        archive
+            .transaction(|tx| -> io::Result<()> {
+                tx.create_file("/a.txt")?.write_all(b"a")?;
+                tx.delete_file("/keep.txt")?;
+                std::fs::copy(&path, &crashed)?;
+                std::fs::copy(&journal, &crashed_journal)?;
+                Ok(())
+            })
+            .unwrap();
+        assert!(!journal.exists());
+        assert_eq!(archive.read("/a.txt").unwrap(), b"a");
+        assert!(!archive.exists("/keep.txt"));
+        drop(archive);
+
+        // the crashed copy still holds the archive from before the transaction
+        let archive = super::Pk2::open_read_only(&crashed, "").unwrap();
+        assert!(!archive.exists("/a.txt"));
+        assert_eq!(archive.read("/keep.txt").unwrap(), b"keep");
+        drop(archive);
+        let archive = super::Pk2::open(&crashed, "").unwrap();
+        assert!(!crashed_journal.exists());
+        assert_eq!(std::fs::metadata(&crashed).unwrap().len(), len);
+        assert!(!archive.exists("/a.txt"));
+        assert_eq!(archive.read("/keep.txt").unwrap(), b"keep");
+        drop(archive);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&crashed).unwrap();
+    }
+
+    #[test]
+    fn custom_salt() {
+        use crate::constants::PK2_SALT;
+        use crate::Key;
+        let salt = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0, 0x11, 0x22];
+        let mut archive = super::Pk2::create_new_in_memory(Key::with_salt("169841", salt)).unwrap();
+        archive
+            .create_file("/a.txt")
+            .unwrap()
+            .write_all(b"a")
+            .unwrap();
+        let data = archive.stream.clone();
+        assert!(matches!(
+            super::Pk2::open_in(data.clone(), "169841"),
+            Err(crate::Error::Key { .. })
+        ));
+        let reopened = super::Pk2::open_in(data.clone(), Key::with_salt("169841", salt)).unwrap();
+        assert_eq!(reopened.read("/a.txt").unwrap(), b"a");
+        // the raw key is the key xored with the salt
+        let raw = b"169841"
+            .iter()
+            .zip(&salt)
+            .map(|(k, s)| k ^ s)
+            .collect::<Vec<_>>();
+        let reopened = super::Pk2::open_in(data, Key::raw(raw)).unwrap();
+        assert_eq!(reopened.read("/a.txt").unwrap(), b"a");
+
+        let data = super::Pk2::create_new_in_memory("169841").unwrap().stream;
+        assert!(super::Pk2::open_in(data, Key::with_salt("169841", PK2_SALT)).is_ok());
+        assert!(super::Pk2::create_new_in_memory(Key::with_salt("169841", [0; 57])).is_err());
+    }
+
+    #[test]
+    fn custom_cipher() {
+        use crate::{Blowfish, IndexCipher};
+
+        #[derive(Clone)]
+        struct Xor(u8);
+
+        impl IndexCipher for Xor {
+            fn encrypt(&self, data: &mut [u8]) {
+                data.iter_mut().for_each(|b| *b ^= self.0);
+            }
+
+            fn decrypt(&self, data: &mut [u8]) {
+                self.encrypt(data)
+            }
+        }
+
+        let mut archive = super::Pk2::create_new_in_memory(Xor(0x5A)).unwrap();
+        for i in 0..30 {
+            archive
+                .create_file(format!("/dir/{}.txt", i))
+                .unwrap()
+                .write_all(i.to_string().as_bytes())
+                .unwrap();
+        }
+        archive.delete_file("/dir/3.txt").unwrap();
+        let data = archive.stream.clone();
+        assert!(matches!(
+            super::Pk2::open_in(data.clone(), "169841"),
+            Err(crate::Error::Key { .. })
+        ));
+        assert!(matches!(
+            super::Pk2::open_in(data.clone(), Xor(0x5B)),
+            Err(crate::Error::Key { .. })
+        ));
+        let reopened = super::Pk2::open_in(data, Xor(0x5A)).unwrap();
+        assert!(reopened.check().unwrap().is_ok());
+        assert!(!reopened.exists("/dir/3.txt"));
+        assert_eq!(reopened.read("/dir/29.txt").unwrap(), b"29");
+
+        // blowfish is just the default cipher
+        let data = super::Pk2::create_new_in_memory("169841").unwrap().stream;
+        let bf = Blowfish::new(b"169841").unwrap();
+        assert!(super::Pk2::open_in(data, bf).is_ok());
+    }
+
+    #[test]
+    fn rekey() {
+        let mut archive = super::Pk2::create_new_in_memory("169841").unwrap();
+        archive
+            .create_file("/a.txt")
+            .unwrap()
+            .write_all(b"a")
+            .unwrap();
+        for i in 0..30 {
+            archive.create_file(format!("/dir/{}.txt", i)).unwrap();
+        }
+        let len = archive.stream.len();
+        archive.rekey("abcdef").unwrap();
+        assert_eq!(archive.stream.len(), len);
+        assert_eq!(archive.read("/a.txt").unwrap(), b"a");
+
+        let data = archive.stream.clone();
+        assert!(matches!(
+            super::Pk2::open_in(data.clone(), "169841"),
+            Err(crate::Error::Key { .. })
+        ));
+        let reopened = super::Pk2::open_in(data, "abcdef").unwrap();
+        assert_eq!(reopened.read("/a.txt").unwrap(), b"a");
+        assert!(reopened.exists("/dir/29.txt"));
+
+        archive.rekey("").unwrap();
+        assert!(!archive.header().unwrap().encrypted);
+        let reopened = super::Pk2::open_in(archive.stream.clone(), "").unwrap();
+        assert_eq!(reopened.read("/a.txt").unwrap(), b"a");
+        assert!(reopened.exists("/dir/29.txt"));
+        assert!(reopened.check().unwrap().is_ok());
+
+        assert!(matches!(
+            archive.rekey("abc"),
+            Err(crate::Error::Key { source: Some(_) })
+        ));
+    }
+
+    // fails the next `failures` writes into the first `protected` bytes
+    struct FlakyStream {
+        data: Vec<u8>,
+        protected: u64,
+        failures: u32,
+    }
+
+    impl crate::ReadAt for FlakyStream {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            self.data.read_at(buf, offset)
+        }
+
+        fn stream_len(&self) -> io::Result<u64> {
+            self.data.stream_len()
+        }
+    }
+
+    impl crate::WriteAt for FlakyStream {
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+            if offset < self.protected && self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::other("injected write failure"));
+            }
+            self.data.write_at(buf, offset)
+        }
+
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            crate::WriteAt::set_len(&mut self.data, len)
+        }
+    }
+
+    #[test]
+    fn rekey_commit_failure() {
+        let stream = FlakyStream {
+            data: Vec::new(),
+            protected: 0,
+            failures: 0,
+        };
+        let mut archive = super::Pk2::create_new_in(stream, "169841").unwrap();
+        archive
+            .create_file("/a.txt")
+            .unwrap()
+            .write_all(b"a")
+            .unwrap();
+
+        // the commit fails before anything was applied, so the old key stays
+        archive.stream.protected = archive.stream.data.len() as u64;
+        archive.stream.failures = 1;
+        assert!(archive.rekey("abcdef").is_err());
+        archive.create_file("/b.txt").unwrap();
+        let reopened = super::Pk2::open_in(archive.stream.data.clone(), "169841").unwrap();
+        assert_eq!(reopened.read("/a.txt").unwrap(), b"a");
+        assert!(reopened.exists("/b.txt"));
+
+        // the commit fails after the journal was completed, so recovering it
+        // applies the new key after all
+        let journal =
+            std::env::temp_dir().join(format!("pk2-rekey-{}.journal", std::process::id()));
+        archive.journal = Some(journal.clone());
+        archive.stream.protected = archive.stream.data.len() as u64;
+        archive.stream.failures = 1;
+        assert!(archive.rekey("abcdef").is_err());
+        assert!(!journal.exists());
+        archive.create_file("/c.txt").unwrap();
+        let reopened = super::Pk2::open_in(archive.stream.data.clone(), "abcdef").unwrap();
+        assert_eq!(reopened.read("/a.txt").unwrap(), b"a");
+        assert!(reopened.exists("/b.txt"));
+        assert!(reopened.exists("/c.txt"));
+    }
+
+    #[test]
+    fn check() {
+        use super::check::Finding;
+        use super::{ChainIndex, PK2_ROOT_BLOCK};
+        use std::path::PathBuf;
+
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive
+            .create_file("/a/x.txt")
+            .unwrap()
+            .write_all(b"x")
+            .unwrap();
+        archive
+            .create_file("/b.txt")
+            .unwrap()
+            .write_all(b"b")
+            .unwrap();
+        assert!(archive.check().unwrap().is_ok());
+
+        let entry_offset = |archive: &super::Pk2<Vec<u8>>, chain, idx| {
+            archive
+                .get_chain(chain)
+                .unwrap()
+                .stream_offset_for_entry(idx)
+                .unwrap()
+                .0 as usize
+        };
+        let root = archive.get_chain(PK2_ROOT_BLOCK).unwrap();
+        let a = entry_offset(&archive, PK2_ROOT_BLOCK, root.position("a").unwrap());
+        let b = entry_offset(&archive, PK2_ROOT_BLOCK, root.position("b.txt").unwrap());
+        let a_chain = root.find_block_chain_index_of("a").unwrap();
+        let a_dir = archive.get_chain(a_chain).unwrap();
+        let x = entry_offset(&archive, a_chain, a_dir.position("x.txt").unwrap());
+        let a_parent = entry_offset(&archive, a_chain, a_dir.position("..").unwrap());
+        let empty = |idx| entry_offset(&archive, PK2_ROOT_BLOCK, idx);
+        let (invalid, shared, past_eof, last) = (empty(5), empty(6), empty(7), empty(19));
+        let stream_len = archive.stream.len() as u64;
+
+        let stream = &mut archive.stream;
+        // data position and size, children position and next block fields
+        let position = 1 + 81 + 24;
+        let next_block = position + 12;
+        stream.copy_within(x + position..x + position + 12, b + position);
+        stream[invalid] = 9;
+        stream.copy_within(a..a + 128, shared);
+        stream[shared + 1] = b'c';
+        stream.copy_within(a..a + 128, past_eof);
+        stream[past_eof + 1] = b'd';
+        stream[past_eof + position..past_eof + position + 8]
+            .copy_from_slice(&(stream_len + 4096).to_le_bytes());
+        stream[a_parent + position..a_parent + position + 8]
+            .copy_from_slice(&a_chain.0.to_le_bytes());
+        stream[last + next_block..last + next_block + 8]
+            .copy_from_slice(&PK2_ROOT_BLOCK.0.to_le_bytes());
+
+        let findings = archive.check().unwrap().into_findings();
+        let expected = [
+            Finding::DataOverlap {
+                first: PathBuf::from("/b.txt"),
+                second: PathBuf::from("/a/x.txt"),
+            },
+            Finding::InvalidEntryType {
+                chain: PK2_ROOT_BLOCK,
+                entry: 5,
+                ty: 9,
+            },
+            Finding::SharedDirectory {
+                chain: a_chain,
+                first: PathBuf::from("/a"),
+                second: PathBuf::from("/c"),
+            },
+            Finding::ChainPastEof {
+                directory: PathBuf::from("/d"),
+                block: (ChainIndex(stream_len + 4096)).into(),
+            },
+            Finding::WrongParentLink {
+                directory: PathBuf::from("/a"),
+                expected: PK2_ROOT_BLOCK,
+                found: a_chain,
+            },
+            Finding::BlockCycle {
+                directory: PathBuf::from("/"),
+                block: PK2_ROOT_BLOCK.into(),
+            },
+        ];
+        for finding in &expected {
+            assert!(
+                findings.contains(finding),
+                "{:?} not in {:?}",
+                finding,
+                findings
+            );
+        }
+    }
+
+    #[test]
+    fn open_salvage() {
+        use super::{ChainIndex, Pk2, ReadOnly, Skipped, PK2_ROOT_BLOCK};
+        use std::path::PathBuf;
+
+        let mut archive = Pk2::create_new_in_memory("").unwrap();
+        archive
+            .create_file("/a/x.txt")
+            .unwrap()
+            .write_all(b"x")
+            .unwrap();
+        archive
+            .create_file("/b.txt")
+            .unwrap()
+            .write_all(b"b")
+            .unwrap();
+        let root = archive.get_chain(PK2_ROOT_BLOCK).unwrap();
+        let a = root
+            .stream_offset_for_entry(root.position("a").unwrap())
+            .unwrap()
+            .0 as usize;
+        let a_chain = root.find_block_chain_index_of("a").unwrap();
+        let a_dir = archive.get_chain(a_chain).unwrap();
+        let a_last = a_dir.stream_offset_for_entry(19).unwrap().0 as usize;
+        let empty = |idx| root.stream_offset_for_entry(idx).unwrap().0 as usize;
+        let (invalid, past_eof) = (empty(5), empty(6));
+        let past_eof_chain = ChainIndex(archive.stream.len() as u64 + 4096);
+
+        let mut stream = archive.stream.clone();
+        let position = 1 + 81 + 24;
+        stream[invalid] = 9;
+        stream.copy_within(a..a + 128, past_eof);
+        stream[past_eof + 1] = b'd';
+        stream[past_eof + position..past_eof + position + 8]
+            .copy_from_slice(&past_eof_chain.0.to_le_bytes());
+        stream[a_last + position + 12..a_last + position + 20]
+            .copy_from_slice(&past_eof_chain.0.to_le_bytes());
+        assert!(Pk2::<_, ReadOnly>::open_in_read_only(stream.clone(), "").is_err());
+
+        let (archive, skipped) = Pk2::<_, ReadOnly>::open_in_salvage(stream, "").unwrap();
+        assert_eq!(
+            skipped,
+            [
+                Skipped::Entry {
+                    directory: PathBuf::from("/"),
+                    entry: 5,
+                    ty: 9,
+                },
+                Skipped::Directory {
+                    directory: PathBuf::from("/d"),
+                    error: io::ErrorKind::UnexpectedEof,
+                },
+                Skipped::Block {
+                    directory: PathBuf::from("/a"),
+                    block: past_eof_chain.into(),
+                    error: io::ErrorKind::UnexpectedEof,
+                },
+            ]
+        );
+        assert!(!archive.exists("/d"));
+        assert_eq!(archive.read("/a/x.txt").unwrap(), b"x");
+        assert_eq!(archive.read("/b.txt").unwrap(), b"b");
+    }
+
+    #[test]
+    fn stats() {
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive
+            .create_file("/a/x.txt")
+            .unwrap()
+            .write_all(&[0; 100])
+            .unwrap();
+        archive
+            .create_file("/b.txt")
+            .unwrap()
+            .write_all(&[0; 50])
+            .unwrap();
+        for i in 0..25 {
+            archive.create_file(format!("/a/{}", i)).unwrap();
+        }
+        archive.delete_file("/b.txt").unwrap();
+
+        let stats = archive.stats().unwrap();
+        assert_eq!(stats.stream_len, archive.stream.len() as u64);
+        assert_eq!(stats.files, 26);
+        assert_eq!(stats.file_data, 100);
+        assert_eq!(stats.orphaned, 50);
+        assert_eq!(stats.chains.len(), 2);
+        assert_eq!(stats.index, 3 * super::PK2_FILE_BLOCK_SIZE as u64);
+        let a = stats
+            .chains
+            .iter()
+            .find(|chain| chain.path == *"/a")
+            .unwrap();
+        assert_eq!(a.blocks, 2);
+        assert_eq!(a.empty_entries, 40 - 28);
+        // the second block was appended after the data of a file
+        assert_eq!(a.discontiguous_blocks, 1);
+    }
+
+    #[test]
+    fn transaction_recovery() {
+        let path = std::env::temp_dir().join(format!("pk2-recovery-{}.pk2", std::process::id()));
+        let journal = super::transaction::journal_path(&path);
+        let mut archive = super::Pk2::create_new(&path, "").unwrap();
+        archive
+            .create_file("/a.txt")
+            .unwrap()
+            .write_all(b"a")
+            .unwrap();
+        drop(archive);
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        // a transaction that died before its journal was completed
+        let mut header = b"PK2JRNL1".to_vec();
+        header.extend_from_slice(&len.to_le_bytes());
+        std::fs::write(&journal, header).unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&[0xAA; 4096]).unwrap();
+        drop(file);
+
+        let archive = super::Pk2::open(&path, "").unwrap();
+        assert!(!journal.exists());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(archive.read("/a.txt").unwrap(), b"a");
+        drop(archive);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_read_only() {
+        use std::io::Write;
+        let path =
+            std::env::temp_dir().join(format!("pk2_open_read_only_{}.pk2", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut archive = super::Pk2::create_new(&path, "169841").unwrap();
+            archive
+                .create_file("/test/foo.baz")
+                .unwrap()
+                .write_all(b"foobar")
+                .unwrap();
+        }
+        let archive = super::Pk2::open_read_only(&path, "169841").unwrap();
+        assert_eq!(archive.read("/test/foo.baz").unwrap(), b"foobar");
+        drop(archive);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_with_any_key() {
+        use crate::constants::{PK2_KNOWN_KEYS, PK2_ROOT_BLOCK};
+        let path =
+            std::env::temp_dir().join(format!("pk2_open_with_any_key_{}.pk2", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        drop(super::Pk2::create_new(&path, "169841").unwrap());
+        // too short keys are skipped just like wrong ones
+        let wrong: [&[u8]; 2] = [b"abc", b"wrong key"];
+        let candidates = wrong
+            .iter()
+            .copied()
+            .chain(PK2_KNOWN_KEYS.iter().map(|&(_, key)| key));
+        let (archive, key) = super::Pk2::open_read_only_with_any_key(&path, candidates).unwrap();
+        assert_eq!(key, b"169841");
+        assert!(archive.open_directory("/").is_ok());
+        drop(archive);
+        let (_, key) = super::Pk2::open_with_any_key(&path, ["wrong key", "169841"]).unwrap();
+        assert_eq!(key, "169841");
+        assert!(matches!(
+            super::Pk2::open_read_only_with_any_key(&path, ["wrong key"]),
+            Err(crate::Error::Key { .. })
+        ));
+        std::fs::remove_file(&path).unwrap();
+
+        // a key passing the header's checksum is still rejected if the root
+        // block doesn't decrypt to the root directory
+        let mut data = super::Pk2::create_new_in_memory("169841").unwrap().stream;
+        data[PK2_ROOT_BLOCK.0 as usize..][..8].copy_from_slice(&[0; 8]);
+        assert!(matches!(
+            super::Pk2::<_, super::ReadOnly>::probe_key(&data, ["169841"]),
+            Err(crate::Error::Key { .. })
+        ));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn open_mmap() {
+        use std::io::Write;
+        let path = std::env::temp_dir().join(format!("pk2_open_mmap_{}.pk2", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut archive = super::Pk2::create_new(&path, "169841").unwrap();
+            archive
+                .create_file("/test/foo.baz")
+                .unwrap()
+                .write_all(b"foobar")
+                .unwrap();
+        }
+        let archive = unsafe { super::Pk2::open_mmap(&path, "169841") }.unwrap();
+        assert_eq!(archive.read_bytes("/test/foo.baz").unwrap(), b"foobar");
+        assert_eq!(archive.read("/test/foo.baz").unwrap(), b"foobar");
+        drop(archive);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rename_file() {
+        use std::io::{Read, Write};
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive
+            .create_file("/test/foo.baz")
+            .unwrap()
+            .write_all(b"foo")
+            .unwrap();
+        archive.create_directory("/other").unwrap();
+        archive.rename("/test/foo.baz", "/test/bar.baz").unwrap();
+        assert!(archive.open_file("/test/foo.baz").is_err());
+        archive.rename("/test/bar.baz", "/other/baz.baz").unwrap();
+        assert!(archive.open_file("/test/bar.baz").is_err());
+        let mut buf = Vec::new();
+        archive
+            .open_file("/other/baz.baz")
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(buf, b"foo");
+        archive.create_file("/test/qux.baz").unwrap();
+        match archive.rename("/test/qux.baz", "/other/BAZ.baz") {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::AlreadyExists),
+            Ok(_) => panic!("rename replaced an existing file"),
+        };
+    }
+
+    #[test]
+    fn rename_directory() {
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.create_file("/test/foo/bar.baz").unwrap();
+        archive.create_directory("/other").unwrap();
+        archive.rename("/test/foo", "/other/moved").unwrap();
+        assert!(archive.open_directory("/test/foo").is_err());
+        assert!(archive.open_file("/other/moved/bar.baz").is_ok());
+        let other = archive
+            .block_manager
+            .resolve_path_to_block_chain_index_at(
+                archive.chain_source(),
+                super::PK2_ROOT_BLOCK,
+                "other".as_ref(),
+            )
+            .unwrap();
+        let moved = archive
+            .block_manager
+            .resolve_path_to_block_chain_index_at(
+                archive.chain_source(),
+                super::PK2_ROOT_BLOCK,
+                "other/moved".as_ref(),
+            )
+            .unwrap();
+        assert_eq!(
+            archive
+                .get_chain(moved)
+                .unwrap()
+                .find_block_chain_index_of(super::PK2_PARENT_DIR_IDENT),
+            Ok(other)
+        );
+        match archive.rename("/other", "/other/moved/other") {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            Ok(_) => panic!("directory was moved into itself"),
+        };
+    }
+
+    #[test]
+    fn delete_directory_refuses_root_and_links() {
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.create_file("/test/foo.baz").unwrap();
+        for path in &["/", "/.", "/test/.."] {
+            match archive.delete_directory(path, true) {
+                Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+                Ok(_) => panic!("{} was deleted", path),
+            };
+        }
+        assert!(archive.open_file("/test/foo.baz").is_ok());
+    }
 }
@@ -0,0 +1,345 @@
+//! Integrity checking of an archive's index, see [`Pk2::check`](crate::archive::Pk2::check).
+//!
+//! The check reads the index straight from the stream instead of going
+//! through the parsed index, as most of the problems it looks for are either
+//! rejected or silently worked around when an archive is opened.
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::constants::{PK2_FILE_BLOCK_ENTRY_COUNT, PK2_FILE_BLOCK_SIZE, PK2_ROOT_BLOCK};
+use crate::error::Result;
+use crate::raw::entry::PackEntry;
+use crate::raw::{BlockOffset, ChainIndex};
+use crate::{IndexCipher, ReadAt};
+
+/// A problem found by [`Pk2::check`](crate::archive::Pk2::check).
+///
+/// Directories are identified by the path they were first reached through,
+/// entries by the chain they are in and their index in it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Finding {
+    /// A block of the directory's chain lies past the end of the stream.
+    ChainPastEof {
+        directory: PathBuf,
+        block: BlockOffset,
+    },
+    /// A block of the directory's chain links back to a block that is
+    /// already part of the chain.
+    BlockCycle {
+        directory: PathBuf,
+        block: BlockOffset,
+    },
+    /// A directory's chain is referenced by more than one directory entry.
+    SharedDirectory {
+        chain: ChainIndex,
+        first: PathBuf,
+        second: PathBuf,
+    },
+    /// The directory has no `.` entry.
+    MissingCurrentLink { directory: PathBuf },
+    /// The directory's `.` entry doesn't point at the directory itself.
+    WrongCurrentLink {
+        directory: PathBuf,
+        expected: ChainIndex,
+        found: ChainIndex,
+    },
+    /// The directory has no `..` entry.
+    MissingParentLink { directory: PathBuf },
+    /// The directory's `..` entry doesn't point at its parent.
+    WrongParentLink {
+        directory: PathBuf,
+        expected: ChainIndex,
+        found: ChainIndex,
+    },
+    /// The data of two files overlaps.
+    DataOverlap { first: PathBuf, second: PathBuf },
+    /// The data of a file overlaps the header or the index block at `offset`.
+    DataOverlapsIndex { file: PathBuf, offset: u64 },
+    /// The data of a file extends past the end of the stream.
+    DataPastEof { file: PathBuf },
+    /// An entry has a type byte other than empty, directory or file. Such
+    /// entries are treated as empty by the rest of the check.
+    InvalidEntryType {
+        chain: ChainIndex,
+        entry: usize,
+        ty: u8,
+    },
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Finding::ChainPastEof { directory, block } => write!(
+                f,
+                "chain of {:?} has a block at {} past the end of the archive",
+                directory, block.0
+            ),
+            Finding::BlockCycle { directory, block } => write!(
+                f,
+                "chain of {:?} links back to its block at {}",
+                directory, block.0
+            ),
+            Finding::SharedDirectory {
+                chain,
+                first,
+                second,
+            } => write!(
+                f,
+                "{:?} and {:?} share the chain at {}",
+                first, second, chain.0
+            ),
+            Finding::MissingCurrentLink { directory } => {
+                write!(f, "{:?} has no `.` entry", directory)
+            }
+            Finding::WrongCurrentLink {
+                directory,
+                expected,
+                found,
+            } => write!(
+                f,
+                "`.` of {:?} points at {} instead of {}",
+                directory, found.0, expected.0
+            ),
+            Finding::MissingParentLink { directory } => {
+                write!(f, "{:?} has no `..` entry", directory)
+            }
+            Finding::WrongParentLink {
+                directory,
+                expected,
+                found,
+            } => write!(
+                f,
+                "`..` of {:?} points at {} instead of {}",
+                directory, found.0, expected.0
+            ),
+            Finding::DataOverlap { first, second } => {
+                write!(f, "data of {:?} overlaps data of {:?}", first, second)
+            }
+            Finding::DataOverlapsIndex { file, offset } => {
+                write!(f, "data of {:?} overlaps the index at {}", file, offset)
+            }
+            Finding::DataPastEof { file } => {
+                write!(f, "data of {:?} extends past the end of the archive", file)
+            }
+            Finding::InvalidEntryType { chain, entry, ty } => write!(
+                f,
+                "entry {} of the chain at {} has the invalid type {}",
+                entry, chain.0, ty
+            ),
+        }
+    }
+}
+
+/// The result of [`Pk2::check`](crate::archive::Pk2::check).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    findings: Vec<Finding>,
+}
+
+impl CheckReport {
+    /// Whether no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    pub fn into_findings(self) -> Vec<Finding> {
+        self.findings
+    }
+}
+
+enum Region {
+    Index,
+    File(PathBuf),
+}
+
+pub(super) fn check<C: IndexCipher + ?Sized, B: ReadAt + ?Sized>(
+    bf: Option<&C>,
+    stream: &B,
+) -> Result<CheckReport> {
+    let stream_len = stream.stream_len()?;
+    let mut findings = Vec::new();
+    // the path every chain was first reached through
+    let mut reached = HashMap::new();
+    reached.insert(PK2_ROOT_BLOCK, PathBuf::from("/"));
+    // the header is never pointed at by anything, but data overlapping it is
+    // just as bad as data overlapping an index block
+    let mut regions = vec![(0, PK2_ROOT_BLOCK.0, Region::Index)];
+    let mut stack = vec![(PK2_ROOT_BLOCK, PathBuf::from("/"), None)];
+    while let Some((chain, path, parent)) = stack.pop() {
+        let entries = read_chain(bf, stream, stream_len, chain, &path, &mut findings)?;
+        let mut current = None;
+        let mut parent_link = None;
+        for (idx, entry, offset) in entries {
+            if idx % PK2_FILE_BLOCK_ENTRY_COUNT == 0 {
+                regions.push((offset, PK2_FILE_BLOCK_SIZE as u64, Region::Index));
+            }
+            match entry {
+                PackEntry::Directory(dir) if dir.is_current_link() => {
+                    current.get_or_insert(dir.children_position());
+                }
+                PackEntry::Directory(dir) if dir.is_parent_link() => {
+                    parent_link.get_or_insert(dir.children_position());
+                }
+                PackEntry::Directory(dir) => {
+                    let sub_path = path.join(dir.name());
+                    let sub_chain = dir.children_position();
+                    match reached.get(&sub_chain) {
+                        Some(first) => findings.push(Finding::SharedDirectory {
+                            chain: sub_chain,
+                            first: first.clone(),
+                            second: sub_path,
+                        }),
+                        None => {
+                            reached.insert(sub_chain, sub_path.clone());
+                            stack.push((sub_chain, sub_path, Some(chain)));
+                        }
+                    }
+                }
+                PackEntry::File(file) => {
+                    let file_path = path.join(file.name());
+                    let start = file.pos_data().0;
+                    let len = u64::from(file.size());
+                    if start.saturating_add(len) > stream_len {
+                        findings.push(Finding::DataPastEof {
+                            file: file_path.clone(),
+                        });
+                    }
+                    if len != 0 {
+                        regions.push((start, len, Region::File(file_path)));
+                    }
+                }
+                PackEntry::Empty(_) => (),
+            }
+        }
+
+        match current {
+            None => findings.push(Finding::MissingCurrentLink {
+                directory: path.clone(),
+            }),
+            Some(found) if found != chain => findings.push(Finding::WrongCurrentLink {
+                directory: path.clone(),
+                expected: chain,
+                found,
+            }),
+            Some(_) => (),
+        }
+        // the root has no parent, so it usually has no `..` either
+        match (parent, parent_link) {
+            (Some(_), None) => findings.push(Finding::MissingParentLink { directory: path }),
+            (Some(expected), Some(found)) if found != expected => {
+                findings.push(Finding::WrongParentLink {
+                    directory: path,
+                    expected,
+                    found,
+                })
+            }
+            _ => (),
+        }
+    }
+
+    find_overlaps(regions, &mut findings);
+    Ok(CheckReport { findings })
+}
+
+/// Reads the entries of a chain together with their index in it and the
+/// offset of the block they are in, stopping at blocks past the end of the
+/// stream and at blocks that were already visited.
+fn read_chain<C: IndexCipher + ?Sized, B: ReadAt + ?Sized>(
+    bf: Option<&C>,
+    stream: &B,
+    stream_len: u64,
+    chain: ChainIndex,
+    path: &std::path::Path,
+    findings: &mut Vec<Finding>,
+) -> Result<Vec<(usize, PackEntry, u64)>> {
+    let mut entries = Vec::new();
+    let mut visited = HashSet::new();
+    let mut offset = chain.0;
+    loop {
+        if offset.saturating_add(PK2_FILE_BLOCK_SIZE as u64) > stream_len {
+            findings.push(Finding::ChainPastEof {
+                directory: path.to_owned(),
+                block: BlockOffset(offset),
+            });
+            break;
+        }
+        if !visited.insert(offset) {
+            findings.push(Finding::BlockCycle {
+                directory: path.to_owned(),
+                block: BlockOffset(offset),
+            });
+            break;
+        }
+        let mut invalid = Vec::new();
+        let block =
+            crate::io::read_block_at_lenient(bf, stream, BlockOffset(offset), &mut invalid)?;
+        findings.extend(
+            invalid
+                .into_iter()
+                .map(|(idx, ty)| Finding::InvalidEntryType {
+                    chain,
+                    entry: entries.len() + idx,
+                    ty,
+                }),
+        );
+        let next = block.entries().last().and_then(PackEntry::next_block);
+        let base = entries.len();
+        entries.extend(
+            block
+                .entries()
+                .cloned()
+                .enumerate()
+                .map(|(idx, entry)| (base + idx, entry, offset)),
+        );
+        match next {
+            Some(next) => offset = next.get(),
+            None => break,
+        }
+    }
+    Ok(entries)
+}
+
+fn find_overlaps(mut regions: Vec<(u64, u64, Region)>, findings: &mut Vec<Finding>) {
+    regions.sort_by_key(|&(start, len, _)| (start, len));
+    // the region reaching the furthest so far
+    let mut furthest: Option<(u64, u64, &Region)> = None;
+    for (start, len, region) in &regions {
+        let end = start.saturating_add(*len);
+        if let Some((other_start, other_end, other)) = furthest {
+            if *start < other_end {
+                match (other, region) {
+                    (Region::File(first), Region::File(second)) => {
+                        findings.push(Finding::DataOverlap {
+                            first: first.clone(),
+                            second: second.clone(),
+                        })
+                    }
+                    (Region::File(file), Region::Index) => {
+                        findings.push(Finding::DataOverlapsIndex {
+                            file: file.clone(),
+                            offset: *start,
+                        })
+                    }
+                    (Region::Index, Region::File(file)) => {
+                        findings.push(Finding::DataOverlapsIndex {
+                            file: file.clone(),
+                            offset: other_start,
+                        })
+                    }
+                    // a block that is part of multiple chains is only
+                    // reported through the problems it causes in them
+                    (Region::Index, Region::Index) => (),
+                }
+            }
+            if end <= other_end {
+                continue;
+            }
+        }
+        furthest = Some((*start, end, region));
+    }
+}
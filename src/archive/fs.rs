@@ -1,29 +1,29 @@
 #![allow(clippy::match_ref_pats)]
 use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
 use std::time::SystemTime;
 
-use crate::archive::Pk2;
-use crate::error::{ChainLookupError, ChainLookupResult};
-use crate::raw::block_chain::PackBlockChain;
-use crate::raw::entry::{DirectoryEntry, FileEntry, PackEntry};
+use crate::archive::glob::Glob;
+use crate::archive::{Pk2, ReadWrite};
+use crate::error::{ChainLookupError, Error, Result};
+use crate::raw::block_chain::{EntryHandle, PackBlockChain};
+use crate::raw::entry::{DirectoryRef, EntryRef, FileEntry, FileRef};
 use crate::raw::{ChainIndex, StreamOffset};
+use crate::{Blowfish, IndexCipher, ReadAt, WriteAt};
 
-pub struct File<'pk2, B = std::fs::File> {
-    archive: &'pk2 Pk2<B>,
-    // the chain this file resides in
-    chain: ChainIndex,
-    // the index of this file in the chain
-    entry_index: usize,
+pub struct File<'pk2, B = std::fs::File, M = ReadWrite, C = Blowfish> {
+    archive: &'pk2 Pk2<B, M, C>,
+    // the entry of this file and the chain it resides in
+    handle: EntryHandle,
     seek_pos: u64,
 }
 
-impl<'pk2, B> File<'pk2, B> {
-    pub(super) fn new(archive: &'pk2 Pk2<B>, chain: ChainIndex, entry_index: usize) -> Self {
+impl<'pk2, B, M, C> File<'pk2, B, M, C> {
+    pub(super) fn new(archive: &'pk2 Pk2<B, M, C>, handle: EntryHandle) -> Self {
         File {
             archive,
-            chain,
-            entry_index,
+            handle,
             seek_pos: 0,
         }
     }
@@ -41,7 +41,7 @@ impl<'pk2, B> File<'pk2, B> {
     }
 
     pub fn size(&self) -> u32 {
-        self.entry().size
+        self.entry().size()
     }
 
     #[inline]
@@ -49,12 +49,13 @@ impl<'pk2, B> File<'pk2, B> {
         self.entry().name()
     }
 
+    pub fn metadata(&self) -> Metadata {
+        Metadata::new(self.handle.chain_index(), self.handle.entry())
+    }
+
     #[inline]
-    fn entry(&self) -> &FileEntry {
-        self.archive
-            .get_entry(self.chain, self.entry_index)
-            .and_then(PackEntry::as_file)
-            .expect("invalid file object")
+    fn entry(&self) -> FileRef<'_> {
+        self.handle.entry().as_file().expect("invalid file object")
     }
 
     #[inline]
@@ -63,26 +64,26 @@ impl<'pk2, B> File<'pk2, B> {
     }
 }
 
-impl<B> Seek for File<'_, B> {
+impl<B, M, C> Seek for File<'_, B, M, C> {
     fn seek(&mut self, seek: SeekFrom) -> io::Result<u64> {
         let size = self.entry().size() as u64;
-        seek_impl(seek, self.seek_pos, size).map(|new_pos| {
+        seek_impl(seek, self.seek_pos, size).inspect(|&new_pos| {
             self.seek_pos = new_pos;
-            new_pos
         })
     }
 }
 
-impl<B> Read for File<'_, B>
+impl<B, M, C> Read for File<'_, B, M, C>
 where
-    B: Read + Seek,
+    B: ReadAt,
+    C: IndexCipher,
 {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let pos_data = self.entry().pos_data();
         let rem_len = self.remaining_len();
         let len = buf.len().min(rem_len);
         let n = crate::io::read_at(
-            &mut *self.archive.stream.borrow_mut(),
+            &self.archive.stream,
             pos_data + StreamOffset(self.seek_pos),
             &mut buf[..len],
         )?;
@@ -99,8 +100,9 @@ where
                 "failed to fill whole buffer",
             ))
         } else {
-            crate::io::read_at(
-                &mut *self.archive.stream.borrow_mut(),
+            // data cut off by the end of the stream is an error, not zeroes
+            crate::io::read_exact_at(
+                &self.archive.stream,
                 pos_data + StreamOffset(self.seek_pos),
                 &mut buf[..rem_len],
             )?;
@@ -117,71 +119,149 @@ where
     }
 }
 
-pub struct FileMut<'pk2, B = std::fs::File>
+/// Reads relative to the start of the file's data, independent of the file's
+/// seek position.
+impl<B, M, C> ReadAt for File<'_, B, M, C>
+where
+    B: ReadAt,
+    C: IndexCipher,
+{
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let entry = self.entry();
+        let rem_len = (entry.size() as u64).saturating_sub(offset);
+        let len = (buf.len() as u64).min(rem_len) as usize;
+        crate::io::read_at(
+            &self.archive.stream,
+            entry.pos_data() + StreamOffset(offset),
+            &mut buf[..len],
+        )
+    }
+
+    #[inline]
+    fn stream_len(&self) -> io::Result<u64> {
+        Ok(self.entry().size() as u64)
+    }
+}
+
+pub struct FileMut<'pk2, B = std::fs::File, C = Blowfish>
 where
-    B: Read + Write + Seek,
+    B: WriteAt,
+    C: IndexCipher,
 {
-    archive: &'pk2 mut Pk2<B>,
+    archive: &'pk2 mut Pk2<B, ReadWrite, C>,
     // the chain this file resides in
     chain: ChainIndex,
     // the index of this file in the chain
     entry_index: usize,
+    // the chain the entry is read from, it is released whenever the entry is
+    // updated so the chain isn't copied
+    cached_chain: OnceLock<Arc<PackBlockChain>>,
     data: Cursor<Vec<u8>>,
+    // whether `data` holds the file's contents yet, they are read lazily
+    loaded: bool,
+    // whether `data` has changes that have yet to be written
+    dirty: bool,
+    // whether writes always go to the end of the file
+    append: bool,
 }
 
-impl<'pk2, B> FileMut<'pk2, B>
+impl<'pk2, B, C> FileMut<'pk2, B, C>
 where
-    B: Read + Write + Seek,
+    B: WriteAt,
+    C: IndexCipher,
 {
-    pub(super) fn new(archive: &'pk2 mut Pk2<B>, chain: ChainIndex, entry_index: usize) -> Self {
+    pub(super) fn new(
+        archive: &'pk2 mut Pk2<B, ReadWrite, C>,
+        chain: ChainIndex,
+        entry_index: usize,
+    ) -> Self {
         FileMut {
             archive,
             chain,
             entry_index,
+            cached_chain: OnceLock::new(),
             data: Cursor::new(Vec::new()),
+            loaded: false,
+            dirty: false,
+            append: false,
         }
     }
 
+    /// A handle to a file that was just created, making sure its entry gets
+    /// written even if no data is.
+    pub(super) fn new_created(
+        archive: &'pk2 mut Pk2<B, ReadWrite, C>,
+        chain: ChainIndex,
+        entry_index: usize,
+    ) -> Self {
+        let mut this = Self::new(archive, chain, entry_index);
+        this.loaded = true;
+        this.dirty = true;
+        this
+    }
+
+    /// Discards the file's contents, the file is empty once flushed.
+    pub(super) fn truncate(&mut self) {
+        self.data = Cursor::new(Vec::new());
+        self.loaded = true;
+        self.dirty = true;
+    }
+
+    pub(super) fn set_append(&mut self, append: bool) {
+        self.append = append;
+    }
+
+    /// Writes the file's data and entry to the archive, returning any error
+    /// that occurs. Dropping a `FileMut` does the same but has to ignore
+    /// errors, so this should be preferred.
+    pub fn commit(mut self) -> io::Result<()> {
+        let res = self.flush();
+        // nothing is left to write on success, and retrying in drop won't
+        // help on failure
+        self.dirty = false;
+        res
+    }
+
     pub fn modify_time(&self) -> Option<SystemTime> {
-        self.entry().modify_time.into_systime()
+        self.entry().modify_time()
     }
 
     pub fn access_time(&self) -> Option<SystemTime> {
-        self.entry().access_time.into_systime()
+        self.entry().access_time()
     }
 
     pub fn create_time(&self) -> Option<SystemTime> {
-        self.entry().create_time.into_systime()
+        self.entry().create_time()
     }
 
     pub fn set_modify_time(&mut self, time: SystemTime) {
-        self.entry_mut().modify_time = time.into();
+        self.update_entry(|entry| entry.modify_time = time.into());
     }
 
     pub fn set_access_time(&mut self, time: SystemTime) {
-        self.entry_mut().access_time = time.into();
+        self.update_entry(|entry| entry.access_time = time.into());
     }
 
     pub fn set_create_time(&mut self, time: SystemTime) {
-        self.entry_mut().create_time = time.into();
+        self.update_entry(|entry| entry.create_time = time.into());
     }
 
-    pub fn copy_file_times<'a, A>(&mut self, other: &File<'a, A>) {
-        let this = self.entry_mut();
-        let other = other.entry();
-        this.modify_time = other.modify_time;
-        this.create_time = other.create_time;
-        this.access_time = other.access_time;
+    pub fn copy_file_times<A, AM>(&mut self, other: &File<'_, A, AM>) {
+        let [access_time, create_time, modify_time] = other.entry().entry().filetimes();
+        self.update_entry(|this| {
+            this.modify_time = modify_time;
+            this.create_time = create_time;
+            this.access_time = access_time;
+        });
     }
 
     pub fn size(&self) -> u32 {
-        self.entry().size
+        self.entry().size()
     }
 
-    pub fn flush_drop(mut self) -> io::Result<()> {
-        let res = self.flush();
-        std::mem::forget(self);
-        res
+    /// See [`FileMut::commit`].
+    pub fn flush_drop(self) -> io::Result<()> {
+        self.commit()
     }
 
     #[inline]
@@ -189,59 +269,72 @@ where
         self.entry().name()
     }
 
+    pub fn metadata(&self) -> Metadata {
+        Metadata::new(self.chain, self.entry().entry())
+    }
+
     #[inline]
-    fn entry(&self) -> &FileEntry {
-        self.archive
-            .get_entry(self.chain, self.entry_index)
-            .and_then(PackEntry::as_file)
+    fn entry(&self) -> FileRef<'_> {
+        self.cached_chain
+            .get_or_init(|| self.archive.get_chain(self.chain).expect("invalid chain"))
+            .get(self.entry_index)
+            .and_then(EntryRef::as_file)
             .expect("invalid file object")
     }
 
     #[inline]
-    fn entry_mut(&mut self) -> &mut FileEntry {
+    fn update_entry<R>(&mut self, f: impl FnOnce(&mut FileEntry) -> R) -> R {
+        self.cached_chain.take();
         self.archive
-            .get_entry_mut(self.chain, self.entry_index)
-            .and_then(PackEntry::as_file_mut)
+            .get_chain_mut(self.chain)
             .expect("invalid file object")
+            .update_entry(self.entry_index, |entry| {
+                f(entry.as_file_mut().expect("invalid file object"))
+            })
     }
 
     fn fetch_data(&mut self) -> io::Result<()> {
         let pos_data = self.entry().pos_data();
         let size = self.entry().size();
         self.data.get_mut().resize(size as usize, 0);
-        crate::io::read_exact_at(
-            &mut *self.archive.stream.borrow_mut(),
-            pos_data,
-            self.data.get_mut(),
-        )
+        crate::io::read_exact_at(&self.archive.stream, pos_data, self.data.get_mut())
     }
 
     #[inline]
     fn try_fetch_data(&mut self) -> io::Result<()> {
-        if self.data.get_ref().is_empty() && self.entry().size() > 0 {
-            self.fetch_data()
+        if !self.loaded {
+            self.fetch_data()?;
+            self.loaded = true;
+        }
+        Ok(())
+    }
+
+    // the current size of the file, including unflushed changes
+    fn len(&self) -> u64 {
+        if self.loaded {
+            self.data.get_ref().len() as u64
         } else {
-            Ok(())
+            self.entry().size() as u64
         }
     }
 }
 
-impl<B> Seek for FileMut<'_, B>
+impl<B, C> Seek for FileMut<'_, B, C>
 where
-    B: Read + Write + Seek,
+    B: WriteAt,
+    C: IndexCipher,
 {
     fn seek(&mut self, seek: SeekFrom) -> io::Result<u64> {
-        let size = self.data.get_ref().len().max(self.entry().size() as usize) as u64;
-        seek_impl(seek, self.data.position(), size).map(|new_pos| {
+        seek_impl(seek, self.data.position(), self.len()).inspect(|&new_pos| {
             self.data.set_position(new_pos);
-            new_pos
         })
     }
 }
 
-impl<B> Read for FileMut<'_, B>
+impl<B, C> Read for FileMut<'_, B, C>
 where
-    B: Read + Write + Seek,
+    B: WriteAt,
+    C: IndexCipher,
 {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.try_fetch_data()?;
@@ -254,79 +347,306 @@ where
     }
 
     fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
-        let len = buf.len();
-        let size = self.data.get_ref().len().max(self.entry().size() as usize);
-        buf.resize(len + size as usize, 0);
-        self.read_exact(&mut buf[len..]).map(|()| size)
+        self.try_fetch_data()?;
+        self.data.read_to_end(buf)
     }
 }
 
-impl<B> Write for FileMut<'_, B>
+impl<B, C> Write for FileMut<'_, B, C>
 where
-    B: Read + Write + Seek,
+    B: WriteAt,
+    C: IndexCipher,
 {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.try_fetch_data()?;
-        let len = self.data.get_ref().len();
-        match len
-            .checked_add(buf.len())
-            .map(|new_len| new_len.checked_sub(u32::MAX as usize))
-        {
-            // data + buf < u32::MAX
-            Some(None) | Some(Some(0)) => self.data.write(buf),
-            // data + buf > u32::MAX, truncate buf
-            Some(Some(slice_overflow)) => self.data.write(&buf[..buf.len() - slice_overflow]),
-            // data + buf overflows usize::MAX
-            None => Ok(0),
+        if self.append {
+            self.data.seek(SeekFrom::End(0))?;
         }
+        let remaining = (u32::MAX as u64).saturating_sub(self.data.position());
+        if remaining == 0 && !buf.is_empty() {
+            return Err(file_too_large());
+        }
+        let buf = &buf[..buf.len().min(remaining as usize)];
+        self.dirty = true;
+        self.data.write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        if self.data.get_ref().is_empty() {
+        if !self.dirty {
             return Ok(()); // nothing to write
         }
         self.set_modify_time(SystemTime::now());
-        let chain = self
-            .archive
-            .block_manager
-            .get_mut(self.chain)
-            .expect("invalid chain");
-        let entry_offset = chain
-            .stream_offset_for_entry(self.entry_index)
-            .expect("invalid entry");
-
-        let entry = chain.get_mut(self.entry_index).expect("invalid entry");
-        let fentry = entry
-            .as_file_mut()
-            .expect("invalid file object, this is a bug");
-
-        let stream = &mut *self.archive.stream.borrow_mut();
+        let (mut pos_data, size) = {
+            let entry = self.entry();
+            (entry.pos_data(), entry.size())
+        };
+
+        let stream = &mut self.archive.stream;
         let data = &self.data.get_ref()[..];
         debug_assert!(data.len() <= !0u32 as usize);
         let data_len = data.len() as u32;
-        // new unwritten file/more data than what fits, so use a new block
-        if data_len > fentry.size {
-            // FIXME reuse previous buffer somehow?
-            fentry.pos_data = crate::io::append_data(&mut *stream, data)?;
+        let free_space = &mut self.archive.free_space;
+        // new unwritten file/more data than what fits, so look for a new region
+        if data_len > size {
+            free_space.release(pos_data, size as u64);
+            pos_data = match free_space.allocate(data_len as u64) {
+                Some(offset) => {
+                    crate::io::write_data_at(&mut *stream, offset, data)?;
+                    offset
+                }
+                None => crate::io::append_data(&mut *stream, data)?,
+            };
         // data fits into the previous buffer space
         } else {
-            crate::io::write_data_at(&mut *stream, fentry.pos_data, data)?;
+            crate::io::write_data_at(&mut *stream, pos_data, data)?;
+            free_space.release(
+                pos_data + StreamOffset(data_len as u64),
+                (size - data_len) as u64,
+            );
         }
-        fentry.size = data_len;
-
-        crate::io::write_entry_at(self.archive.blowfish.as_ref(), stream, entry_offset, entry)
+        self.update_entry(|entry| {
+            entry.pos_data = pos_data;
+            entry.size = data_len;
+        });
+
+        let chain = self.archive.get_chain(self.chain).expect("invalid chain");
+        crate::io::write_chain_entry(
+            self.archive.cipher.as_ref(),
+            &mut self.archive.stream,
+            &chain,
+            self.entry_index,
+        )?;
+        self.dirty = false;
+        Ok(())
     }
 }
 
-impl<B> Drop for FileMut<'_, B>
+impl<B, C> Drop for FileMut<'_, B, C>
 where
-    B: Write + Read + Seek,
+    B: WriteAt,
+    C: IndexCipher,
 {
     fn drop(&mut self) {
         let _ = self.flush();
     }
 }
 
+/// Options for opening a file for writing, modelled after
+/// [`std::fs::OpenOptions`] and created by [`Pk2::file_options`].
+///
+/// By default an existing file is opened and written to from its start,
+/// without truncating it.
+pub struct FileOptions<'pk2, B = std::fs::File, C = Blowfish>
+where
+    B: WriteAt,
+    C: IndexCipher,
+{
+    archive: &'pk2 mut Pk2<B, ReadWrite, C>,
+    create: bool,
+    create_new: bool,
+    truncate: bool,
+    append: bool,
+}
+
+impl<'pk2, B, C> FileOptions<'pk2, B, C>
+where
+    B: WriteAt,
+    C: IndexCipher,
+{
+    pub(super) fn new(archive: &'pk2 mut Pk2<B, ReadWrite, C>) -> Self {
+        FileOptions {
+            archive,
+            create: false,
+            create_new: false,
+            truncate: false,
+            append: false,
+        }
+    }
+
+    /// Create the file if it doesn't exist yet.
+    pub fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    /// Create the file, failing with [`io::ErrorKind::AlreadyExists`] if it
+    /// exists already. This takes precedence over `create` and `truncate`.
+    pub fn create_new(mut self, create_new: bool) -> Self {
+        self.create_new = create_new;
+        self
+    }
+
+    /// Discard the contents of an existing file.
+    pub fn truncate(mut self, truncate: bool) -> Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Write to the end of the file, regardless of the seek position.
+    pub fn append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
+    }
+
+    pub fn open<P: AsRef<Path>>(self, path: P) -> Result<FileMut<'pk2, B, C>> {
+        let path = path.as_ref();
+        if self.truncate && self.append && !self.create_new {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a file can't be truncated and appended to at the same time",
+            )
+            .into());
+        }
+        let exists = match self.archive.metadata(path) {
+            Ok(_) => true,
+            Err(Error::Lookup {
+                source: ChainLookupError::NotFound,
+                ..
+            }) => false,
+            Err(e) => return Err(e),
+        };
+        let mut file = if self.create_new || (self.create && !exists) {
+            self.archive.create_file(path)?
+        } else {
+            let mut file = self.archive.open_file_mut(path)?;
+            if self.truncate {
+                file.truncate();
+            }
+            file
+        };
+        file.set_append(self.append);
+        Ok(file)
+    }
+}
+
+/// A writer that writes a file's data straight to the end of the archive
+/// instead of buffering it in memory, created by
+/// [`Pk2::create_file_streaming`].
+///
+/// The file only becomes part of the archive once [`FileWriter::finish`]
+/// succeeds. Dropping the writer without finishing it, for example after a
+/// failed write, discards the file and the space its data was written to is
+/// reused by later writes. Files can't be larger than [`u32::MAX`] bytes,
+/// writes past that size fail with [`io::ErrorKind::FileTooLarge`].
+pub struct FileWriter<'pk2, B = std::fs::File, C = Blowfish>
+where
+    B: WriteAt,
+    C: IndexCipher,
+{
+    archive: &'pk2 mut Pk2<B, ReadWrite, C>,
+    // the chain this file resides in
+    chain: ChainIndex,
+    // the index of this file in the chain
+    entry_index: usize,
+    // the start of the file's data, the data is always the last thing in the
+    // stream while the writer is alive
+    pos_data: StreamOffset,
+    written: u32,
+    finished: bool,
+}
+
+impl<'pk2, B, C> FileWriter<'pk2, B, C>
+where
+    B: WriteAt,
+    C: IndexCipher,
+{
+    pub(super) fn new(
+        archive: &'pk2 mut Pk2<B, ReadWrite, C>,
+        chain: ChainIndex,
+        entry_index: usize,
+    ) -> io::Result<Self> {
+        let pos_data = StreamOffset(archive.stream.stream_len()?);
+        Ok(FileWriter {
+            archive,
+            chain,
+            entry_index,
+            pos_data,
+            written: 0,
+            finished: false,
+        })
+    }
+
+    /// The number of bytes written so far.
+    pub fn size(&self) -> u32 {
+        self.written
+    }
+
+    /// Updates the file's entry to point to the written data, adding the
+    /// file to the archive.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.finished = true;
+        self.finish_impl()
+    }
+
+    fn finish_impl(&mut self) -> io::Result<()> {
+        let (pos_data, size) = (self.pos_data, self.written);
+        let chain = self
+            .archive
+            .get_chain_mut(self.chain)
+            .expect("invalid file object");
+        chain.update_entry(self.entry_index, |entry| {
+            let entry = entry.as_file_mut().expect("invalid file object");
+            entry.pos_data = pos_data;
+            entry.size = size;
+            entry.modify_time = SystemTime::now().into();
+        });
+        crate::io::write_chain_entry(
+            self.archive.cipher.as_ref(),
+            &mut self.archive.stream,
+            &self.archive.block_manager.get(self.chain).unwrap(),
+            self.entry_index,
+        )
+    }
+}
+
+impl<B, C> Write for FileWriter<'_, B, C>
+where
+    B: WriteAt,
+    C: IndexCipher,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let remaining = (u32::MAX - self.written) as usize;
+        if remaining == 0 && !buf.is_empty() {
+            return Err(file_too_large());
+        }
+        let buf = &buf[..buf.len().min(remaining)];
+        let offset = self.pos_data.0 + self.written as u64;
+        let n = self.archive.stream.write_at(buf, offset)?;
+        self.written += n as u32;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<B, C> Drop for FileWriter<'_, B, C>
+where
+    B: WriteAt,
+    C: IndexCipher,
+{
+    fn drop(&mut self) {
+        if !self.finished {
+            // the entry has yet to be written, so dropping it from the index
+            // is enough to discard the file
+            if let Some(chain) = self.archive.get_chain_mut(self.chain) {
+                chain.remove(self.entry_index);
+            }
+            self.archive
+                .free_space
+                .release(self.pos_data, self.written as u64);
+        }
+    }
+}
+
+fn file_too_large() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::FileTooLarge,
+        "files in an archive can't be larger than u32::MAX bytes",
+    )
+}
+
 fn seek_impl(seek: SeekFrom, seek_pos: u64, size: u64) -> io::Result<u64> {
     let (base_pos, offset) = match seek {
         SeekFrom::Start(n) => {
@@ -349,128 +669,267 @@ fn seek_impl(seek: SeekFrom, seek_pos: u64, size: u64) -> io::Result<u64> {
     }
 }
 
-pub enum DirEntry<'pk2, B> {
-    Directory(Directory<'pk2, B>),
-    File(File<'pk2, B>),
+/// The kind of an entry in an archive.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
 }
 
-impl<'pk2, B> DirEntry<'pk2, B> {
-    fn from(
-        entry: &PackEntry,
-        archive: &'pk2 Pk2<B>,
-        chain: ChainIndex,
-        idx: usize,
-    ) -> Option<Self> {
-        match entry {
-            PackEntry::File(_) => Some(DirEntry::File(File::new(archive, chain, idx))),
-            PackEntry::Directory(dir) => {
-                if dir.is_normal_link() {
-                    Some(DirEntry::Directory(Directory::new(archive, chain, idx)))
-                } else {
-                    None
-                }
-            }
-            PackEntry::Empty(_) => None,
+/// Metadata information about an entry of an archive, see
+/// [`Pk2::metadata`].
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    kind: EntryKind,
+    size: u32,
+    access_time: Option<SystemTime>,
+    create_time: Option<SystemTime>,
+    modify_time: Option<SystemTime>,
+    data_offset: Option<StreamOffset>,
+    chain: ChainIndex,
+    entry_index: usize,
+}
+
+impl Metadata {
+    pub(super) fn new(chain: ChainIndex, entry: EntryRef<'_>) -> Self {
+        let file = entry.as_file();
+        Metadata {
+            kind: if file.is_some() {
+                EntryKind::File
+            } else {
+                EntryKind::Directory
+            },
+            size: file.map_or(0, FileRef::size),
+            access_time: entry.access_time(),
+            create_time: entry.create_time(),
+            modify_time: entry.modify_time(),
+            data_offset: file.map(FileRef::pos_data),
+            chain,
+            entry_index: entry.index(),
         }
     }
+
+    pub fn kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.kind == EntryKind::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == EntryKind::Directory
+    }
+
+    /// The size of the file's data, this is always 0 for directories.
+    pub fn len(&self) -> u32 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn access_time(&self) -> Option<SystemTime> {
+        self.access_time
+    }
+
+    pub fn create_time(&self) -> Option<SystemTime> {
+        self.create_time
+    }
+
+    pub fn modify_time(&self) -> Option<SystemTime> {
+        self.modify_time
+    }
+
+    /// The offset of the file's data in the archive, `None` for directories.
+    pub fn data_offset(&self) -> Option<StreamOffset> {
+        self.data_offset
+    }
+
+    /// The chain containing the entry.
+    pub fn chain(&self) -> ChainIndex {
+        self.chain
+    }
+
+    /// The index of the entry in its chain.
+    pub fn entry_index(&self) -> usize {
+        self.entry_index
+    }
 }
 
-pub struct Directory<'pk2, B = std::fs::File> {
-    archive: &'pk2 Pk2<B>,
-    chain: ChainIndex,
-    entry_index: usize,
+pub enum DirEntry<'pk2, B, M = ReadWrite, C = Blowfish> {
+    Directory(Directory<'pk2, B, M, C>),
+    File(File<'pk2, B, M, C>),
 }
 
-impl<'pk2, B> Directory<'pk2, B> {
-    pub(super) fn new(archive: &'pk2 Pk2<B>, chain: ChainIndex, entry_index: usize) -> Self {
-        Directory {
-            archive,
-            chain,
-            entry_index,
+impl<'pk2, B, M, C> DirEntry<'pk2, B, M, C> {
+    fn from(archive: &'pk2 Pk2<B, M, C>, handle: EntryHandle) -> Option<Self> {
+        match handle.entry().as_directory() {
+            Some(dir) if dir.is_normal_link() => {
+                Some(DirEntry::Directory(Directory::new(archive, handle)))
+            }
+            Some(_) => None,
+            None => Some(DirEntry::File(File::new(archive, handle))),
         }
     }
+}
 
-    #[inline]
-    fn entry(&self) -> &DirectoryEntry {
-        self.archive
-            .get_entry(self.chain, self.entry_index)
-            .and_then(PackEntry::as_directory)
-            .expect("invalid file object")
+impl<B, M, C> DirEntry<'_, B, M, C> {
+    pub fn kind(&self) -> EntryKind {
+        match self {
+            DirEntry::File(_) => EntryKind::File,
+            DirEntry::Directory(_) => EntryKind::Directory,
+        }
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        match self {
+            DirEntry::File(file) => file.metadata(),
+            DirEntry::Directory(dir) => dir.metadata(),
+        }
+    }
+}
+
+pub struct Directory<'pk2, B = std::fs::File, M = ReadWrite, C = Blowfish> {
+    archive: &'pk2 Pk2<B, M, C>,
+    handle: EntryHandle,
+}
+
+// manual impl, deriving would require B: Clone and M: Clone
+impl<B, M, C> Clone for Directory<'_, B, M, C> {
+    fn clone(&self) -> Self {
+        Directory::new(self.archive, self.handle.clone())
+    }
+}
+
+impl<'pk2, B, M, C> Directory<'pk2, B, M, C> {
+    pub(super) fn new(archive: &'pk2 Pk2<B, M, C>, handle: EntryHandle) -> Self {
+        Directory { archive, handle }
     }
 
-    // returns the chain this folder represents
     #[inline]
-    fn dir_chain(&self, chain: ChainIndex) -> &'pk2 PackBlockChain {
-        self.archive.get_chain(chain).expect("invalid dir object")
+    fn entry(&self) -> DirectoryRef<'_> {
+        self.handle
+            .entry()
+            .as_directory()
+            .expect("invalid file object")
     }
 
     pub fn name(&self) -> &str {
         self.entry().name()
     }
 
+    pub fn metadata(&self) -> Metadata {
+        Metadata::new(self.handle.chain_index(), self.handle.entry())
+    }
+
     pub fn modify_time(&self) -> Option<SystemTime> {
-        self.entry().modify_time.into_systime()
+        self.entry().modify_time()
     }
 
     pub fn access_time(&self) -> Option<SystemTime> {
-        self.entry().access_time.into_systime()
+        self.entry().access_time()
     }
 
     pub fn create_time(&self) -> Option<SystemTime> {
-        self.entry().create_time.into_systime()
+        self.entry().create_time()
     }
+}
 
-    pub fn open_file(&self, path: impl AsRef<Path>) -> ChainLookupResult<File<'pk2, B>> {
-        let (chain, entry_idx, entry) = self
-            .archive
+impl<'pk2, B, M, C> Directory<'pk2, B, M, C>
+where
+    B: ReadAt,
+    C: IndexCipher,
+{
+    // returns the chain this folder represents, loading it if necessary
+    #[inline]
+    fn dir_chain(&self) -> Result<Arc<PackBlockChain>> {
+        let chain = self.entry().children_position();
+        self.archive
             .block_manager
-            .resolve_path_to_entry_and_parent(self.chain, path.as_ref())?;
-        Pk2::<B>::is_file(entry).map(|_| File::new(self.archive, chain, entry_idx))
+            .load(self.archive.chain_source(), chain)
+            .map_err(|e| Error::lookup(Path::new("."), None, e))
     }
 
-    pub fn open_directory(&self, path: impl AsRef<Path>) -> ChainLookupResult<Directory<'pk2, B>> {
-        let (chain, entry_idx, entry) = self
-            .archive
-            .block_manager
-            .resolve_path_to_entry_and_parent(self.chain, path.as_ref())?;
+    // resolves `path` relative to this folder, errors report `path` as is
+    fn resolve(&self, path: &Path) -> Result<EntryHandle> {
+        self.archive
+            .resolve_path_to_entry_and_parent(self.entry().children_position(), path, path)
+    }
 
+    pub fn open_file(&self, path: impl AsRef<Path>) -> Result<File<'pk2, B, M, C>> {
+        let path = path.as_ref();
+        let handle = self.resolve(path)?;
+        Pk2::<B, M, C>::is_file(path, handle.entry())?;
+        Ok(File::new(self.archive, handle))
+    }
+
+    pub fn open_directory(&self, path: impl AsRef<Path>) -> Result<Directory<'pk2, B, M, C>> {
+        let path = path.as_ref();
+        let handle = self.resolve(path)?;
+        let entry = handle.entry();
         if entry
             .as_directory()
-            .map(DirectoryEntry::is_normal_link)
+            .map(DirectoryRef::is_normal_link)
             .unwrap_or(false)
         {
-            Ok(Directory::new(self.archive, chain, entry_idx))
+            Ok(Directory::new(self.archive, handle))
         } else {
-            Err(ChainLookupError::NotFound)
+            Err(Error::lookup(
+                path,
+                Some(entry.name()),
+                ChainLookupError::NotFound,
+            ))
         }
     }
 
-    pub fn open(&self, path: impl AsRef<Path>) -> ChainLookupResult<DirEntry<'pk2, B>> {
-        let (chain, entry_idx, entry) = self
-            .archive
-            .block_manager
-            .resolve_path_to_entry_and_parent(self.chain, path.as_ref())?;
-        DirEntry::from(entry, self.archive, chain, entry_idx).ok_or(ChainLookupError::NotFound)
+    pub fn open(&self, path: impl AsRef<Path>) -> Result<DirEntry<'pk2, B, M, C>> {
+        let path = path.as_ref();
+        let handle = self.resolve(path)?;
+        DirEntry::from(self.archive, handle.clone()).ok_or_else(|| {
+            Error::lookup(
+                path,
+                Some(handle.entry().name()),
+                ChainLookupError::NotFound,
+            )
+        })
     }
 
-    /// Returns an iterator over all files in this directory.
-    pub fn files(&self) -> impl Iterator<Item = File<'pk2, B>> {
-        let chain = self.entry().children_position();
-        let archive = self.archive;
-        self.dir_chain(chain)
+    /// Returns an iterator over all files below this directory whose path
+    /// relative to it matches the glob `pattern`, see [`Glob`] for the
+    /// supported syntax.
+    pub fn glob(&self, pattern: &str) -> Result<Glob<'pk2, B, M, C>> {
+        Glob::new(self.clone(), PathBuf::new(), pattern)
+    }
+
+    /// Returns an iterator over all files in this directory. This fails if
+    /// the directory's chain has yet to be loaded and reading it fails.
+    pub fn files(&self) -> Result<impl Iterator<Item = File<'pk2, B, M, C>>> {
+        let dir_chain = self.dir_chain()?;
+        let files = dir_chain
             .entries()
-            .enumerate()
-            .flat_map(move |(idx, entry)| entry.as_file().map(|_| File::new(archive, chain, idx)))
+            .filter(|entry| entry.is_file())
+            .map(EntryRef::index)
+            .collect::<Vec<_>>();
+        let archive = self.archive;
+        Ok(files.into_iter().map(move |idx| {
+            File::new(
+                archive,
+                EntryHandle::new(Arc::clone(&dir_chain), idx).unwrap(),
+            )
+        }))
     }
 
     /// Returns an iterator over all items in this directory excluding `.` and
-    /// `..`.
-    pub fn entries(&self) -> impl Iterator<Item = DirEntry<'pk2, B>> {
-        let chain = self.entry().children_position();
+    /// `..`. This fails if the directory's chain has yet to be loaded and
+    /// reading it fails.
+    pub fn entries(&self) -> Result<impl Iterator<Item = DirEntry<'pk2, B, M, C>>> {
+        let dir_chain = self.dir_chain()?;
+        let entries = dir_chain.entries().map(EntryRef::index).collect::<Vec<_>>();
         let archive = self.archive;
-        self.dir_chain(chain)
-            .entries()
-            .enumerate()
-            .flat_map(move |(idx, entry)| DirEntry::from(entry, archive, chain, idx))
+        Ok(entries.into_iter().flat_map(move |idx| {
+            DirEntry::from(archive, EntryHandle::new(Arc::clone(&dir_chain), idx)?)
+        }))
     }
 }
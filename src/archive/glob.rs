@@ -0,0 +1,320 @@
+//! Glob pattern matching over archive paths.
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+
+use crate::archive::fs::{DirEntry, Directory, File};
+use crate::error::{ChainLookupError, Error, Result};
+use crate::{Blowfish, IndexCipher, ReadAt};
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Char(char),
+    // `?`
+    Any,
+    // `*`
+    Star,
+    // `[a-z]`, `[!abc]`
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    // a component without any wildcards, these are looked up directly
+    // instead of scanning the directory
+    Literal(String),
+    Wildcard(Vec<Token>),
+    // `**`
+    Recursive,
+}
+
+impl Segment {
+    fn parse(component: &str) -> Result<Self, ChainLookupError> {
+        if component == "**" {
+            return Ok(Segment::Recursive);
+        }
+        if !component.contains(['*', '?', '[']) {
+            return Ok(Segment::Literal(component.to_owned()));
+        }
+        let mut tokens = Vec::new();
+        let mut chars = component.chars();
+        while let Some(c) = chars.next() {
+            tokens.push(match c {
+                '?' => Token::Any,
+                // consecutive stars are equivalent to a single one
+                '*' if tokens.last() == Some(&Token::Star) => continue,
+                '*' => Token::Star,
+                '[' => {
+                    let mut rest = chars.clone();
+                    let negated = matches!(rest.clone().next(), Some('!' | '^'));
+                    if negated {
+                        rest.next();
+                    }
+                    let mut ranges = Vec::new();
+                    let mut closed = false;
+                    while let Some(c) = rest.next() {
+                        // a leading `]` is part of the class
+                        if c == ']' && !ranges.is_empty() {
+                            closed = true;
+                            break;
+                        }
+                        let mut lookahead = rest.clone();
+                        match (lookahead.next(), lookahead.next()) {
+                            (Some('-'), Some(end)) if end != ']' => {
+                                ranges.push((c, end));
+                                rest = lookahead;
+                            }
+                            _ => ranges.push((c, c)),
+                        }
+                    }
+                    if !closed {
+                        return Err(ChainLookupError::InvalidPath);
+                    }
+                    chars = rest;
+                    Token::Class { negated, ranges }
+                }
+                c => Token::Char(c),
+            });
+        }
+        Ok(Segment::Wildcard(tokens))
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Segment::Literal(literal) => literal.eq_ignore_ascii_case(name),
+            Segment::Wildcard(tokens) => matches_tokens(tokens, &name.chars().collect::<Vec<_>>()),
+            Segment::Recursive => true,
+        }
+    }
+}
+
+fn matches_char(token: &Token, c: char) -> bool {
+    match *token {
+        Token::Char(expected) => expected.eq_ignore_ascii_case(&c),
+        Token::Any => true,
+        Token::Star => unreachable!(),
+        Token::Class {
+            negated,
+            ref ranges,
+        } => {
+            let lower = c.to_ascii_lowercase();
+            let upper = c.to_ascii_uppercase();
+            let in_class = ranges.iter().any(|&(start, end)| {
+                (start..=end).contains(&lower) || (start..=end).contains(&upper)
+            });
+            in_class != negated
+        }
+    }
+}
+
+fn matches_tokens(tokens: &[Token], name: &[char]) -> bool {
+    let (mut t, mut n) = (0, 0);
+    // the position after the last star seen and the name position it is
+    // currently matched up to, used for backtracking
+    let mut backtrack = None;
+    while n < name.len() {
+        match tokens.get(t) {
+            Some(Token::Star) => {
+                t += 1;
+                backtrack = Some((t, n));
+            }
+            Some(token) if matches_char(token, name[n]) => {
+                t += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // let the star swallow one more character
+                Some((star_t, star_n)) => {
+                    t = star_t;
+                    n = star_n + 1;
+                    backtrack = Some((star_t, n));
+                }
+                None => return false,
+            },
+        }
+    }
+    tokens[t..].iter().all(|token| *token == Token::Star)
+}
+
+/// A parsed glob pattern, split into its path components.
+struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Result<Self, ChainLookupError> {
+        let mut segments = Vec::new();
+        for component in pattern.split('/').filter(|c| !c.is_empty()) {
+            let segment = Segment::parse(component)?;
+            // `**/**` is the same as `**`
+            if segment == Segment::Recursive && segments.last() == Some(&Segment::Recursive) {
+                continue;
+            }
+            segments.push(segment);
+        }
+        if segments.is_empty() {
+            return Err(ChainLookupError::InvalidPath);
+        }
+        Ok(Pattern { segments })
+    }
+}
+
+/// An iterator over the files matching a glob pattern, created by
+/// [`Pk2::glob`](crate::archive::Pk2::glob) or [`Directory::glob`].
+///
+/// Patterns are matched per path component ignoring ascii case and support
+/// `?` for any single character, `*` for any number of characters, `**` for
+/// any number of directories and character classes like `[a-z]` or `[!0-9]`.
+/// Components without wildcards are looked up directly, so only the parts of
+/// the tree that can match are visited.
+pub struct Glob<'pk2, B, M, C = Blowfish> {
+    pattern: Pattern,
+    // directories left to visit together with their path and the index of
+    // the segment they have to be matched against
+    stack: Vec<(Directory<'pk2, B, M, C>, PathBuf, usize)>,
+    matches: VecDeque<(PathBuf, File<'pk2, B, M, C>)>,
+    // with more than one `**` a file can be reached in multiple ways
+    seen: Option<HashSet<PathBuf>>,
+}
+
+impl<'pk2, B, M, C> Glob<'pk2, B, M, C>
+where
+    B: ReadAt,
+    C: IndexCipher,
+{
+    pub(super) fn new(
+        base: Directory<'pk2, B, M, C>,
+        base_path: PathBuf,
+        pattern: &str,
+    ) -> Result<Self> {
+        let pattern = Pattern::parse(pattern)
+            .map_err(|e| Error::lookup(std::path::Path::new(pattern), None, e))?;
+        let recursive = pattern
+            .segments
+            .iter()
+            .filter(|segment| **segment == Segment::Recursive)
+            .count();
+        Ok(Glob {
+            pattern,
+            stack: vec![(base, base_path, 0)],
+            matches: VecDeque::new(),
+            seen: (recursive > 1).then(HashSet::new),
+        })
+    }
+
+    fn visit(&mut self, dir: Directory<'pk2, B, M, C>, path: PathBuf, seg: usize) -> Result<()> {
+        let segments = &self.pattern.segments;
+        let is_last = seg + 1 == segments.len();
+        let mut subdirs = Vec::new();
+        match &segments[seg] {
+            Segment::Literal(name) => match dir.open(name) {
+                Ok(DirEntry::File(file)) if is_last => {
+                    self.matches.push_back((path.join(file.name()), file));
+                }
+                Ok(DirEntry::Directory(sub)) if !is_last => {
+                    subdirs.push((path.join(sub.name()), sub, seg + 1));
+                }
+                Ok(_)
+                | Err(Error::Lookup {
+                    source: ChainLookupError::NotFound,
+                    ..
+                }) => (),
+                Err(e) => return Err(e.in_dir(&path)),
+            },
+            Segment::Wildcard(_) => {
+                for entry in dir.entries().map_err(|e| e.in_dir(&path))? {
+                    match entry {
+                        DirEntry::File(file) if is_last && segments[seg].matches(file.name()) => {
+                            self.matches.push_back((path.join(file.name()), file));
+                        }
+                        DirEntry::Directory(sub)
+                            if !is_last && segments[seg].matches(sub.name()) =>
+                        {
+                            subdirs.push((path.join(sub.name()), sub, seg + 1));
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            Segment::Recursive => {
+                if !is_last {
+                    // `**` matching no directories at all
+                    self.stack.push((dir.clone(), path.clone(), seg + 1));
+                }
+                for entry in dir.entries().map_err(|e| e.in_dir(&path))? {
+                    match entry {
+                        // a trailing `**` matches every file in the subtree
+                        DirEntry::File(file) if is_last => {
+                            self.matches.push_back((path.join(file.name()), file));
+                        }
+                        DirEntry::Directory(sub) => {
+                            subdirs.push((path.join(sub.name()), sub, seg));
+                        }
+                        DirEntry::File(_) => (),
+                    }
+                }
+            }
+        }
+        // reversed so that directories are visited in the order they appear
+        // in
+        self.stack.extend(
+            subdirs
+                .into_iter()
+                .rev()
+                .map(|(path, sub, seg)| (sub, path, seg)),
+        );
+        Ok(())
+    }
+}
+
+impl<'pk2, B, M, C> Iterator for Glob<'pk2, B, M, C>
+where
+    B: ReadAt,
+    C: IndexCipher,
+{
+    type Item = Result<(PathBuf, File<'pk2, B, M, C>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            while let Some((path, file)) = self.matches.pop_front() {
+                if let Some(seen) = &mut self.seen {
+                    if !seen.insert(path.clone()) {
+                        continue;
+                    }
+                }
+                return Some(Ok((path, file)));
+            }
+            let (dir, path, seg) = self.stack.pop()?;
+            if let Err(e) = self.visit(dir, path, seg) {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Segment;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        Segment::parse(pattern).unwrap().matches(name)
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*.ddj", "Foo.DDJ"));
+        assert!(matches("*", ""));
+        assert!(!matches("*.ddj", "foo.ddj.bak"));
+        assert!(matches("a*b*c", "aXXbYYbc"));
+        assert!(matches("f?o", "fOo"));
+        assert!(!matches("f?o", "fo"));
+        assert!(matches("[a-c]x", "Bx"));
+        assert!(!matches("[!a-c]x", "bx"));
+        assert!(matches("[!a-c]x", "dx"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(Segment::parse("[abc").is_err());
+    }
+}
@@ -0,0 +1,127 @@
+//! Space usage statistics of an archive, see [`Pk2::stats`](crate::archive::Pk2::stats).
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use crate::constants::{PK2_FILE_BLOCK_SIZE, PK2_ROOT_BLOCK};
+use crate::error::{Error, Result};
+use crate::raw::block_manager::{BlockManager, ChainSource};
+use crate::raw::entry::EntryRef;
+use crate::raw::{BlockOffset, ChainIndex};
+
+/// Space usage and fragmentation of an archive, created by
+/// [`Pk2::stats`](crate::archive::Pk2::stats).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArchiveStats {
+    /// The size of the archive's stream.
+    pub stream_len: u64,
+    /// The number of bytes of file data referenced by the index.
+    pub file_data: u64,
+    /// The number of bytes taken up by index blocks.
+    pub index: u64,
+    /// The number of bytes that neither the header, the index nor any file
+    /// references. These are reclaimed by repacking the archive.
+    pub orphaned: u64,
+    /// The number of files in the archive.
+    pub files: usize,
+    /// Statistics for every directory's chain, starting with the root.
+    pub chains: Vec<ChainStats>,
+}
+
+impl ArchiveStats {
+    /// The number of empty entries over all chains.
+    pub fn empty_entries(&self) -> usize {
+        self.chains.iter().map(|chain| chain.empty_entries).sum()
+    }
+
+    /// The number of blocks over all chains that don't directly follow the
+    /// previous block of their chain.
+    pub fn discontiguous_blocks(&self) -> usize {
+        self.chains
+            .iter()
+            .map(|chain| chain.discontiguous_blocks)
+            .sum()
+    }
+}
+
+/// Statistics of a single chain, see [`ArchiveStats`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainStats {
+    pub chain: ChainIndex,
+    /// The path of the directory the chain belongs to.
+    pub path: PathBuf,
+    pub blocks: usize,
+    pub empty_entries: usize,
+    /// The number of blocks that don't directly follow the previous block of
+    /// the chain in the stream.
+    pub discontiguous_blocks: usize,
+}
+
+pub(super) fn collect(
+    block_manager: &BlockManager,
+    src: ChainSource<'_>,
+    stream_len: u64,
+) -> Result<ArchiveStats> {
+    let mut stats = ArchiveStats {
+        stream_len,
+        ..ArchiveStats::default()
+    };
+    // the header is the only region not covered by the index
+    let mut regions = vec![(0, PK2_ROOT_BLOCK.0)];
+    let mut visited = HashSet::new();
+    let mut stack = vec![(PK2_ROOT_BLOCK, PathBuf::from("/"))];
+    while let Some((chain_index, path)) = stack.pop() {
+        if !visited.insert(chain_index) {
+            continue;
+        }
+        let chain = block_manager
+            .load(src, chain_index)
+            .map_err(|e| Error::lookup(&path, None, e))?;
+        let mut discontiguous_blocks = 0;
+        let mut prev: Option<BlockOffset> = None;
+        for block in chain.block_offsets() {
+            if prev.is_some_and(|BlockOffset(prev)| prev + PK2_FILE_BLOCK_SIZE as u64 != block.0) {
+                discontiguous_blocks += 1;
+            }
+            prev = Some(block);
+            regions.push((block.0, PK2_FILE_BLOCK_SIZE as u64));
+        }
+        for file in chain.entries().filter_map(EntryRef::as_file) {
+            stats.files += 1;
+            stats.file_data += u64::from(file.size());
+            regions.push((file.pos_data().0, u64::from(file.size())));
+        }
+        stats.index += (chain.len() * PK2_FILE_BLOCK_SIZE) as u64;
+        stats.chains.push(ChainStats {
+            chain: chain_index,
+            path: path.clone(),
+            blocks: chain.len(),
+            empty_entries: chain.num_entries() - chain.num_occupied(),
+            discontiguous_blocks,
+        });
+        stack.extend(
+            chain
+                .entries()
+                .filter_map(EntryRef::as_directory)
+                .filter(|dir| dir.is_normal_link())
+                .map(|dir| (dir.children_position(), path.join(dir.name()))),
+        );
+    }
+    stats.orphaned = stream_len - covered_len(regions, stream_len);
+    Ok(stats)
+}
+
+/// The number of bytes of the stream covered by at least one of the regions.
+fn covered_len(mut regions: Vec<(u64, u64)>, stream_len: u64) -> u64 {
+    regions.sort_unstable();
+    let mut covered = 0;
+    let mut end = 0;
+    for (start, len) in regions {
+        let region_end = start.saturating_add(len).min(stream_len);
+        let start = start.max(end);
+        if region_end > start {
+            covered += region_end - start;
+            end = region_end;
+        }
+    }
+    covered
+}
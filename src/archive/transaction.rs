@@ -0,0 +1,393 @@
+//! Write-ahead journaling for [`Pk2::transaction`](crate::archive::Pk2::transaction).
+//!
+//! While a transaction is running, writes to the parts of the stream that
+//! existed when it started are held back in memory, while writes past the
+//! original end of the stream go through directly as nothing references them
+//! yet. On commit the held back writes are first recorded in the journal,
+//! only then applied to the stream, after which the journal is removed.
+//!
+//! Archives opened from a path keep the journal in a sidecar file next to the
+//! archive, `<archive>.journal`. When such an archive is opened for writing
+//! with a journal left behind, a complete journal is applied again while an
+//! incomplete one is discarded and the stream is truncated back to its
+//! original length. Opening such an archive read-only fails if the journal is
+//! complete, as the stream may only be partially updated then. The stream is
+//! left untouched by an incomplete journal, so it can be read as is.
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::archive::{Pk2, ReadWrite};
+use crate::{Blowfish, ReadAt, WriteAt};
+
+/// The archive handed to the closure of
+/// [`Pk2::transaction`](crate::archive::Pk2::transaction).
+pub type Transaction<'a, B, C = Blowfish> = Pk2<Journaled<'a, B>, ReadWrite, C>;
+
+const JOURNAL_MAGIC: &[u8; 8] = b"PK2JRNL1";
+// magic and the original stream length
+const JOURNAL_HEADER_LEN: usize = 16;
+
+/// A stream that holds back all writes to the first `original_len` bytes of
+/// the stream it wraps, see the [module documentation](self).
+pub struct Journaled<'a, B> {
+    inner: &'a mut B,
+    original_len: u64,
+    // writes to the original part of the stream keyed by their offset,
+    // overlapping and adjacent ones are merged so every byte is held once
+    writes: BTreeMap<u64, Vec<u8>>,
+}
+
+impl<'a, B: WriteAt> Journaled<'a, B> {
+    fn new(inner: &'a mut B, original_len: u64) -> Self {
+        Journaled {
+            inner,
+            original_len,
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub(super) fn into_writes(self) -> Vec<(u64, Vec<u8>)> {
+        self.writes.into_iter().collect()
+    }
+
+    fn hold_back(&mut self, offset: u64, data: &[u8]) {
+        let mut start = offset;
+        let mut end = offset + data.len() as u64;
+        if let Some((&prev, prev_data)) = self.writes.range(..offset).next_back() {
+            if prev + prev_data.len() as u64 >= offset {
+                start = prev;
+            }
+        }
+        let mut merged = Vec::new();
+        while let Some((&write_offset, _)) = self.writes.range(start..=end).next() {
+            let write = self.writes.remove(&write_offset).unwrap();
+            end = end.max(write_offset + write.len() as u64);
+            merged.push((write_offset, write));
+        }
+        let buf = match merged.pop() {
+            // the common case of a region being rewritten as a whole
+            Some((write_offset, mut write))
+                if merged.is_empty() && write_offset == offset && write.len() == data.len() =>
+            {
+                write.copy_from_slice(data);
+                write
+            }
+            last => {
+                let mut buf = vec![0; (end - start) as usize];
+                for (write_offset, write) in merged.into_iter().chain(last) {
+                    buf[(write_offset - start) as usize..][..write.len()].copy_from_slice(&write);
+                }
+                buf[(offset - start) as usize..][..data.len()].copy_from_slice(data);
+                buf
+            }
+        };
+        self.writes.insert(start, buf);
+    }
+}
+
+impl<B: ReadAt> ReadAt for Journaled<'_, B> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let n = self.inner.read_at(buf, offset)?;
+        let end = offset + n as u64;
+        let first = self
+            .writes
+            .range(..=offset)
+            .next_back()
+            .map_or(offset, |(&write_offset, _)| write_offset);
+        for (&write_offset, data) in self.writes.range(first..end) {
+            let write_end = write_offset + data.len() as u64;
+            if offset < write_end {
+                let start = offset.max(write_offset);
+                let stop = end.min(write_end);
+                buf[(start - offset) as usize..(stop - offset) as usize].copy_from_slice(
+                    &data[(start - write_offset) as usize..(stop - write_offset) as usize],
+                );
+            }
+        }
+        Ok(n)
+    }
+
+    fn stream_len(&self) -> io::Result<u64> {
+        self.inner.stream_len()
+    }
+}
+
+impl<B: WriteAt> WriteAt for Journaled<'_, B> {
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        if offset >= self.original_len {
+            return self.inner.write_at(buf, offset);
+        }
+        let held_back = buf.len().min((self.original_len - offset) as usize);
+        self.hold_back(offset, &buf[..held_back]);
+        Ok(held_back)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        if len < self.original_len {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "an archive can't be shrunk inside of a transaction",
+            ));
+        }
+        self.inner.set_len(len)
+    }
+}
+
+/// The journal of a running transaction.
+pub(super) struct Journal {
+    // the sidecar file, `None` for archives that weren't opened from a path
+    file: Option<(fs::File, PathBuf)>,
+    original_len: u64,
+}
+
+impl Journal {
+    /// Starts a new journal, recording the stream's original length so that
+    /// an interrupted transaction can be rolled back.
+    pub(super) fn begin(path: Option<&Path>, original_len: u64) -> io::Result<Self> {
+        let file = match path {
+            Some(path) => {
+                let mut file = fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path)?;
+                file.write_all(JOURNAL_MAGIC)?;
+                file.write_all(&original_len.to_le_bytes())?;
+                file.sync_all()?;
+                Some((file, path.to_owned()))
+            }
+            None => None,
+        };
+        Ok(Journal { file, original_len })
+    }
+
+    pub(super) fn stream<'a, B: WriteAt>(&self, stream: &'a mut B) -> Journaled<'a, B> {
+        Journaled::new(stream, self.original_len)
+    }
+
+    /// Records the held back writes in the journal and applies them. If this
+    /// fails after the journal has been completed, the transaction is rolled
+    /// forward the next time the archive is opened.
+    pub(super) fn commit<B: WriteAt>(
+        self,
+        stream: &mut B,
+        writes: &[(u64, Vec<u8>)],
+    ) -> io::Result<()> {
+        if let Some((mut file, path)) = self.file {
+            let mut body = Vec::new();
+            body.extend_from_slice(&(writes.len() as u32).to_le_bytes());
+            for (offset, data) in writes {
+                body.extend_from_slice(&offset.to_le_bytes());
+                body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                body.extend_from_slice(data);
+            }
+            let checksum = checksum(self.original_len, &body);
+            body.extend_from_slice(&checksum.to_le_bytes());
+            file.write_all(&body)?;
+            file.sync_all()?;
+
+            // the data appended by the transaction has to be durable before
+            // the index starts pointing at it
+            stream.sync()?;
+            apply(stream, writes)?;
+            drop(file);
+            fs::remove_file(path)
+        } else {
+            apply(stream, writes)
+        }
+    }
+
+    /// Discards everything the transaction appended to the stream.
+    pub(super) fn rollback<B: WriteAt>(self, stream: &mut B) -> io::Result<()> {
+        stream.set_len(self.original_len)?;
+        if let Some((file, path)) = self.file {
+            stream.sync()?;
+            drop(file);
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// The path of the journal belonging to the archive at `archive`.
+pub(super) fn journal_path(archive: &Path) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(".journal");
+    PathBuf::from(path)
+}
+
+/// The state of the journal of an archive.
+enum JournalState {
+    Missing,
+    // the journal doesn't even have a header, so the stream wasn't touched
+    Empty,
+    // the transaction didn't commit, anything past the original length of
+    // the stream is garbage
+    Incomplete { original_len: u64 },
+    // the transaction committed, but its writes might not have been applied
+    Complete { writes: Vec<(u64, Vec<u8>)> },
+}
+
+fn read_journal(path: &Path) -> io::Result<JournalState> {
+    let mut buf = Vec::new();
+    match fs::File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut buf)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(JournalState::Missing),
+        Err(e) => return Err(e),
+    };
+    // the header is written before the transaction touches the stream, so
+    // without it there is nothing to recover
+    if buf.len() < JOURNAL_HEADER_LEN || !buf.starts_with(JOURNAL_MAGIC) {
+        return Ok(JournalState::Empty);
+    }
+    let original_len = u64::from_le_bytes(buf[8..16].try_into().unwrap());
+    Ok(
+        match parse_writes(original_len, &buf[JOURNAL_HEADER_LEN..]) {
+            Some(writes) => JournalState::Complete { writes },
+            None => JournalState::Incomplete { original_len },
+        },
+    )
+}
+
+/// Rolls a transaction that was interrupted forward or back, depending on
+/// whether its journal was completed.
+pub(super) fn recover<B: WriteAt>(stream: &mut B, path: &Path) -> io::Result<()> {
+    match read_journal(path)? {
+        JournalState::Missing => return Ok(()),
+        JournalState::Empty => (),
+        JournalState::Incomplete { original_len } => {
+            if stream.stream_len()? > original_len {
+                stream.set_len(original_len)?;
+            }
+            stream.sync()?;
+        }
+        JournalState::Complete { writes } => apply(stream, &writes)?,
+    }
+    fs::remove_file(path)
+}
+
+/// Fails if the journal at `path` belongs to a committed transaction that
+/// might not have been applied completely, which is the only case in which
+/// the archive can't be read without recovering it first.
+pub(super) fn ensure_recovered(path: &Path) -> io::Result<()> {
+    match read_journal(path)? {
+        JournalState::Complete { .. } => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the archive has a partially applied transaction, \
+             open it for writing to recover it",
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn apply<B: WriteAt>(stream: &mut B, writes: &[(u64, Vec<u8>)]) -> io::Result<()> {
+    for (offset, data) in writes {
+        stream.write_all_at(data, *offset)?;
+    }
+    stream.sync()
+}
+
+fn parse_writes(original_len: u64, body: &[u8]) -> Option<Vec<(u64, Vec<u8>)>> {
+    let (body, checksum_bytes) = body.split_at(body.len().checked_sub(8)?);
+    if checksum(original_len, body) != u64::from_le_bytes(checksum_bytes.try_into().ok()?) {
+        return None;
+    }
+    fn take(rest: &mut &[u8], n: usize) -> Option<Vec<u8>> {
+        if rest.len() < n {
+            return None;
+        }
+        let (head, tail) = rest.split_at(n);
+        *rest = tail;
+        Some(head.to_vec())
+    }
+    let mut rest = body;
+    let count = u32::from_le_bytes(take(&mut rest, 4)?.try_into().ok()?);
+    let mut writes = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let offset = u64::from_le_bytes(take(&mut rest, 8)?.try_into().ok()?);
+        let len = u32::from_le_bytes(take(&mut rest, 4)?.try_into().ok()?);
+        writes.push((offset, take(&mut rest, len as usize)?));
+    }
+    Some(writes)
+}
+
+// FNV-1a over the original length and the journal body
+fn checksum(original_len: u64, body: &[u8]) -> u64 {
+    original_len
+        .to_le_bytes()
+        .iter()
+        .chain(body)
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{checksum, ensure_recovered, journal_path, recover, Journal};
+    use crate::{ReadAt, WriteAt};
+
+    #[test]
+    fn journaled_writes_are_merged() {
+        let mut inner = vec![0u8; 16];
+        let journal = Journal::begin(None, 12).unwrap();
+        let mut stream = journal.stream(&mut inner);
+        stream.write_all_at(&[1, 1], 2).unwrap();
+        stream.write_all_at(&[2, 2], 6).unwrap();
+        // bridges the two writes and overwrites parts of both
+        stream.write_all_at(&[3, 3, 3], 3).unwrap();
+        stream.write_all_at(&[4, 4], 6).unwrap();
+        // adjacent to the merged write
+        stream.write_all_at(&[5], 8).unwrap();
+        stream.write_all_at(&[6], 0).unwrap();
+        // past the original length, so not held back
+        stream.write_all_at(&[7, 7], 12).unwrap();
+
+        let mut buf = [0; 16];
+        stream.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, [6, 0, 1, 3, 3, 3, 4, 4, 5, 0, 0, 0, 7, 7, 0, 0]);
+        let mut buf = [0; 3];
+        stream.read_exact_at(&mut buf, 5).unwrap();
+        assert_eq!(buf, [3, 4, 4]);
+        assert_eq!(
+            stream.into_writes(),
+            [(0, vec![6]), (2, vec![1, 3, 3, 3, 4, 4, 5])]
+        );
+        assert_eq!(inner[..12], [0; 12]);
+    }
+
+    #[test]
+    fn recover_complete_journal() {
+        let path = std::env::temp_dir().join(format!("pk2-journal-{}", std::process::id()));
+        let journal = journal_path(&path);
+        let mut stream = vec![0u8; 16];
+        let mut body = Vec::new();
+        body.extend_from_slice(&1u32.to_le_bytes());
+        body.extend_from_slice(&4u64.to_le_bytes());
+        body.extend_from_slice(&3u32.to_le_bytes());
+        body.extend_from_slice(&[1, 2, 3]);
+        body.extend_from_slice(&checksum(12, &body).to_le_bytes());
+        let mut contents = super::JOURNAL_MAGIC.to_vec();
+        contents.extend_from_slice(&12u64.to_le_bytes());
+        contents.extend_from_slice(&body);
+        std::fs::write(&journal, contents).unwrap();
+        // the stream may be half updated, so it can't be read as is
+        assert!(ensure_recovered(&journal).is_err());
+
+        recover(&mut stream, &journal).unwrap();
+        assert!(!journal.exists());
+        // rolled forward, the data appended by the transaction is kept
+        assert_eq!(stream.len(), 16);
+        assert_eq!(stream[4..7], [1, 2, 3]);
+
+        // an aborted journal truncates the stream back to its original size
+        let journal_handle = Journal::begin(Some(&journal), 12).unwrap();
+        drop(journal_handle);
+        assert!(ensure_recovered(&journal).is_ok());
+        recover(&mut stream, &journal).unwrap();
+        assert_eq!(stream.len(), 12);
+    }
+}
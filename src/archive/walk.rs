@@ -0,0 +1,182 @@
+//! Recursive traversal of an archive's directory tree.
+use std::path::{Path, PathBuf};
+
+use crate::archive::fs::{DirEntry, Directory, EntryKind};
+use crate::error::Result;
+use crate::{Blowfish, IndexCipher, ReadAt};
+
+/// An entry yielded by a [`Walk`].
+pub struct WalkEntry<'pk2, B, M, C = Blowfish> {
+    path: PathBuf,
+    depth: usize,
+    entry: DirEntry<'pk2, B, M, C>,
+}
+
+impl<'pk2, B, M, C> WalkEntry<'pk2, B, M, C> {
+    /// The absolute path of this entry in the archive.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn into_path(self) -> PathBuf {
+        self.path
+    }
+
+    /// The depth of this entry relative to the walk's base directory, which
+    /// has a depth of 0.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn kind(&self) -> EntryKind {
+        self.entry.kind()
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.kind() == EntryKind::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind() == EntryKind::Directory
+    }
+
+    pub fn entry(&self) -> &DirEntry<'pk2, B, M, C> {
+        &self.entry
+    }
+
+    pub fn into_entry(self) -> DirEntry<'pk2, B, M, C> {
+        self.entry
+    }
+}
+
+struct Frame<'pk2, B, M, C> {
+    dir: Directory<'pk2, B, M, C>,
+    path: PathBuf,
+    depth: usize,
+    children: std::vec::IntoIter<DirEntry<'pk2, B, M, C>>,
+}
+
+/// A recursive iterator over the entries of a directory, created by
+/// [`Pk2::walk`](crate::archive::Pk2::walk).
+///
+/// The base directory itself is yielded first with a depth of 0, or last if
+/// the walk is in post-order. Directories are only read once the walk enters
+/// them, failing to do so yields an error in place of their contents.
+pub struct Walk<'pk2, B, M, C = Blowfish> {
+    base: Option<(Directory<'pk2, B, M, C>, PathBuf)>,
+    stack: Vec<Frame<'pk2, B, M, C>>,
+    max_depth: usize,
+    contents_first: bool,
+}
+
+impl<'pk2, B, M, C> Walk<'pk2, B, M, C>
+where
+    B: ReadAt,
+    C: IndexCipher,
+{
+    pub(super) fn new(base: Directory<'pk2, B, M, C>, path: PathBuf) -> Self {
+        Walk {
+            base: Some((base, path)),
+            stack: Vec::new(),
+            max_depth: usize::MAX,
+            contents_first: false,
+        }
+    }
+
+    /// Don't descend into directories deeper than `depth`, so that only
+    /// entries with a depth of at most `depth` are yielded.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Yield the contents of directories before the directories themselves,
+    /// walking the tree in post-order instead of pre-order.
+    pub fn contents_first(mut self, contents_first: bool) -> Self {
+        self.contents_first = contents_first;
+        self
+    }
+
+    /// Skips the remaining contents of the directory that is currently being
+    /// walked. In pre-order, calling this right after a directory has been
+    /// yielded skips that directory's entire subtree.
+    pub fn skip_current_dir(&mut self) {
+        self.stack.pop();
+    }
+
+    fn enter(
+        &mut self,
+        dir: Directory<'pk2, B, M, C>,
+        path: PathBuf,
+        depth: usize,
+    ) -> Option<Result<WalkEntry<'pk2, B, M, C>>> {
+        if depth >= self.max_depth {
+            return Some(Ok(WalkEntry {
+                path,
+                depth,
+                entry: DirEntry::Directory(dir),
+            }));
+        }
+        let children = match dir.entries() {
+            Ok(children) => children.collect::<Vec<_>>().into_iter(),
+            Err(e) => return Some(Err(e.in_dir(&path))),
+        };
+        let yielded = (!self.contents_first).then(|| WalkEntry {
+            path: path.clone(),
+            depth,
+            entry: DirEntry::Directory(dir.clone()),
+        });
+        self.stack.push(Frame {
+            dir,
+            path,
+            depth,
+            children,
+        });
+        yielded.map(Ok)
+    }
+}
+
+impl<'pk2, B, M, C> Iterator for Walk<'pk2, B, M, C>
+where
+    B: ReadAt,
+    C: IndexCipher,
+{
+    type Item = Result<WalkEntry<'pk2, B, M, C>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((base, path)) = self.base.take() {
+            if let Some(item) = self.enter(base, path, 0) {
+                return Some(item);
+            }
+        }
+        loop {
+            let frame = self.stack.last_mut()?;
+            let depth = frame.depth + 1;
+            match frame.children.next() {
+                Some(DirEntry::File(file)) => {
+                    return Some(Ok(WalkEntry {
+                        path: frame.path.join(file.name()),
+                        depth,
+                        entry: DirEntry::File(file),
+                    }));
+                }
+                Some(DirEntry::Directory(dir)) => {
+                    let path = frame.path.join(dir.name());
+                    if let Some(item) = self.enter(dir, path, depth) {
+                        return Some(item);
+                    }
+                }
+                None => {
+                    let frame = self.stack.pop().unwrap();
+                    if self.contents_first {
+                        return Some(Ok(WalkEntry {
+                            path: frame.path,
+                            depth: frame.depth,
+                            entry: DirEntry::Directory(frame.dir),
+                        }));
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! Positional io traits for the streams backing an archive.
+//!
+//! Unlike [`std::io::Read`] and [`std::io::Seek`] reading at an offset does
+//! not require mutable access to the stream, allowing an archive to be read
+//! from multiple threads at once.
+
+use std::{fs, io};
+
+/// A stream that can be read from at arbitrary offsets through a shared
+/// reference.
+pub trait ReadAt {
+    /// Reads bytes starting at `offset` into `buf`, returning the number of
+    /// bytes read. A return value of 0 means the end of the stream has been
+    /// reached.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// The current length of the stream in bytes.
+    fn stream_len(&self) -> io::Result<u64>;
+
+    /// Reads exactly `buf.len()` bytes starting at `offset`.
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => break,
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if buf.is_empty() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            ))
+        }
+    }
+}
+
+/// A stream that can be written to at arbitrary offsets.
+pub trait WriteAt: ReadAt {
+    /// Writes bytes from `buf` starting at `offset`, returning the number of
+    /// bytes written. Writing past the end of the stream extends it.
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize>;
+
+    /// Truncates or extends the stream to `len` bytes.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Makes sure all written data has reached its destination, like
+    /// [`std::fs::File::sync_data`]. Does nothing by default.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Writes all of `buf` starting at `offset`.
+    fn write_all_at(&mut self, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write_at(buf, offset) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ))
+                }
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for &T {
+    #[inline]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    #[inline]
+    fn stream_len(&self) -> io::Result<u64> {
+        (**self).stream_len()
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for &mut T {
+    #[inline]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    #[inline]
+    fn stream_len(&self) -> io::Result<u64> {
+        (**self).stream_len()
+    }
+}
+
+impl<T: WriteAt + ?Sized> WriteAt for &mut T {
+    #[inline]
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        (**self).write_at(buf, offset)
+    }
+
+    #[inline]
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        (**self).set_len(len)
+    }
+
+    #[inline]
+    fn sync(&mut self) -> io::Result<()> {
+        (**self).sync()
+    }
+}
+
+impl ReadAt for fs::File {
+    #[cfg(unix)]
+    #[inline]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    #[inline]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+
+    #[inline]
+    fn stream_len(&self) -> io::Result<u64> {
+        self.metadata().map(|metadata| metadata.len())
+    }
+}
+
+impl WriteAt for fs::File {
+    #[cfg(unix)]
+    #[inline]
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::write_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    #[inline]
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_write(self, buf, offset)
+    }
+
+    #[inline]
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        fs::File::set_len(self, len)
+    }
+
+    #[inline]
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+impl ReadAt for [u8] {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let start = (offset as usize).min(self.len());
+        let n = buf.len().min(self.len() - start);
+        buf[..n].copy_from_slice(&self[start..start + n]);
+        Ok(n)
+    }
+
+    #[inline]
+    fn stream_len(&self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+}
+
+impl ReadAt for Vec<u8> {
+    #[inline]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        <[u8]>::read_at(self, buf, offset)
+    }
+
+    #[inline]
+    fn stream_len(&self) -> io::Result<u64> {
+        <[u8]>::stream_len(self)
+    }
+}
+
+impl WriteAt for Vec<u8> {
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let offset = offset as usize;
+        let end = offset + buf.len();
+        if end > self.len() {
+            self.resize(end, 0);
+        }
+        self[offset..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl ReadAt for memmap2::Mmap {
+    #[inline]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        <[u8]>::read_at(self, buf, offset)
+    }
+
+    #[inline]
+    fn stream_len(&self) -> io::Result<u64> {
+        <[u8]>::stream_len(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ReadAt, WriteAt};
+
+    #[test]
+    fn vec_read_write_at() {
+        let mut buf = Vec::new();
+        buf.write_all_at(b"bar", 3).unwrap();
+        buf.write_all_at(b"foo", 0).unwrap();
+        assert_eq!(buf, b"foobar");
+        let mut out = [0; 4];
+        assert_eq!(buf.read_at(&mut out, 4).unwrap(), 2);
+        assert_eq!(&out[..2], b"ar");
+        assert!(buf.read_exact_at(&mut out, 4).is_err());
+        buf.read_exact_at(&mut out, 1).unwrap();
+        assert_eq!(&out, b"ooba");
+    }
+}
@@ -16,6 +16,7 @@ impl fmt::Display for InvalidKey {
     }
 }
 
+#[derive(Clone)]
 pub struct Blowfish {
     s: [[u32; 256]; 4],
     p: [u32; 18],
@@ -36,14 +37,31 @@ fn next_u32_wrap(buf: &[u8], offset: &mut usize) -> u32 {
 }
 
 impl Blowfish {
+    /// Creates the cipher for `key`, deriving the blowfish key from it with
+    /// the salt the official clients use, [`PK2_SALT`].
     pub fn new(key: &[u8]) -> Result<Self, InvalidKey> {
-        if key.len() < 4 || key.len() > 56 {
+        Self::new_with_salt(key, &PK2_SALT)
+    }
+
+    /// Like [`Blowfish::new`], but derives the blowfish key with a custom
+    /// `salt` of at most 56 bytes, as some modified clients do.
+    pub fn new_with_salt(key: &[u8], salt: &[u8]) -> Result<Self, InvalidKey> {
+        if salt.len() > 56 {
             return Err(InvalidKey);
         }
         let mut key = key.to_vec();
-        gen_final_blowfish_key_inplace(&mut key);
+        gen_final_blowfish_key_inplace(&mut key, salt);
+        Self::new_raw(&key)
+    }
+
+    /// Creates the cipher for an already derived blowfish key, which is used
+    /// as is.
+    pub fn new_raw(key: &[u8]) -> Result<Self, InvalidKey> {
+        if key.len() < 4 || key.len() > 56 {
+            return Err(InvalidKey);
+        }
         let mut this = Blowfish { p: P, s: S };
-        this.expand_key(&key);
+        this.expand_key(key);
         Ok(this)
     }
 
@@ -130,11 +148,11 @@ impl Blowfish {
     }
 }
 
-fn gen_final_blowfish_key_inplace(key: &mut [u8]) {
+fn gen_final_blowfish_key_inplace(key: &mut [u8], salt: &[u8]) {
     let key_len = key.len().min(56);
 
     let mut base_key = [0; 56];
-    base_key[0..PK2_SALT.len()].copy_from_slice(&PK2_SALT);
+    base_key[0..salt.len()].copy_from_slice(salt);
 
     for i in 0..key_len {
         key[i] ^= base_key[i];
@@ -0,0 +1,46 @@
+//! The cipher the index of an archive is encrypted with.
+use crate::constants::PK2_CHECKSUM;
+use crate::raw::header::PackHeader;
+use crate::Blowfish;
+
+/// A cipher the index of an archive is encrypted with. File data is never
+/// encrypted.
+///
+/// Buffers are always a multiple of 8 bytes long, as blocks and single
+/// entries are encrypted on their own. The cipher therefore has to encrypt
+/// every 8 byte chunk independently of the others, like a block cipher in ECB
+/// mode does.
+///
+/// [`Blowfish`] is the cipher of the official clients, others can be used by
+/// passing them as the key of an archive, see
+/// [`ArchiveKey`](crate::ArchiveKey).
+pub trait IndexCipher {
+    fn encrypt(&self, data: &mut [u8]);
+
+    fn decrypt(&self, data: &mut [u8]);
+
+    /// The checksum stored in the header of archives encrypted with this
+    /// cipher, by default the encrypted [`PK2_CHECKSUM`].
+    fn checksum(&self) -> [u8; 16] {
+        let mut checksum = *PK2_CHECKSUM;
+        self.encrypt(&mut checksum);
+        checksum
+    }
+
+    /// Whether the archive with the given header was encrypted with this
+    /// cipher. By default only the part of the checksum the official clients
+    /// store is compared.
+    fn verify(&self, header: &PackHeader) -> bool {
+        header.verify(self.checksum()).is_ok()
+    }
+}
+
+impl IndexCipher for Blowfish {
+    fn encrypt(&self, data: &mut [u8]) {
+        Blowfish::encrypt(self, data)
+    }
+
+    fn decrypt(&self, data: &mut [u8]) {
+        Blowfish::decrypt(self, data)
+    }
+}
@@ -10,6 +10,14 @@ pub const PK2_SIGNATURE: &[u8; 30] =
 pub const PK2_SALT: [u8; 10] = [0x03, 0xF8, 0xE4, 0x44, 0x88, 0x99, 0x3F, 0x64, 0xFE, 0x35];
 pub const PK2_CHECKSUM_STORED: usize = 3;
 pub const PK2_CHECKSUM: &[u8; 16] = b"Joymax Pak File\0";
+/// Blowfish keys of official clients together with the clients using them,
+/// see [`Pk2::open_with_any_key`](crate::archive::Pk2::open_with_any_key).
+///
+/// This is not a complete registry. Only the international and vietnamese
+/// key could be sourced, the keys of the other regional clients (KSRO, CSRO,
+/// JSRO, TRSRO, ...) are missing and have to be passed as candidates by the
+/// caller.
+pub const PK2_KNOWN_KEYS: &[(&str, &[u8])] = &[("ISRO, VSRO", b"169841")];
 
 pub const PK2_FILE_ENTRY_SIZE: usize = mem::size_of::<RawPackFileEntry>();
 pub const PK2_FILE_BLOCK_ENTRY_COUNT: usize = 20;
@@ -18,12 +26,16 @@ pub const PK2_FILE_BLOCK_SIZE: usize =
 
 pub const PK2_ROOT_BLOCK: ChainIndex = ChainIndex(mem::size_of::<RawPackHeader>() as u64);
 // Sentinel entry to give the root block a proper path descriptor
-pub const PK2_ROOT_BLOCK_VIRTUAL: ChainIndex = ChainIndex(0 as u64);
+pub const PK2_ROOT_BLOCK_VIRTUAL: ChainIndex = ChainIndex(0_u64);
+
+// the structs mirror the on-disk layout, so they must not contain padding
+const _: () = assert!(mem::size_of::<RawPackHeader>() == 256);
+const _: () = assert!(PK2_FILE_ENTRY_SIZE == 128);
 
 pub static PK2_CURRENT_DIR_IDENT: &str = ".";
 pub static PK2_PARENT_DIR_IDENT: &str = "..";
 
-#[repr(packed)]
+#[repr(C, packed)]
 pub struct RawPackHeader {
     pub signature: [u8; 30],
     pub version: u32,
@@ -32,7 +44,7 @@ pub struct RawPackHeader {
     pub reserved: [u8; 205],
 }
 
-#[repr(packed)]
+#[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct RawPackFileEntry {
     pub ty: u8, //0 = Empty, 1 = Directory, 2  = File
@@ -1,3 +1,4 @@
+use std::path::{Path, PathBuf};
 use std::{error, fmt, io};
 
 pub use crate::blowfish::InvalidKey;
@@ -10,6 +11,8 @@ pub enum ChainLookupError {
     InvalidChainIndex,
     ExpectedDirectory,
     ExpectedFile,
+    /// Reading a chain that wasn't loaded yet failed.
+    Io(io::ErrorKind),
 }
 
 impl error::Error for ChainLookupError {}
@@ -28,42 +31,229 @@ impl From<ChainLookupError> for io::Error {
             ChainLookupError::InvalidChainIndex => io::ErrorKind::InvalidData,
             ChainLookupError::ExpectedDirectory => io::ErrorKind::NotFound,
             ChainLookupError::ExpectedFile => io::ErrorKind::NotFound,
+            ChainLookupError::Io(kind) => kind,
         }
         .into()
     }
 }
 
-pub type OpenResult<T> = std::result::Result<T, OpenError>;
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The error type of the operations of this crate.
+///
+/// Every error can be converted into an [`io::Error`] of the corresponding
+/// [`io::ErrorKind`], see [`Error::kind`], which keeps this error as its inner
+/// error.
 #[derive(Debug)]
-pub enum OpenError {
-    InvalidKey,
-    CorruptedFile,
-    UnsupportedVersion,
-    Io(io::Error),
+pub enum Error {
+    /// Looking up a path inside of the archive failed.
+    Lookup {
+        /// The path that was looked up.
+        path: PathBuf,
+        /// The component of `path` the lookup failed at, if it failed at a
+        /// specific one.
+        component: Option<String>,
+        source: ChainLookupError,
+    },
+    /// The archive is malformed at the given stream offset, which is the
+    /// offset of the block for malformed index entries.
+    Corrupted { offset: u64, reason: &'static str },
+    /// The key is invalid or it isn't the one the archive was encrypted with.
+    Key { source: Option<InvalidKey> },
+    /// The archive has a version other than the supported one.
+    UnsupportedVersion { version: u32 },
+    /// Reading from or writing to the stream failed.
+    Io {
+        /// The path of the file or directory in the archive that was being
+        /// accessed, if any.
+        path: Option<PathBuf>,
+        /// The stream offset that was being accessed, if known.
+        offset: Option<u64>,
+        source: io::Error,
+    },
+}
+
+impl Error {
+    pub(crate) fn lookup(path: &Path, component: Option<&str>, source: ChainLookupError) -> Self {
+        Error::Lookup {
+            path: path.to_owned(),
+            component: component.map(ToOwned::to_owned),
+            source,
+        }
+    }
+
+    /// Attaches `path` to the error if it is an I/O error without one.
+    pub(crate) fn with_path(self, path: &Path) -> Self {
+        match self {
+            Error::Io {
+                path: None,
+                offset,
+                source,
+            } => Error::Io {
+                path: Some(path.to_owned()),
+                offset,
+                source,
+            },
+            e => e,
+        }
+    }
+
+    /// Makes the path of the error, which is relative to the directory at
+    /// `dir`, absolute. A path of `.` refers to the directory itself.
+    pub(crate) fn in_dir(self, dir: &Path) -> Self {
+        let join = |path: &Path| match path == Path::new(".") {
+            true => dir.to_owned(),
+            false => dir.join(path),
+        };
+        match self {
+            Error::Lookup {
+                path,
+                component,
+                source,
+            } => Error::Lookup {
+                path: join(&path),
+                component,
+                source,
+            },
+            Error::Io {
+                path,
+                offset,
+                source,
+            } => Error::Io {
+                path: Some(path.as_deref().map_or_else(|| dir.to_owned(), join)),
+                offset,
+                source,
+            },
+            e => e,
+        }
+    }
+
+    /// Attaches the stream `offset` to the error if it is an I/O error
+    /// without one.
+    pub(crate) fn with_offset(self, offset: u64) -> Self {
+        match self {
+            Error::Io {
+                path,
+                offset: None,
+                source,
+            } => Error::Io {
+                path,
+                offset: Some(offset),
+                source,
+            },
+            e => e,
+        }
+    }
+
+    /// The kind of [`io::Error`] this error converts into.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::Lookup { source, .. } => io::Error::from(*source).kind(),
+            Error::Corrupted { .. } => io::ErrorKind::InvalidData,
+            Error::Key { .. } => io::ErrorKind::InvalidInput,
+            Error::UnsupportedVersion { .. } => io::ErrorKind::Unsupported,
+            Error::Io { source, .. } => source.kind(),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Lookup { source, .. } => Some(source),
+            Error::Key {
+                source: Some(source),
+            } => Some(source),
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
 }
 
-impl error::Error for OpenError {}
-impl fmt::Display for OpenError {
+impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            OpenError::CorruptedFile => write!(f, "archive is invalid or corrupted"),
-            OpenError::UnsupportedVersion => write!(f, "archive version is not supported"),
-            OpenError::InvalidKey => write!(f, "blowfish key was invalid"),
-            OpenError::Io(e) => fmt::Display::fmt(e, f),
+            Error::Lookup {
+                path,
+                component,
+                source,
+            } => {
+                write!(f, "failed to look up {:?}: ", path)?;
+                let Some(component) = component else {
+                    return fmt::Display::fmt(source, f);
+                };
+                match source {
+                    ChainLookupError::NotFound => write!(f, "{:?} not found", component),
+                    ChainLookupError::InvalidPath => write!(f, "invalid path"),
+                    ChainLookupError::InvalidChainIndex => {
+                        write!(f, "{:?} refers to a chain that doesn't exist", component)
+                    }
+                    ChainLookupError::ExpectedDirectory => {
+                        write!(f, "{:?} is not a directory", component)
+                    }
+                    ChainLookupError::ExpectedFile => write!(f, "{:?} is not a file", component),
+                    ChainLookupError::Io(kind) => {
+                        write!(f, "reading the chain of {:?} failed: {}", component, kind)
+                    }
+                }
+            }
+            Error::Corrupted { offset, reason } => {
+                write!(f, "archive is corrupted at offset {}: {}", offset, reason)
+            }
+            Error::Key { .. } => write!(f, "invalid key or cipher for this archive"),
+            Error::UnsupportedVersion { version } => {
+                write!(f, "archive version {:#x} is not supported", version)
+            }
+            Error::Io {
+                path,
+                offset,
+                source,
+            } => {
+                match (path, offset) {
+                    (Some(path), Some(offset)) => write!(f, "{:?} at offset {}: ", path, offset)?,
+                    (Some(path), None) => write!(f, "{:?}: ", path)?,
+                    (None, Some(offset)) => write!(f, "at offset {}: ", offset)?,
+                    (None, None) => (),
+                }
+                fmt::Display::fmt(source, f)
+            }
         }
     }
 }
 
-impl From<io::Error> for OpenError {
+impl From<Error> for io::Error {
+    #[inline]
+    fn from(this: Error) -> Self {
+        match this {
+            // nothing to add to the original error
+            Error::Io {
+                path: None,
+                offset: None,
+                source,
+            } => source,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
     #[inline]
     fn from(e: io::Error) -> Self {
-        OpenError::Io(e)
+        // undo the conversion into an io::Error
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        Error::Io {
+            path: None,
+            offset: None,
+            source: e,
+        }
     }
 }
 
-impl From<InvalidKey> for OpenError {
+impl From<InvalidKey> for Error {
     #[inline]
-    fn from(_: InvalidKey) -> Self {
-        OpenError::InvalidKey
+    fn from(e: InvalidKey) -> Self {
+        Error::Key { source: Some(e) }
     }
 }
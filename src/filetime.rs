@@ -1,7 +1,7 @@
 use std::time::{Duration, SystemTime};
 
-#[allow(non_snake_case)]
-#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[allow(non_snake_case, clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
 pub struct FILETIME {
     pub dwLowDateTime: u32,
     pub dwHighDateTime: u32,
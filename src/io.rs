@@ -2,56 +2,82 @@
 
 //! General io for reading/writing from/to buffers.
 
-use std::io::{self, SeekFrom};
+use std::io;
+use std::sync::Arc;
 
 use crate::constants::{
     PK2_CURRENT_DIR_IDENT, PK2_FILE_BLOCK_SIZE, PK2_FILE_ENTRY_SIZE, PK2_PARENT_DIR_IDENT,
 };
-use crate::error::OpenResult;
+use crate::error::{Error, Result};
 use crate::raw::block_chain::{PackBlock, PackBlockChain};
 use crate::raw::entry::PackEntry;
+use crate::raw::free_space::FreeSpaceMap;
 use crate::raw::{BlockOffset, ChainIndex, EntryOffset, StreamOffset};
-use crate::Blowfish;
+use crate::{IndexCipher, ReadAt, WriteAt};
 
 /// Read a block at a given offset.
-pub fn read_block_at<F: io::Seek + io::Read>(
-    bf: Option<&Blowfish>,
-    mut stream: F,
+pub fn read_block_at<C: IndexCipher + ?Sized, F: ReadAt>(
+    bf: Option<&C>,
+    stream: F,
+    BlockOffset(offset): BlockOffset,
+) -> Result<PackBlock> {
+    let mut buf = [0; PK2_FILE_BLOCK_SIZE];
+    stream
+        .read_exact_at(&mut buf, offset)
+        .map_err(|e| Error::from(e).with_offset(offset))?;
+    bf.map(|bf| bf.decrypt(&mut buf));
+    // reading from the buffer can't fail, only parsing the entries can
+    PackBlock::from_reader(&buf[..]).map_err(|_| Error::Corrupted {
+        offset,
+        reason: "block contains an entry of an unknown type",
+    })
+}
+
+/// Read a block at a given offset like [`read_block_at`], but treat entries
+/// with an unknown type as empty instead of failing. The indices and type
+/// bytes of such entries are pushed onto `invalid`.
+pub fn read_block_at_lenient<C: IndexCipher + ?Sized, F: ReadAt>(
+    bf: Option<&C>,
+    stream: F,
     BlockOffset(offset): BlockOffset,
-) -> OpenResult<PackBlock> {
+    invalid: &mut Vec<(usize, u8)>,
+) -> Result<PackBlock> {
+    // the offset of the next block field, it is at the same place for every
+    // kind of entry
+    const NEXT_BLOCK_OFFSET: usize = PK2_FILE_ENTRY_SIZE - 10;
     let mut buf = [0; PK2_FILE_BLOCK_SIZE];
-    stream.seek(SeekFrom::Start(offset))?;
-    stream.read_exact(&mut buf)?;
+    stream
+        .read_exact_at(&mut buf, offset)
+        .map_err(|e| Error::from(e).with_offset(offset))?;
     bf.map(|bf| bf.decrypt(&mut buf));
+    for (idx, raw) in buf.chunks_exact_mut(PK2_FILE_ENTRY_SIZE).enumerate() {
+        if raw[0] > 2 {
+            invalid.push((idx, raw[0]));
+            raw[..NEXT_BLOCK_OFFSET].fill(0);
+        }
+    }
     PackBlock::from_reader(&buf[..]).map_err(Into::into)
 }
 
-pub fn read_exact_at<F: io::Seek + io::Read>(
-    mut stream: F,
+pub fn read_exact_at<F: ReadAt>(
+    stream: F,
     StreamOffset(offset): StreamOffset,
     buf: &mut [u8],
 ) -> io::Result<()> {
-    stream.seek(SeekFrom::Start(offset))?;
-    stream.read_exact(buf)
+    stream.read_exact_at(buf, offset)
 }
 
-pub fn read_at<F: io::Seek + io::Read>(
-    mut stream: F,
+pub fn read_at<F: ReadAt>(
+    stream: F,
     StreamOffset(offset): StreamOffset,
     buf: &mut [u8],
 ) -> io::Result<usize> {
-    stream.seek(SeekFrom::Start(offset))?;
-    stream.read(buf)
-}
-
-#[inline]
-fn stream_len<F: io::Seek>(mut stream: F) -> io::Result<u64> {
-    stream.seek(SeekFrom::End(0))
+    stream.read_at(buf, offset)
 }
 
 /// Write/Update a block at the given block offset in the file.
-pub fn write_block<F: io::Seek + io::Write>(
-    bf: Option<&Blowfish>,
+pub fn write_block<C: IndexCipher + ?Sized, F: WriteAt>(
+    bf: Option<&C>,
     mut stream: F,
     BlockOffset(offset): BlockOffset,
     block: &PackBlock,
@@ -59,14 +85,23 @@ pub fn write_block<F: io::Seek + io::Write>(
     let mut buf = [0; PK2_FILE_BLOCK_SIZE];
     block.to_writer(&mut buf[..])?;
     bf.map(|bf| bf.encrypt(&mut buf));
-    stream.seek(SeekFrom::Start(offset))?;
-    stream.write_all(&buf)?;
-    Ok(())
+    stream.write_all_at(&buf, offset)
+}
+
+/// Write/Update all blocks of the given chain in the file.
+pub fn write_chain<C: IndexCipher + ?Sized, F: WriteAt>(
+    bf: Option<&C>,
+    mut stream: F,
+    chain: &PackBlockChain,
+) -> io::Result<()> {
+    chain
+        .blocks()
+        .try_for_each(|(offset, block)| write_block(bf, &mut stream, offset, &block))
 }
 
 /// Write/Update an entry at the given entry offset in the file.
-pub fn write_entry_at<F: io::Seek + io::Write>(
-    bf: Option<&Blowfish>,
+pub fn write_entry_at<C: IndexCipher + ?Sized, F: WriteAt>(
+    bf: Option<&C>,
     mut stream: F,
     EntryOffset(offset): EntryOffset,
     entry: &PackEntry,
@@ -74,16 +109,14 @@ pub fn write_entry_at<F: io::Seek + io::Write>(
     let mut buf = [0; PK2_FILE_ENTRY_SIZE];
     entry.to_writer(&mut buf[..])?;
     bf.map(|bf| bf.encrypt(&mut buf));
-    stream.seek(SeekFrom::Start(offset))?;
-    stream.write_all(&buf)?;
-    Ok(())
+    stream.write_all_at(&buf, offset)
 }
 
 /// Write/Update a chain's entry at the given chain offset and entry index in
 /// the file.
 #[inline]
-pub fn write_chain_entry<F: io::Seek + io::Write>(
-    bf: Option<&Blowfish>,
+pub fn write_chain_entry<C: IndexCipher + ?Sized, F: WriteAt>(
+    bf: Option<&C>,
     stream: F,
     chain: &PackBlockChain,
     entry_index: usize,
@@ -93,46 +126,53 @@ pub fn write_chain_entry<F: io::Seek + io::Write>(
         bf,
         stream,
         chain.stream_offset_for_entry(entry_index).unwrap(),
-        &chain[entry_index],
+        &chain.pack_entry(entry_index).unwrap(),
     )
 }
 
 /// Write data to the end of the file returning the offset of the written
 /// data in the file.
-pub fn append_data<F: io::Seek + io::Write>(
-    mut stream: F,
-    data: &[u8],
-) -> io::Result<StreamOffset> {
-    let stream_end = stream_len(&mut stream)?;
-    stream.write_all(data)?;
+pub fn append_data<F: WriteAt>(mut stream: F, data: &[u8]) -> io::Result<StreamOffset> {
+    let stream_end = stream.stream_len()?;
+    stream.write_all_at(data, stream_end)?;
     Ok(StreamOffset(stream_end))
 }
 
 /// Write raw data at the given offset into the buffer.
-pub fn write_data_at<F: io::Seek + io::Write>(
+pub fn write_data_at<F: WriteAt>(
     mut stream: F,
     StreamOffset(offset): StreamOffset,
     data: &[u8],
 ) -> io::Result<()> {
-    stream.seek(SeekFrom::Start(offset))?;
-    stream.write_all(data)
+    stream.write_all_at(data, offset)
 }
 
-/// Create a new [`PackBlockChain`] at the end of the buffer and update the
-/// corresponding entry in the chain.
-pub fn allocate_new_block_chain<F: io::Seek + io::Write>(
-    blowfish: Option<&Blowfish>,
+/// Returns the offset to put a new block at, which is either a free region
+/// large enough to hold it or the end of the stream.
+fn allocate_block_offset<F: WriteAt>(free_space: &mut FreeSpaceMap, stream: F) -> io::Result<u64> {
+    match free_space.allocate(PK2_FILE_BLOCK_SIZE as u64) {
+        Some(StreamOffset(offset)) => Ok(offset),
+        None => stream.stream_len(),
+    }
+}
+
+/// Create a new [`PackBlockChain`] in a free region or at the end of the
+/// buffer and update the corresponding entry in the chain.
+pub fn allocate_new_block_chain<C: IndexCipher + ?Sized, F: WriteAt>(
+    blowfish: Option<&C>,
     mut stream: F,
+    free_space: &mut FreeSpaceMap,
     current_chain: &mut PackBlockChain,
     dir_name: &str,
     chain_entry_idx: usize,
 ) -> io::Result<PackBlockChain> {
     debug_assert!(current_chain.contains_entry_index(chain_entry_idx));
-    let new_chain_offset = stream_len(&mut stream).map(ChainIndex)?;
+    let new_chain_offset = allocate_block_offset(free_space, &mut stream).map(ChainIndex)?;
 
-    let entry = &mut current_chain[chain_entry_idx];
-    debug_assert!(entry.is_empty());
-    *entry = PackEntry::new_directory(dir_name, new_chain_offset, entry.next_block());
+    current_chain.update_entry(chain_entry_idx, |entry| {
+        debug_assert!(entry.is_empty());
+        *entry = PackEntry::new_directory(dir_name, new_chain_offset, entry.next_block());
+    });
 
     let mut block = PackBlock::default();
     block[0] = PackEntry::new_directory(PK2_CURRENT_DIR_IDENT, new_chain_offset, None);
@@ -143,19 +183,45 @@ pub fn allocate_new_block_chain<F: io::Seek + io::Write>(
         .stream_offset_for_entry(chain_entry_idx)
         .unwrap();
 
-    write_entry_at(blowfish, stream, offset, &current_chain[chain_entry_idx])?;
-    Ok(PackBlockChain::from_blocks(vec![(
-        new_chain_offset.into(),
-        block,
-    )]))
+    write_entry_at(
+        blowfish,
+        stream,
+        offset,
+        &current_chain.pack_entry(chain_entry_idx).unwrap(),
+    )?;
+    Ok(PackBlockChain::from_blocks(
+        Arc::clone(current_chain.arena()),
+        vec![(new_chain_offset.into(), block)],
+    ))
+}
+
+/// Returns the index of the first empty entry in the chain, appending a new
+/// empty block to the chain if it is full.
+pub fn find_or_allocate_empty_entry<C: IndexCipher + ?Sized, F: WriteAt>(
+    bf: Option<&C>,
+    mut stream: F,
+    free_space: &mut FreeSpaceMap,
+    chain: &mut PackBlockChain,
+) -> io::Result<usize> {
+    if let Some(idx) = chain.first_empty_entry() {
+        return Ok(idx);
+    }
+    // the chain is full so create a new block and append it
+    let (offset, block) = allocate_empty_block(bf, &mut stream, free_space)?;
+    let chain_entry_idx = chain.num_entries();
+    chain.push_and_link(offset, block);
+    write_chain_entry(bf, stream, chain, chain_entry_idx - 1)?;
+    Ok(chain_entry_idx)
 }
 
-/// Create a new empty [`PackBlock`] at the end of the buffer.
-pub fn allocate_empty_block<F: io::Seek + io::Write>(
-    bf: Option<&Blowfish>,
+/// Create a new empty [`PackBlock`] in a free region or at the end of the
+/// buffer.
+pub fn allocate_empty_block<C: IndexCipher + ?Sized, F: WriteAt>(
+    bf: Option<&C>,
     mut stream: F,
+    free_space: &mut FreeSpaceMap,
 ) -> io::Result<(BlockOffset, PackBlock)> {
-    let offset = stream_len(&mut stream).map(BlockOffset)?;
+    let offset = allocate_block_offset(free_space, &mut stream).map(BlockOffset)?;
     let block = PackBlock::default();
     write_block(bf, stream, offset, &block).and(Ok((offset, block)))
 }
@@ -0,0 +1,77 @@
+//! Keys the index of an archive is encrypted with.
+use crate::blowfish::{Blowfish, InvalidKey};
+use crate::IndexCipher;
+
+/// Something the cipher of an archive's index is created from, taken by the
+/// constructors of [`Pk2`](crate::archive::Pk2).
+///
+/// Byte strings like `"169841"` are keys that are salted with
+/// [`PK2_SALT`](crate::constants::PK2_SALT) like the official clients do, an
+/// empty one leaving the index unencrypted. Archives of clients that derive
+/// their blowfish key differently are opened with a [`Key`] instead.
+///
+/// Archives using a custom [`IndexCipher`] are opened by passing the cipher
+/// itself as the key.
+///
+/// The cipher is only created once the archive turns out to be encrypted, so
+/// the key of an unencrypted archive is never validated.
+pub trait ArchiveKey<C = Blowfish> {
+    /// Creates the cipher, `None` meaning that the index isn't encrypted.
+    fn cipher(&self) -> Result<Option<C>, InvalidKey>;
+}
+
+impl<K: AsRef<[u8]> + ?Sized> ArchiveKey for K {
+    fn cipher(&self) -> Result<Option<Blowfish>, InvalidKey> {
+        match self.as_ref() {
+            [] => Ok(None),
+            key => Blowfish::new(key).map(Some),
+        }
+    }
+}
+
+/// A key whose blowfish key is derived with a custom salt or that already is
+/// the derived blowfish key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Key {
+    key: Vec<u8>,
+    // None if the key is used as is
+    salt: Option<Vec<u8>>,
+}
+
+impl Key {
+    /// A key that is salted with `salt` instead of the default salt, see
+    /// [`Blowfish::new_with_salt`].
+    pub fn with_salt(key: impl AsRef<[u8]>, salt: impl AsRef<[u8]>) -> Self {
+        Key {
+            key: key.as_ref().to_vec(),
+            salt: Some(salt.as_ref().to_vec()),
+        }
+    }
+
+    /// An already derived blowfish key, see [`Blowfish::new_raw`].
+    pub fn raw(key: impl AsRef<[u8]>) -> Self {
+        Key {
+            key: key.as_ref().to_vec(),
+            salt: None,
+        }
+    }
+}
+
+impl ArchiveKey for Key {
+    fn cipher(&self) -> Result<Option<Blowfish>, InvalidKey> {
+        if self.key.is_empty() {
+            return Ok(None);
+        }
+        match &self.salt {
+            Some(salt) => Blowfish::new_with_salt(&self.key, salt),
+            None => Blowfish::new_raw(&self.key),
+        }
+        .map(Some)
+    }
+}
+
+impl<C: IndexCipher + Clone> ArchiveKey<C> for C {
+    fn cipher(&self) -> Result<Option<C>, InvalidKey> {
+        Ok(Some(self.clone()))
+    }
+}
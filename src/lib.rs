@@ -7,11 +7,20 @@ pub mod raw;
 
 pub(crate) mod io;
 
+mod backend;
+pub use self::backend::{ReadAt, WriteAt};
+
 mod error;
-pub use self::error::{ChainLookupError, ChainLookupResult, InvalidKey, OpenError};
+pub use self::error::{ChainLookupError, ChainLookupResult, Error, InvalidKey, Result};
 
 mod filetime;
 pub(crate) use self::filetime::FILETIME;
 
 mod blowfish;
 pub use self::blowfish::Blowfish;
+
+mod cipher;
+pub use self::cipher::IndexCipher;
+
+mod key;
+pub use self::key::{ArchiveKey, Key};
@@ -1,7 +1,10 @@
+pub mod arena;
 pub mod block_chain;
 pub mod block_manager;
 pub mod entry;
+pub mod free_space;
 pub mod header;
+mod name_index;
 
 use std::ops;
 
@@ -35,3 +38,33 @@ impl ops::Add for StreamOffset {
         StreamOffset(self.0 + rhs)
     }
 }
+
+/// A hasher builder for `u64` keys that can be used as their own hash, like
+/// chain offsets and name hashes.
+#[derive(Clone, Default)]
+pub(crate) struct NoHashHasherBuilder;
+impl std::hash::BuildHasher for NoHashHasherBuilder {
+    type Hasher = NoHashHasher;
+    #[inline]
+    fn build_hasher(&self) -> Self::Hasher {
+        NoHashHasher(0)
+    }
+}
+
+pub(crate) struct NoHashHasher(u64);
+impl std::hash::Hasher for NoHashHasher {
+    #[inline]
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    #[inline]
+    fn write(&mut self, _: &[u8]) {
+        panic!("NoHashHasher only supports u64 keys. This is a bug!");
+    }
+
+    #[inline]
+    fn write_u64(&mut self, key: u64) {
+        self.0 = key;
+    }
+}
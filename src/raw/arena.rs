@@ -0,0 +1,216 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::{DefaultHasher, Entry, HashMap};
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+use super::NoHashHasherBuilder;
+use crate::FILETIME;
+
+/// The storage shared by all chains of an index. Entry names and timestamps
+/// are interned here, so a [`PackBlockChain`](super::block_chain::PackBlockChain)
+/// only keeps their ids in its records and every distinct value is stored
+/// once no matter how many entries, chains or reloads of a chain carry it.
+///
+/// The arena only ever grows, values that are no longer used by any entry
+/// are kept until the index itself is dropped.
+#[derive(Default)]
+pub struct IndexArena {
+    names: Interner<Box<str>>,
+    // access, create and modify time
+    times: Interner<[FILETIME; 3]>,
+}
+
+impl IndexArena {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the id of `name`, storing it first if it isn't yet.
+    pub fn intern_name(&self, name: &str) -> u32 {
+        self.names.intern(name, |name| name.into())
+    }
+
+    /// Returns the id of the `[access, create, modify]` times, storing them
+    /// first if they aren't yet.
+    pub fn intern_times(&self, times: [FILETIME; 3]) -> u32 {
+        self.times.intern(&times, |&times| times)
+    }
+
+    /// # Panics
+    ///
+    /// Panics if `id` wasn't returned by [`IndexArena::intern_name`].
+    #[inline]
+    pub fn name(&self, id: u32) -> &str {
+        self.names.get(id)
+    }
+
+    /// # Panics
+    ///
+    /// Panics if `id` wasn't returned by [`IndexArena::intern_times`].
+    #[inline]
+    pub fn times(&self, id: u32) -> [FILETIME; 3] {
+        *self.times.get(id)
+    }
+
+    /// The number of distinct names stored.
+    pub fn num_names(&self) -> usize {
+        self.names.len()
+    }
+
+    /// The approximate amount of heap memory used by the arena.
+    pub fn memory_usage(&self) -> usize {
+        let name_bytes = self.names.values().map(|name| name.len()).sum::<usize>();
+        self.names.memory_usage() + name_bytes + self.times.memory_usage()
+    }
+}
+
+// the first bucket holds this many values, every following one twice as many
+// as the one before it, which is enough buckets for every `u32` id
+const FIRST_BUCKET_LEN: u64 = 64;
+const BUCKETS: usize = 27;
+
+/// An append-only store handing out `u32` ids for values, equal values share
+/// an id. Values never move once stored, so they can be read without locking
+/// while others are being added.
+struct Interner<T> {
+    buckets: [OnceLock<Box<[OnceLock<T>]>>; BUCKETS],
+    ids: Mutex<Ids>,
+}
+
+#[derive(Default)]
+struct Ids {
+    // the id of the first value of every hash
+    first: HashMap<u64, u32, NoHashHasherBuilder>,
+    len: u32,
+}
+
+impl<T> Default for Interner<T> {
+    fn default() -> Self {
+        Interner {
+            buckets: Default::default(),
+            ids: Mutex::default(),
+        }
+    }
+}
+
+impl<T> Interner<T> {
+    fn intern<Q>(&self, value: &Q, to_owned: impl FnOnce(&Q) -> T) -> u32
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+        let mut ids = self.ids.lock().unwrap_or_else(PoisonError::into_inner);
+        let id = ids.len;
+        match ids.first.entry(hash) {
+            Entry::Occupied(first) if self.get(*first.get()).borrow() == value => {
+                return *first.get()
+            }
+            // a collision, the value is stored again without being shared
+            Entry::Occupied(_) => (),
+            Entry::Vacant(slot) => {
+                slot.insert(id);
+            }
+        }
+        ids.len = id.checked_add(1).expect("index arena overflow");
+        let (bucket, offset) = Self::slot(id);
+        let stored = self.buckets[bucket].get_or_init(|| {
+            let len = FIRST_BUCKET_LEN << bucket;
+            (0..len).map(|_| OnceLock::new()).collect()
+        })[offset]
+            .set(to_owned(value));
+        debug_assert!(stored.is_ok());
+        id
+    }
+
+    #[inline]
+    fn get(&self, id: u32) -> &T {
+        let (bucket, offset) = Self::slot(id);
+        self.buckets[bucket]
+            .get()
+            .and_then(|bucket| bucket[offset].get())
+            .expect("unknown arena id")
+    }
+
+    fn len(&self) -> usize {
+        self.ids.lock().unwrap_or_else(PoisonError::into_inner).len as usize
+    }
+
+    fn values(&self) -> impl Iterator<Item = &T> {
+        self.buckets
+            .iter()
+            .filter_map(OnceLock::get)
+            .flat_map(|bucket| bucket.iter().map_while(OnceLock::get))
+    }
+
+    fn memory_usage(&self) -> usize {
+        let slots = self
+            .buckets
+            .iter()
+            .filter_map(OnceLock::get)
+            .map(|bucket| bucket.len())
+            .sum::<usize>();
+        let ids = self.ids.lock().unwrap_or_else(PoisonError::into_inner);
+        // a key, a value and a control byte per bucket of the map
+        slots * mem::size_of::<OnceLock<T>>()
+            + ids.first.capacity() * (mem::size_of::<(u64, u32)>() + 1)
+    }
+
+    // the bucket and the offset in it of the value with the given id
+    #[inline]
+    fn slot(id: u32) -> (usize, usize) {
+        let n = id as u64 / FIRST_BUCKET_LEN + 1;
+        let bucket = n.ilog2();
+        let first_id = FIRST_BUCKET_LEN * ((1 << bucket) - 1);
+        (bucket as usize, (id as u64 - first_id) as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IndexArena, Interner};
+    use crate::FILETIME;
+
+    #[test]
+    fn equal_values_share_an_id() {
+        let arena = IndexArena::new();
+        let foo = arena.intern_name("foo");
+        let bar = arena.intern_name("bar");
+        assert_ne!(foo, bar);
+        assert_eq!(arena.intern_name("foo"), foo);
+        // names are case sensitive
+        assert_ne!(arena.intern_name("FOO"), foo);
+        assert_eq!(arena.name(foo), "foo");
+        assert_eq!(arena.name(bar), "bar");
+        assert_eq!(arena.num_names(), 3);
+
+        let time = FILETIME {
+            dwLowDateTime: 1,
+            dwHighDateTime: 2,
+        };
+        let times = arena.intern_times([time; 3]);
+        assert_eq!(arena.intern_times([time; 3]), times);
+        assert_ne!(arena.intern_times([FILETIME::default(); 3]), times);
+        assert_eq!(arena.times(times), [time; 3]);
+    }
+
+    #[test]
+    fn ids_span_buckets() {
+        let interner = Interner::<u32>::default();
+        for value in 0..10_000u32 {
+            assert_eq!(interner.intern(&value, |&value| value), value);
+        }
+        for value in 0..10_000u32 {
+            assert_eq!(*interner.get(value), value);
+        }
+        assert_eq!(interner.values().count(), 10_000);
+        assert_eq!(Interner::<u32>::slot(63), (0, 63));
+        assert_eq!(Interner::<u32>::slot(64), (1, 0));
+        assert_eq!(Interner::<u32>::slot(191), (1, 127));
+        assert_eq!(Interner::<u32>::slot(192), (2, 0));
+        assert_eq!(Interner::<u32>::slot(u32::MAX).0, 26);
+    }
+}
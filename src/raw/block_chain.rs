@@ -1,7 +1,12 @@
 use std::io::{Read, Result as IoResult, Write};
+use std::mem;
+use std::num::NonZeroU64;
 use std::ops;
+use std::sync::Arc;
 
-use super::entry::{DirectoryEntry, PackEntry};
+use super::arena::IndexArena;
+use super::entry::{DirectoryRef, EntryKind, EntryRecord, EntryRef, PackEntry};
+use super::name_index::NameIndex;
 use super::{BlockOffset, ChainIndex, EntryOffset};
 use crate::constants::*;
 use crate::error::{ChainLookupError, ChainLookupResult};
@@ -9,37 +14,87 @@ use crate::io::RawIo;
 
 /// A collection of [`PackBlock`]s where each blocks next_block field points to
 /// the following block in the file. A PackBlockChain is never empty.
+///
+/// In memory a chain does not keep its blocks around. Only the occupied
+/// entries are stored, as packed records ordered by their index, with their
+/// names and timestamps interned in the [`IndexArena`] shared by all chains of
+/// the index. Entries are handed out as [`EntryRef`] views and have to be
+/// modified through [`PackBlockChain::update_entry`], which also keeps the
+/// chain's case-insensitive name index in sync.
+#[derive(Clone)]
 pub struct PackBlockChain {
-    // (offset, block)
-    blocks: Vec<(BlockOffset, PackBlock)>,
+    arena: Arc<IndexArena>,
+    // offsets of the blocks making up this chain
+    blocks: Vec<BlockOffset>,
+    // the occupied entries, ordered by their index
+    records: Vec<EntryRecord>,
+    name_index: NameIndex,
 }
 
 #[allow(clippy::len_without_is_empty)]
 impl PackBlockChain {
-    #[inline]
-    pub fn from_blocks(blocks: Vec<(BlockOffset, PackBlock)>) -> Self {
+    pub fn from_blocks(arena: Arc<IndexArena>, blocks: Vec<(BlockOffset, PackBlock)>) -> Self {
         debug_assert!(!blocks.is_empty());
-        PackBlockChain { blocks }
+        let mut this = PackBlockChain {
+            arena,
+            blocks: Vec::with_capacity(blocks.len()),
+            records: Vec::new(),
+            name_index: NameIndex::default(),
+        };
+        for (offset, block) in blocks {
+            this.push_block(offset, block);
+        }
+        this.blocks.shrink_to_fit();
+        this.records.shrink_to_fit();
+        this
     }
 
+    fn push_block(&mut self, offset: BlockOffset, block: PackBlock) {
+        let base = self.num_entries();
+        self.blocks.push(offset);
+        for (idx, entry) in block.entries().enumerate() {
+            if let Some(record) = EntryRecord::from_entry(&self.arena, base + idx, entry) {
+                self.name_index
+                    .insert(self.arena.name(record.name), base + idx);
+                self.records.push(record);
+            }
+        }
+    }
+
+    /// The arena the names and timestamps of this chain's entries are
+    /// interned in.
     #[inline]
-    pub fn push_and_link(&mut self, offset: BlockOffset, block: PackBlock) {
-        self.last_entry_mut().set_next_block(offset);
-        self.blocks.push((offset, block));
+    pub fn arena(&self) -> &Arc<IndexArena> {
+        &self.arena
     }
 
+    /// Appends the block to the chain, linking it to the previous last block.
     #[inline]
+    pub fn push_and_link(&mut self, offset: BlockOffset, block: PackBlock) {
+        self.push_block(offset, block);
+    }
+
+    /// Removes the last block of the chain along with its entries.
     pub fn pop_and_unlink(&mut self) {
+        assert!(self.blocks.len() > 1);
         self.blocks.pop();
-        assert!(!self.blocks.is_empty());
-        self.last_entry_mut().set_next_block(BlockOffset(0));
+        let num_entries = self.num_entries();
+        if self
+            .records
+            .last()
+            .is_some_and(|record| record.index as usize >= num_entries)
+        {
+            self.records
+                .retain(|record| (record.index as usize) < num_entries);
+            self.reindex();
+        }
     }
 
     /// This blockchains chain index/file offset.
     /// Note: This is the same as its first block
     #[inline]
     pub fn chain_index(&self) -> ChainIndex {
-        ChainIndex((self.blocks[0].0).0)
+        ChainIndex(self.blocks[0].0)
     }
 
     /// Returns the file offset of the entry at the given idx in this block
@@ -47,14 +102,14 @@ impl PackBlockChain {
     pub fn stream_offset_for_entry(&self, idx: usize) -> Option<EntryOffset> {
         self.blocks
             .get(idx / PK2_FILE_BLOCK_ENTRY_COUNT)
-            .map(|(BlockOffset(offset), _)| {
+            .map(|BlockOffset(offset)| {
                 EntryOffset(
                     offset + (PK2_FILE_ENTRY_SIZE * (idx % PK2_FILE_BLOCK_ENTRY_COUNT)) as u64,
                 )
             })
     }
 
-    /// Returns the number of PackEntries in this chain.
+    /// Returns the number of entries in this chain, including empty ones.
     #[inline]
     pub fn num_entries(&self) -> usize {
         self.blocks.len() * PK2_FILE_BLOCK_ENTRY_COUNT
@@ -66,47 +121,121 @@ impl PackBlockChain {
         self.blocks.len()
     }
 
-    /// Returns the last entry of this PackBlockChain.
-    #[inline]
-    pub fn last_entry(&self) -> &PackEntry {
-        &self[self.num_entries() - 1]
+    /// An iterator over the offsets of the blocks of this chain.
+    pub fn block_offsets(&self) -> impl Iterator<Item = BlockOffset> + '_ {
+        self.blocks.iter().copied()
     }
 
-    /// Returns the last entry of this PackBlockChain.
-    #[inline]
-    pub fn last_entry_mut(&mut self) -> &mut PackEntry {
-        let last = self.num_entries() - 1;
-        &mut self[last]
+    /// An iterator over the blocks of this chain and their offsets, as they
+    /// are laid out in the file.
+    pub fn blocks(&self) -> impl Iterator<Item = (BlockOffset, PackBlock)> + '_ {
+        self.blocks
+            .iter()
+            .enumerate()
+            .map(move |(block_idx, &offset)| {
+                let mut block = PackBlock::default();
+                let base = block_idx * PK2_FILE_BLOCK_ENTRY_COUNT;
+                for idx in 0..PK2_FILE_BLOCK_ENTRY_COUNT {
+                    block[idx] = self.pack_entry(base + idx).unwrap();
+                }
+                (offset, block)
+            })
     }
 
-    /// An iterator over the entries of this chain.
-    pub fn entries(&self) -> impl Iterator<Item = &PackEntry> {
-        self.blocks.iter().flat_map(|block| &block.1.entries)
+    /// An iterator over the occupied entries of this chain.
+    pub fn entries(&self) -> impl Iterator<Item = EntryRef<'_>> {
+        self.records
+            .iter()
+            .map(move |record| EntryRef::new(&self.arena, record))
     }
 
-    /// An iterator over the entries of this chain.
-    pub fn entries_mut(&mut self) -> impl Iterator<Item = &mut PackEntry> {
-        self.blocks
-            .iter_mut()
-            .flat_map(|block| &mut block.1.entries)
+    /// Get the entry at the specified offset, returning `None` if it is
+    /// empty.
+    pub fn get(&self, entry: usize) -> Option<EntryRef<'_>> {
+        let record = &self.records[self.record_position(entry).ok()?];
+        Some(EntryRef::new(&self.arena, record))
     }
 
-    /// Get the PackEntry at the specified offset.
-    pub fn get(&self, entry: usize) -> Option<&PackEntry> {
-        self.blocks
-            .get(entry / PK2_FILE_BLOCK_ENTRY_COUNT)
-            .and_then(|(_, block)| block.get(entry % PK2_FILE_BLOCK_ENTRY_COUNT))
+    /// Returns the entry at the specified offset as it is stored in the file.
+    pub fn pack_entry(&self, entry: usize) -> Option<PackEntry> {
+        if !self.contains_entry_index(entry) {
+            return None;
+        }
+        let next_block = self.next_block_of(entry);
+        Some(match self.record_position(entry) {
+            Ok(pos) => self.records[pos].to_entry(&self.arena, next_block),
+            Err(_) => PackEntry::new_empty(next_block),
+        })
     }
 
-    /// Get the PackEntry at the specified offset.
-    pub fn get_mut(&mut self, entry: usize) -> Option<&mut PackEntry> {
-        self.blocks
-            .get_mut(entry / PK2_FILE_BLOCK_ENTRY_COUNT)
-            .and_then(|(_, block)| block.get_mut(entry % PK2_FILE_BLOCK_ENTRY_COUNT))
+    /// Returns the offset of the first empty entry.
+    pub fn first_empty_entry(&self) -> Option<usize> {
+        // records are ordered by their index, so the first gap is the first
+        // empty entry
+        let idx = self
+            .records
+            .iter()
+            .enumerate()
+            .find(|&(pos, record)| record.index as usize != pos)
+            .map_or(self.records.len(), |(pos, _)| pos);
+        Some(idx).filter(|&idx| self.contains_entry_index(idx))
     }
 
+    /// Clears the entry at the specified offset, returning the old entry.
     pub fn remove(&mut self, entry: usize) -> Option<PackEntry> {
-        self.get_mut(entry).map(PackEntry::clear)
+        self.get(entry)?;
+        Some(self.update_entry(entry, PackEntry::clear))
+    }
+
+    /// Clears all entries of this chain, keeping its blocks.
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.name_index.clear();
+    }
+
+    /// Applies `f` to the entry at the specified offset, allowing it to be
+    /// modified, renamed or replaced. The `next_block` field of the entry is
+    /// managed by the chain and changes to it are ignored.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `entry` is out of bounds.
+    pub fn update_entry<R>(&mut self, entry: usize, f: impl FnOnce(&mut PackEntry) -> R) -> R {
+        let mut pack_entry = self.pack_entry(entry).expect("entry index out of bounds");
+        let ret = f(&mut pack_entry);
+        let new_record = EntryRecord::from_entry(&self.arena, entry, &pack_entry);
+        match (self.record_position(entry), new_record) {
+            (Ok(pos), Some(new_record)) => {
+                let old = mem::replace(&mut self.records[pos], new_record);
+                if old.name != new_record.name {
+                    self.unindex(&old);
+                    self.index_record(pos);
+                }
+            }
+            (Ok(pos), None) => {
+                let old = self.records.remove(pos);
+                self.unindex(&old);
+            }
+            (Err(pos), Some(new_record)) => {
+                self.records.insert(pos, new_record);
+                self.index_record(pos);
+            }
+            (Err(_), None) => (),
+        }
+        ret
+    }
+
+    /// Returns the offset of the first entry called `name`, ignoring ascii
+    /// case.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.name_index
+            .get(name)
+            .iter()
+            .map(|&idx| idx as usize)
+            .find(|&idx| {
+                self.get(idx)
+                    .is_some_and(|e| e.name_eq_ignore_ascii_case(name))
+            })
     }
 
     #[inline]
@@ -114,49 +243,133 @@ impl PackBlockChain {
         entry < self.num_entries()
     }
 
+    /// Rewrites every stream offset stored in this chain, that is the offsets
+    /// of its blocks, the children positions of directories and the data
+    /// positions of files.
+    pub fn relocate(&mut self, mut relocate: impl FnMut(u64) -> u64) {
+        for BlockOffset(offset) in &mut self.blocks {
+            *offset = relocate(*offset);
+        }
+        for record in &mut self.records {
+            record.position = relocate(record.position);
+        }
+    }
+
     /// Looks up the `directory` name in this [`PackBlockChain`], returning the
     /// offset of the ['PackBlockChain'] corresponding to the directory if
     /// successful.
     pub fn find_block_chain_index_of(&self, directory: &str) -> ChainLookupResult<ChainIndex> {
-        self.entries()
-            .find(|entry| entry.name_eq_ignore_ascii_case(directory))
+        self.position(directory)
+            .and_then(|idx| self.get(idx))
             .ok_or(ChainLookupError::NotFound)?
             .as_directory()
-            .map(DirectoryEntry::children_position)
+            .map(DirectoryRef::children_position)
             .ok_or(ChainLookupError::ExpectedDirectory)
     }
 
-    pub fn sort(&mut self, scratch: &mut Vec<PackEntry>) {
-        use std::cmp::Ordering;
-        self.entries_mut()
-            .for_each(|entry| scratch.push(std::mem::replace(entry, PackEntry::new_empty(None))));
-        scratch.sort_by(|a, b| match (a, b) {
-            (PackEntry::Empty(_), PackEntry::Empty(_)) => Ordering::Equal,
-            (PackEntry::Empty(_), _) | (PackEntry::File(_), PackEntry::Directory(_)) => {
-                Ordering::Greater
-            }
-            (_, PackEntry::Empty(_)) | (PackEntry::Directory(_), PackEntry::File(_)) => {
-                Ordering::Less
-            }
-            (PackEntry::File(a), PackEntry::File(b)) => a.name().cmp(b.name()),
-            (PackEntry::Directory(a), PackEntry::Directory(b)) => a.name().cmp(b.name()),
+    /// Sorts the entries of this chain, directories first, each ordered by
+    /// their name, moving all empty entries to the end.
+    pub fn sort(&mut self) {
+        let arena = &self.arena;
+        let name = |record: &EntryRecord| arena.name(record.name);
+        self.records.sort_by(|a, b| {
+            (a.kind != EntryKind::Directory, name(a))
+                .cmp(&(b.kind != EntryKind::Directory, name(b)))
         });
-        self.entries_mut()
-            .zip(scratch.drain(..))
-            .for_each(|(dst, src)| drop(std::mem::replace(dst, src)));
+        for (idx, record) in self.records.iter_mut().enumerate() {
+            record.index = idx as u32;
+        }
+        self.reindex();
     }
-}
 
-impl ops::Index<usize> for PackBlockChain {
-    type Output = PackEntry;
-    fn index(&self, idx: usize) -> &Self::Output {
-        &self.blocks[idx / PK2_FILE_BLOCK_ENTRY_COUNT].1[idx % PK2_FILE_BLOCK_ENTRY_COUNT]
+    /// The approximate amount of heap memory used by this chain, not
+    /// counting its [arena](PackBlockChain::arena) which is shared with the
+    /// other chains of the index.
+    pub fn memory_usage(&self) -> usize {
+        self.blocks.capacity() * mem::size_of::<BlockOffset>()
+            + self.records.capacity() * mem::size_of::<EntryRecord>()
+            + self.name_index.memory_usage()
+    }
+
+    /// The number of occupied entries in this chain.
+    #[inline]
+    pub fn num_occupied(&self) -> usize {
+        self.records.len()
+    }
+
+    #[inline]
+    fn record_position(&self, entry: usize) -> Result<usize, usize> {
+        self.records
+            .binary_search_by_key(&entry, |record| record.index as usize)
+    }
+
+    fn next_block_of(&self, entry: usize) -> Option<NonZeroU64> {
+        // only the last entry of a block links to the next one
+        if entry % PK2_FILE_BLOCK_ENTRY_COUNT == PK2_FILE_BLOCK_ENTRY_COUNT - 1 {
+            self.blocks
+                .get(entry / PK2_FILE_BLOCK_ENTRY_COUNT + 1)
+                .and_then(|&BlockOffset(offset)| NonZeroU64::new(offset))
+        } else {
+            None
+        }
+    }
+
+    fn index_record(&mut self, pos: usize) {
+        let record = self.records[pos];
+        self.name_index
+            .insert(self.arena.name(record.name), record.index as usize);
+    }
+
+    fn unindex(&mut self, record: &EntryRecord) {
+        self.name_index
+            .remove(self.arena.name(record.name), record.index as usize);
+    }
+
+    fn reindex(&mut self) {
+        self.name_index.clear();
+        for pos in 0..self.records.len() {
+            self.index_record(pos);
+        }
     }
 }
 
-impl ops::IndexMut<usize> for PackBlockChain {
-    fn index_mut(&mut self, idx: usize) -> &mut Self::Output {
-        &mut self.blocks[idx / PK2_FILE_BLOCK_ENTRY_COUNT].1[idx % PK2_FILE_BLOCK_ENTRY_COUNT]
+/// An occupied entry together with a handle to the chain it lives in. The
+/// handle keeps the chain alive even if the index evicts or modifies it, in
+/// which case the entry is the one it was resolved to.
+#[derive(Clone)]
+pub struct EntryHandle {
+    chain: Arc<PackBlockChain>,
+    index: usize,
+}
+
+impl EntryHandle {
+    /// Creates a handle to the entry at `index` of `chain`, returning `None`
+    /// if that entry is empty.
+    pub fn new(chain: Arc<PackBlockChain>, index: usize) -> Option<Self> {
+        chain.get(index)?;
+        Some(EntryHandle { chain, index })
+    }
+
+    #[inline]
+    pub fn entry(&self) -> EntryRef<'_> {
+        self.chain.get(self.index).unwrap()
+    }
+
+    #[inline]
+    pub fn chain(&self) -> &Arc<PackBlockChain> {
+        &self.chain
+    }
+
+    /// The index of the chain the entry lives in.
+    #[inline]
+    pub fn chain_index(&self) -> ChainIndex {
+        self.chain.chain_index()
+    }
+
+    /// The index of the entry in its chain.
+    #[inline]
+    pub fn index(&self) -> usize {
+        self.index
     }
 }
 
@@ -168,12 +381,12 @@ pub struct PackBlock {
 
 impl PackBlock {
     #[inline]
-    pub fn entries(&self) -> std::slice::Iter<PackEntry> {
+    pub fn entries(&self) -> std::slice::Iter<'_, PackEntry> {
         self.entries.iter()
     }
 
     #[inline]
-    pub fn entries_mut(&mut self) -> std::slice::IterMut<PackEntry> {
+    pub fn entries_mut(&mut self) -> std::slice::IterMut<'_, PackEntry> {
         self.entries.iter_mut()
     }
 
@@ -200,8 +413,7 @@ impl RawIo for PackBlock {
     fn to_writer<W: Write>(&self, mut w: W) -> IoResult<()> {
         self.entries
             .iter()
-            .map(|entry| entry.to_writer(&mut w))
-            .collect()
+            .try_for_each(|entry| entry.to_writer(&mut w))
     }
 }
 
@@ -219,3 +431,119 @@ impl ops::IndexMut<usize> for PackBlock {
         &mut self.entries[idx]
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{PackBlock, PackBlockChain};
+    use crate::raw::entry::PackEntry;
+    use crate::raw::{BlockOffset, StreamOffset};
+
+    #[test]
+    fn name_index_stays_in_sync() {
+        let mut block = PackBlock::default();
+        block[0] = PackEntry::new_file("Foo", StreamOffset(0), 0, None);
+        block[3] = PackEntry::new_file("foo", StreamOffset(0), 0, None);
+        block[5] = PackEntry::new_file("bar", StreamOffset(0), 0, None);
+        let mut chain =
+            PackBlockChain::from_blocks(Default::default(), vec![(BlockOffset(256), block)]);
+        assert_eq!(chain.position("FOO"), Some(0));
+        assert_eq!(chain.position("baz"), None);
+
+        chain.remove(0);
+        // the duplicate takes over
+        assert_eq!(chain.position("foo"), Some(3));
+        chain.update_entry(5, |entry| entry.set_name("Baz"));
+        assert_eq!(chain.position("bar"), None);
+        assert_eq!(chain.position("baz"), Some(5));
+
+        chain.push_and_link(BlockOffset(2816), PackBlock::default());
+        chain.update_entry(25, |entry| {
+            *entry = PackEntry::new_file("qux", StreamOffset(0), 0, None)
+        });
+        assert_eq!(chain.position("QUX"), Some(25));
+    }
+
+    #[test]
+    fn duplicate_names() {
+        let mut block = PackBlock::default();
+        block[0] = PackEntry::new_file("a", StreamOffset(0), 0, None);
+        block[4] = PackEntry::new_file("A", StreamOffset(0), 0, None);
+        block[9] = PackEntry::new_file("a", StreamOffset(0), 0, None);
+        block[12] = PackEntry::new_file("b", StreamOffset(0), 0, None);
+        let mut chain =
+            PackBlockChain::from_blocks(Default::default(), vec![(BlockOffset(256), block)]);
+        assert_eq!(chain.position("a"), Some(0));
+        assert_eq!(chain.position("b"), Some(12));
+        assert_eq!(chain.position("c"), None);
+
+        // removing one of the duplicates keeps the others reachable
+        chain.remove(4);
+        assert_eq!(chain.position("a"), Some(0));
+        chain.remove(0);
+        assert_eq!(chain.position("A"), Some(9));
+        chain.remove(9);
+        assert_eq!(chain.position("a"), None);
+        assert_eq!(chain.position("b"), Some(12));
+
+        // the pair is indexed again once it is recreated
+        chain.update_entry(2, |entry| {
+            *entry = PackEntry::new_file("b", StreamOffset(0), 0, None)
+        });
+        assert_eq!(chain.position("b"), Some(2));
+        chain.remove(2);
+        assert_eq!(chain.position("b"), Some(12));
+    }
+
+    #[test]
+    fn rename_into_existing_name() {
+        let mut block = PackBlock::default();
+        block[0] = PackEntry::new_file("foo", StreamOffset(0), 0, None);
+        block[1] = PackEntry::new_file("bar", StreamOffset(0), 0, None);
+        let mut chain =
+            PackBlockChain::from_blocks(Default::default(), vec![(BlockOffset(256), block)]);
+
+        chain.update_entry(1, |entry| entry.set_name("FOO"));
+        assert_eq!(chain.position("foo"), Some(0));
+        assert_eq!(chain.position("bar"), None);
+        chain.remove(0);
+        assert_eq!(chain.position("foo"), Some(1));
+
+        chain.update_entry(1, |entry| entry.set_name("bar"));
+        assert_eq!(chain.position("foo"), None);
+        assert_eq!(chain.position("bar"), Some(1));
+    }
+
+    #[test]
+    fn only_occupied_entries_are_stored() {
+        let mut block = PackBlock::default();
+        block[0] = PackEntry::new_file("foo", StreamOffset(1), 0, None);
+        block[7] = PackEntry::new_file("bar", StreamOffset(2), 0, None);
+        let mut chain = PackBlockChain::from_blocks(
+            Default::default(),
+            vec![
+                (BlockOffset(256), block),
+                (BlockOffset(2816), PackBlock::default()),
+            ],
+        );
+        assert_eq!(chain.num_occupied(), 2);
+        assert_eq!(chain.num_entries(), 40);
+        assert_eq!(chain.first_empty_entry(), Some(1));
+        assert!(chain.get(1).is_none());
+        let indices: Vec<_> = chain.entries().map(|entry| entry.index()).collect();
+        assert_eq!(indices, [0, 7]);
+
+        // the link to the next block is derived from the block list
+        let blocks: Vec<_> = chain.blocks().collect();
+        assert_eq!(
+            blocks[0].1[19].next_block().map(|next| next.get()),
+            Some(2816)
+        );
+        assert_eq!(blocks[0].1[7].name(), Some("bar"));
+        assert!(blocks[1].1[19].next_block().is_none());
+
+        chain.sort();
+        assert_eq!(chain.position("bar"), Some(0));
+        assert_eq!(chain.position("foo"), Some(1));
+        assert_eq!(chain.first_empty_entry(), Some(2));
+    }
+}
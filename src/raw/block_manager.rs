@@ -1,22 +1,104 @@
 use std::collections::{HashMap, HashSet};
 use std::io;
-use std::path::{Component, Path};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use super::block_chain::{PackBlock, PackBlockChain};
-use super::entry::{DirectoryEntry, PackEntry};
-use super::{BlockOffset, ChainIndex};
-use crate::constants::{PK2_FILE_BLOCK_ENTRY_COUNT, PK2_ROOT_BLOCK, PK2_ROOT_BLOCK_VIRTUAL};
-use crate::error::{ChainLookupError, ChainLookupResult, OpenResult};
-use crate::Blowfish;
+use super::arena::IndexArena;
+use super::block_chain::{EntryHandle, PackBlock, PackBlockChain};
+use super::entry::{DirectoryRef, EntryRef, PackEntry};
+use super::{BlockOffset, ChainIndex, NoHashHasherBuilder, StreamOffset};
+use crate::constants::{
+    PK2_FILE_BLOCK_ENTRY_COUNT, PK2_FILE_BLOCK_SIZE, PK2_ROOT_BLOCK, PK2_ROOT_BLOCK_VIRTUAL,
+};
+use crate::error::{ChainLookupError, ChainLookupResult, Error, Result};
+use crate::{IndexCipher, ReadAt};
+
+/// The stream and cipher chains that are not resident yet are read from when
+/// the [`BlockManager`] loads them lazily.
+#[derive(Copy, Clone)]
+pub struct ChainSource<'a> {
+    bf: Option<&'a dyn IndexCipher>,
+    stream: &'a dyn ReadAt,
+}
+
+impl<'a> ChainSource<'a> {
+    pub fn new<C: IndexCipher>(bf: Option<&'a C>, stream: &'a dyn ReadAt) -> Self {
+        ChainSource {
+            bf: bf.map(|bf| bf as _),
+            stream,
+        }
+    }
+}
+
+/// The amount of memory taken up by the resident part of an archive's index.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct IndexMemoryUsage {
+    /// The number of loaded chains.
+    pub chains: usize,
+    /// The number of occupied entries in the loaded chains.
+    pub entries: usize,
+    /// The number of distinct entry names, which are only stored once.
+    pub names: usize,
+    /// The approximate number of heap bytes used by the loaded chains, the
+    /// map holding them and the arena their names and timestamps are
+    /// interned in.
+    pub bytes: usize,
+}
+
+/// A part of a damaged index that was skipped when opening an archive with
+/// [`Pk2::open_salvage`](crate::archive::Pk2::open_salvage).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Skipped {
+    /// An entry of the directory with an unknown type, it is treated as empty.
+    Entry {
+        directory: PathBuf,
+        entry: usize,
+        ty: u8,
+    },
+    /// A block of the directory's chain that couldn't be read. The entries of
+    /// the blocks before it are kept, the ones after it are lost.
+    Block {
+        directory: PathBuf,
+        block: BlockOffset,
+        error: io::ErrorKind,
+    },
+    /// A directory whose chain couldn't be read at all, it is left out of
+    /// the index.
+    Directory {
+        directory: PathBuf,
+        error: io::ErrorKind,
+    },
+}
+
+struct CachedChain {
+    chain: Arc<PackBlockChain>,
+    last_access: AtomicU64,
+}
+
+struct LazyState {
+    max_resident: Option<usize>,
+    clock: AtomicU64,
+}
 
 /// Simple BlockManager backed by a hashmap.
+///
+/// All chains share one [`IndexArena`], so names and timestamps that repeat
+/// across directories are only stored once. In lazy mode chains are only read and decrypted the first time they are
+/// looked up. Lookups hand out reference counted chains, so a chain that is
+/// evicted to stay within the resident limit lives on for as long as it is
+/// still in use, it is just read again the next time it is looked up.
+#[derive(Default)]
 pub struct BlockManager {
-    chains: HashMap<ChainIndex, PackBlockChain, NoHashHasherBuilder>,
+    arena: Arc<IndexArena>,
+    chains: RwLock<ChainMap>,
+    lazy: Option<LazyState>,
 }
 
 impl BlockManager {
     /// Parses the complete index of a pk2 file
-    pub fn new<F: io::Read + io::Seek>(bf: Option<&Blowfish>, mut stream: F) -> OpenResult<Self> {
+    pub fn new<C: IndexCipher + ?Sized, F: ReadAt>(bf: Option<&C>, stream: F) -> Result<Self> {
+        let arena = Arc::new(IndexArena::new());
         let mut chains = HashMap::with_capacity_and_hasher(32, NoHashHasherBuilder);
         // used to prevent an infinite loop that can be caused by specific files
         let mut visited_block_set = HashSet::with_capacity_and_hasher(32, NoHashHasherBuilder);
@@ -26,47 +108,199 @@ impl BlockManager {
                 // skip offsets that are being pointed to multiple times
                 continue;
             }
-            let block_chain =
-                Self::read_chain_from_stream_at(&mut visited_block_set, bf, &mut stream, offset)?;
+            let block_chain = Self::read_chain_from_stream_at(
+                &arena,
+                &mut visited_block_set,
+                bf,
+                &stream,
+                offset,
+            )?;
             visited_block_set.clear();
 
             // put all folder offsets of this chain into the stack to parse them next
             offsets.extend(
                 block_chain
                     .entries()
-                    .filter_map(PackEntry::as_directory)
+                    .filter_map(EntryRef::as_directory)
+                    .filter(|d| d.is_normal_link())
+                    .map(DirectoryRef::children_position),
+            );
+            chains.insert(offset, CachedChain::new(block_chain, 0));
+        }
+        let mut this = BlockManager {
+            arena,
+            chains: RwLock::new(chains),
+            lazy: None,
+        };
+        this.insert_virtual_root();
+        Ok(this)
+    }
+
+    /// Parses the complete index of a damaged pk2 file, skipping entries and
+    /// chains that can't be read instead of failing. Only the root chain has
+    /// to be readable. Directories whose chain can't be read are removed from
+    /// their parent.
+    pub fn new_salvaged<C: IndexCipher + ?Sized, F: ReadAt>(
+        bf: Option<&C>,
+        stream: F,
+    ) -> Result<(Self, Vec<Skipped>)> {
+        let arena = Arc::new(IndexArena::new());
+        let mut chains = HashMap::with_capacity_and_hasher(32, NoHashHasherBuilder);
+        let mut skipped = Vec::new();
+        let mut visited_block_set = HashSet::with_capacity_and_hasher(32, NoHashHasherBuilder);
+        // the chain offset, its path and the entry pointing at it
+        let mut offsets = vec![(PK2_ROOT_BLOCK, PathBuf::from("/"), None)];
+        while let Some((offset, path, parent)) = offsets.pop() {
+            if chains.contains_key(&offset) {
+                continue;
+            }
+            let read = Self::read_chain_salvaged(
+                &arena,
+                &mut visited_block_set,
+                bf,
+                &stream,
+                offset,
+                &path,
+                &mut skipped,
+            );
+            visited_block_set.clear();
+            let block_chain = match (read, parent) {
+                (Ok(block_chain), _) => block_chain,
+                (Err(e), None) => return Err(e),
+                (Err(e), Some((parent, entry))) => {
+                    let parent: &mut CachedChain = chains.get_mut(&parent).unwrap();
+                    Arc::make_mut(&mut parent.chain).remove(entry);
+                    skipped.push(Skipped::Directory {
+                        directory: path,
+                        error: io::Error::from(e).kind(),
+                    });
+                    continue;
+                }
+            };
+
+            offsets.extend(
+                block_chain
+                    .entries()
+                    .filter_map(EntryRef::as_directory)
                     .filter(|d| d.is_normal_link())
-                    .map(DirectoryEntry::children_position),
+                    .map(|d| {
+                        (
+                            d.children_position(),
+                            path.join(d.name()),
+                            Some((offset, d.entry().index())),
+                        )
+                    }),
             );
-            chains.insert(offset, block_chain);
+            chains.insert(offset, CachedChain::new(block_chain, 0));
         }
-        let mut this = BlockManager { chains };
+        let mut this = BlockManager {
+            arena,
+            chains: RwLock::new(chains),
+            lazy: None,
+        };
+        this.insert_virtual_root();
+        Ok((this, skipped))
+    }
+
+    /// Creates a manager that loads chains on demand, only reading the root
+    /// chain upfront. If `max_resident` is set, the least recently accessed
+    /// chains are evicted whenever loading a chain exceeds that many.
+    pub fn new_lazy<C: IndexCipher + ?Sized, F: ReadAt>(
+        bf: Option<&C>,
+        stream: F,
+        max_resident: Option<usize>,
+    ) -> Result<Self> {
+        let arena = Arc::new(IndexArena::new());
+        let mut visited_block_set = HashSet::with_capacity_and_hasher(4, NoHashHasherBuilder);
+        let root = Self::read_chain_from_stream_at(
+            &arena,
+            &mut visited_block_set,
+            bf,
+            &stream,
+            PK2_ROOT_BLOCK,
+        )?;
+        let mut this = BlockManager {
+            arena,
+            chains: RwLock::default(),
+            lazy: Some(LazyState {
+                max_resident,
+                clock: AtomicU64::new(0),
+            }),
+        };
+        this.insert(PK2_ROOT_BLOCK, root);
         this.insert_virtual_root();
         Ok(this)
     }
 
     fn insert_virtual_root(&mut self) {
         // dummy entry to give root a proper name
-        let mut virtual_root = PackBlockChain::from_blocks(vec![(
-            PK2_ROOT_BLOCK_VIRTUAL.into(),
-            PackBlock::default(),
-        )]);
-        virtual_root[0] = PackEntry::new_directory("/", PK2_ROOT_BLOCK, None);
-        self.chains.insert(virtual_root.chain_index(), virtual_root);
+        let mut virtual_root = PackBlockChain::from_blocks(
+            Arc::clone(&self.arena),
+            vec![(PK2_ROOT_BLOCK_VIRTUAL.into(), PackBlock::default())],
+        );
+        virtual_root.update_entry(0, |entry| {
+            *entry = PackEntry::new_directory("/", PK2_ROOT_BLOCK, None)
+        });
+        self.insert(virtual_root.chain_index(), virtual_root);
     }
 
     /// Reads a [`PackBlockChain`] from the given file at the specified offset.
-    fn read_chain_from_stream_at<F: io::Read + io::Seek>(
+    fn read_chain_from_stream_at<C: IndexCipher + ?Sized, F: ReadAt + ?Sized>(
+        arena: &Arc<IndexArena>,
         visited_block_set: &mut HashSet<BlockOffset, NoHashHasherBuilder>,
-        bf: Option<&Blowfish>,
-        stream: &mut F,
+        bf: Option<&C>,
+        stream: &F,
         offset: ChainIndex,
-    ) -> OpenResult<PackBlockChain> {
+    ) -> Result<PackBlockChain> {
         let mut blocks = Vec::new();
         let mut offset = offset.into();
 
         while visited_block_set.insert(offset) {
-            let block = crate::io::read_block_at(bf, &mut *stream, offset)?;
+            let block = crate::io::read_block_at(bf, stream, offset)?;
+            let nc = block.entries().last().and_then(PackEntry::next_block);
+            blocks.push((offset, block));
+            match nc {
+                Some(nc) => offset = BlockOffset(nc.get()),
+                None => break,
+            }
+        }
+        Ok(PackBlockChain::from_blocks(Arc::clone(arena), blocks))
+    }
+
+    /// Like [`BlockManager::read_chain_from_stream_at`], but skips entries of
+    /// an unknown type and stops at the first block that can't be read. Only
+    /// fails if the first block of the chain can't be read.
+    fn read_chain_salvaged<C: IndexCipher + ?Sized, F: ReadAt + ?Sized>(
+        arena: &Arc<IndexArena>,
+        visited_block_set: &mut HashSet<BlockOffset, NoHashHasherBuilder>,
+        bf: Option<&C>,
+        stream: &F,
+        offset: ChainIndex,
+        path: &Path,
+        skipped: &mut Vec<Skipped>,
+    ) -> Result<PackBlockChain> {
+        let mut blocks = Vec::new();
+        let mut offset = offset.into();
+        let mut invalid = Vec::new();
+        while visited_block_set.insert(offset) {
+            let block = match crate::io::read_block_at_lenient(bf, stream, offset, &mut invalid) {
+                Ok(block) => block,
+                Err(e) if blocks.is_empty() => return Err(e),
+                Err(e) => {
+                    skipped.push(Skipped::Block {
+                        directory: path.to_owned(),
+                        block: offset,
+                        error: io::Error::from(e).kind(),
+                    });
+                    break;
+                }
+            };
+            let base = blocks.len() * PK2_FILE_BLOCK_ENTRY_COUNT;
+            skipped.extend(invalid.drain(..).map(|(idx, ty)| Skipped::Entry {
+                directory: path.to_owned(),
+                entry: base + idx,
+                ty,
+            }));
             let nc = block.entries().last().and_then(PackEntry::next_block);
             blocks.push((offset, block));
             match nc {
@@ -74,35 +308,268 @@ impl BlockManager {
                 None => break,
             }
         }
-        Ok(PackBlockChain::from_blocks(blocks))
+        Ok(PackBlockChain::from_blocks(Arc::clone(arena), blocks))
     }
 
+    /// Whether chains are loaded on demand.
     #[inline]
-    pub fn get(&self, chain: ChainIndex) -> Option<&PackBlockChain> {
-        self.chains.get(&chain)
+    pub fn is_lazy(&self) -> bool {
+        self.lazy.is_some()
+    }
+
+    /// The resident limit of a lazily loaded index.
+    pub fn max_resident(&self) -> Option<usize> {
+        self.lazy.as_ref().and_then(|lazy| lazy.max_resident)
+    }
+
+    /// The number of chains currently loaded.
+    pub fn resident_chains(&self) -> usize {
+        // don't count the virtual root
+        self.read_chains().len() - 1
+    }
+
+    /// Reports how much memory the loaded chains occupy.
+    pub fn memory_usage(&self) -> IndexMemoryUsage {
+        let chains = self.read_chains();
+        let mut usage = IndexMemoryUsage {
+            names: self.arena.num_names(),
+            bytes: chains.capacity() * (std::mem::size_of::<(ChainIndex, CachedChain)>() + 1)
+                + std::mem::size_of::<IndexArena>()
+                + self.arena.memory_usage(),
+            ..IndexMemoryUsage::default()
+        };
+        for cached in chains.values() {
+            usage.chains += 1;
+            usage.entries += cached.chain.num_occupied();
+            usage.bytes += std::mem::size_of::<PackBlockChain>() + cached.chain.memory_usage();
+        }
+        usage
     }
 
+    /// An iterator over all loaded chains of the archive.
+    pub fn chains(&mut self) -> impl Iterator<Item = &PackBlockChain> {
+        self.chains_mut()
+            .iter()
+            .filter(|&(&index, _)| index != PK2_ROOT_BLOCK_VIRTUAL)
+            .map(|(_, cached)| &*cached.chain)
+    }
+
+    /// An iterator over the `(offset, len)` regions of the stream that are
+    /// occupied by the blocks and file data of all loaded chains.
+    pub fn used_regions(&mut self) -> impl Iterator<Item = (StreamOffset, u64)> + '_ {
+        self.chains().flat_map(|chain| {
+            let blocks = chain
+                .block_offsets()
+                .map(|BlockOffset(offset)| (StreamOffset(offset), PK2_FILE_BLOCK_SIZE as u64));
+            let files = chain
+                .entries()
+                .filter_map(EntryRef::as_file)
+                .map(|file| (file.pos_data(), file.size() as u64));
+            blocks.chain(files)
+        })
+    }
+
+    /// Returns the chain if it is loaded.
+    #[inline]
+    pub fn get(&self, chain: ChainIndex) -> Option<Arc<PackBlockChain>> {
+        let chains = self.read_chains();
+        let cached = chains.get(&chain)?;
+        self.touch(cached);
+        Some(Arc::clone(&cached.chain))
+    }
+
+    /// Returns the chain mutably if it is loaded. If the chain is still in
+    /// use by a handle returned from [`BlockManager::get`] or
+    /// [`BlockManager::load`], it is copied first and the handle keeps the
+    /// old state.
     #[inline]
     pub fn get_mut(&mut self, chain: ChainIndex) -> Option<&mut PackBlockChain> {
         assert_ne!(chain, PK2_ROOT_BLOCK_VIRTUAL);
-        self.chains.get_mut(&chain)
+        self.chains_mut()
+            .get_mut(&chain)
+            .map(|cached| Arc::make_mut(&mut cached.chain))
+    }
+
+    /// Returns the chain, reading it from `src` first if it isn't loaded yet
+    /// and the manager is lazy. Loading a chain evicts the least recently
+    /// accessed ones if this exceeds the resident limit.
+    pub fn load(
+        &self,
+        src: ChainSource<'_>,
+        chain: ChainIndex,
+    ) -> ChainLookupResult<Arc<PackBlockChain>> {
+        self.load_impl(src, chain, true)
+    }
+
+    fn load_impl(
+        &self,
+        src: ChainSource<'_>,
+        chain: ChainIndex,
+        evict: bool,
+    ) -> ChainLookupResult<Arc<PackBlockChain>> {
+        if let Some(loaded) = self.get(chain) {
+            return Ok(loaded);
+        }
+        let lazy = self
+            .lazy
+            .as_ref()
+            .ok_or(ChainLookupError::InvalidChainIndex)?;
+        let mut visited_block_set = HashSet::with_capacity_and_hasher(4, NoHashHasherBuilder);
+        let block_chain = Self::read_chain_from_stream_at(
+            &self.arena,
+            &mut visited_block_set,
+            src.bf,
+            src.stream,
+            chain,
+        )
+        .map_err(|e| match e {
+            Error::Io { source, .. } => ChainLookupError::Io(source.kind()),
+            _ => ChainLookupError::InvalidChainIndex,
+        })?;
+        let stamp = lazy.clock.fetch_add(1, Ordering::Relaxed);
+        let mut chains = self.write_chains();
+        // another thread might have loaded the chain in the meantime, keep
+        // that one so both share the same chain
+        let loaded = Arc::clone(
+            &chains
+                .entry(chain)
+                .or_insert_with(|| CachedChain::new(block_chain, stamp))
+                .chain,
+        );
+        if evict {
+            Self::evict(&mut chains, lazy.max_resident, chain);
+        }
+        Ok(loaded)
+    }
+
+    /// Like [`BlockManager::load`] but returns a mutable reference.
+    pub fn load_mut(
+        &mut self,
+        src: ChainSource<'_>,
+        chain: ChainIndex,
+    ) -> ChainLookupResult<&mut PackBlockChain> {
+        drop(self.load(src, chain)?);
+        self.get_mut(chain)
+            .ok_or(ChainLookupError::InvalidChainIndex)
+    }
+
+    /// Loads every chain of the archive that isn't loaded yet. This ignores
+    /// the resident limit until the next call to [`BlockManager::trim`] or
+    /// [`BlockManager::load`].
+    pub fn load_all(&self, src: ChainSource<'_>) -> ChainLookupResult<()> {
+        self.collect_sub_chains_impl(src, PK2_ROOT_BLOCK, false)
+            .map(drop)
+    }
+
+    /// Evicts the least recently accessed chains until no more than the
+    /// configured maximum are resident. The root chain is never evicted. Any
+    /// changes to evicted chains have already been written to the stream, so
+    /// they are simply read again when needed.
+    pub fn trim(&mut self) {
+        let max_resident = self.max_resident();
+        Self::evict(self.chains_mut(), max_resident, PK2_ROOT_BLOCK);
+    }
+
+    /// Evicts the least recently accessed chains other than the root and
+    /// `keep` until at most `max_resident` are left.
+    fn evict(chains: &mut ChainMap, max_resident: Option<usize>, keep: ChainIndex) {
+        let Some(max_resident) = max_resident else {
+            return;
+        };
+        // the virtual root is not counted
+        let resident = chains.len() - 1;
+        if resident <= max_resident {
+            return;
+        }
+        let mut candidates = chains
+            .iter()
+            .filter(|&(&index, _)| {
+                index != PK2_ROOT_BLOCK && index != PK2_ROOT_BLOCK_VIRTUAL && index != keep
+            })
+            .map(|(&index, cached)| (cached.last_access.load(Ordering::Relaxed), index))
+            .collect::<Vec<_>>();
+        candidates.sort_unstable();
+        for (_, index) in candidates.into_iter().take(resident - max_resident) {
+            chains.remove(&index);
+        }
     }
 
     #[inline]
     pub fn insert(&mut self, chain: ChainIndex, block: PackBlockChain) {
-        self.chains.insert(chain, block);
+        let stamp = self.stamp();
+        self.chains_mut()
+            .insert(chain, CachedChain::new(block, stamp));
+    }
+
+    #[inline]
+    pub fn remove(&mut self, chain: ChainIndex) -> Option<PackBlockChain> {
+        assert_ne!(chain, PK2_ROOT_BLOCK_VIRTUAL);
+        self.chains_mut()
+            .remove(&chain)
+            .map(|cached| Arc::unwrap_or_clone(cached.chain))
+    }
+
+    /// Collects the chain indices of `chain` and every directory chain
+    /// reachable from it, in the order they were visited, loading them if
+    /// necessary.
+    pub fn collect_sub_chains(
+        &self,
+        src: ChainSource<'_>,
+        chain: ChainIndex,
+    ) -> ChainLookupResult<Vec<ChainIndex>> {
+        self.collect_sub_chains_impl(src, chain, true)
+    }
+
+    fn collect_sub_chains_impl(
+        &self,
+        src: ChainSource<'_>,
+        chain: ChainIndex,
+        evict: bool,
+    ) -> ChainLookupResult<Vec<ChainIndex>> {
+        let mut visited = HashSet::with_capacity_and_hasher(8, NoHashHasherBuilder);
+        let mut stack = vec![chain];
+        let mut chains = Vec::new();
+        while let Some(index) = stack.pop() {
+            if !visited.insert(index) {
+                continue;
+            }
+            let chain = self.load_impl(src, index, evict)?;
+            stack.extend(
+                chain
+                    .entries()
+                    .filter_map(EntryRef::as_directory)
+                    .filter(|d| d.is_normal_link())
+                    .map(DirectoryRef::children_position),
+            );
+            chains.push(index);
+        }
+        Ok(chains)
+    }
+
+    /// Rewrites every stream offset stored in the loaded chains, see
+    /// [`PackBlockChain::relocate`].
+    pub fn relocate(&mut self, mut relocate: impl FnMut(u64) -> u64) {
+        let chains = std::mem::take(self.chains_mut());
+        for (_, mut cached) in chains {
+            Arc::make_mut(&mut cached.chain).relocate(&mut relocate);
+            self.chains_mut().insert(cached.chain.chain_index(), cached);
+        }
     }
 
     pub fn resolve_path_to_parent<'path>(
         &self,
+        src: ChainSource<'_>,
         current_chain: ChainIndex,
         path: &'path Path,
     ) -> ChainLookupResult<(ChainIndex, &'path str)> {
         let mut components = path.components();
 
         if let Some(c) = components.next_back() {
-            let parent_index =
-                self.resolve_path_to_block_chain_index_at(current_chain, components.as_path())?;
+            let parent_index = self.resolve_path_to_block_chain_index_at(
+                src,
+                current_chain,
+                components.as_path(),
+            )?;
             let name = c
                 .as_os_str()
                 .to_str()
@@ -113,42 +580,19 @@ impl BlockManager {
         }
     }
 
-    /// Resolves a path from the specified chain to a parent chain and the entry
-    /// Returns Ok(None) if the path is empty, otherwise (blockchain,
-    /// entry_index, entry)
+    /// Resolves a path from the specified chain to the entry it points to and
+    /// the chain containing it.
     pub fn resolve_path_to_entry_and_parent(
         &self,
+        src: ChainSource<'_>,
         current_chain: ChainIndex,
         path: &Path,
-    ) -> ChainLookupResult<(ChainIndex, usize, &PackEntry)> {
-        self.resolve_path_to_parent(current_chain, path)
+    ) -> ChainLookupResult<EntryHandle> {
+        self.resolve_path_to_parent(src, current_chain, path)
             .and_then(|(parent_index, name)| {
-                self.chains
-                    .get(&parent_index)
-                    .ok_or(ChainLookupError::InvalidChainIndex)?
-                    .entries()
-                    .enumerate()
-                    .find(|(_, entry)| entry.name_eq_ignore_ascii_case(name))
-                    .ok_or(ChainLookupError::NotFound)
-                    .map(|(idx, entry)| (parent_index, idx, entry))
-            })
-    }
-
-    pub fn resolve_path_to_entry_and_parent_mut(
-        &mut self,
-        current_chain: ChainIndex,
-        path: &Path,
-    ) -> ChainLookupResult<(ChainIndex, usize, &mut PackEntry)> {
-        self.resolve_path_to_parent(current_chain, path)
-            .and_then(move |(parent_index, name)| {
-                self.chains
-                    .get_mut(&parent_index)
-                    .ok_or(ChainLookupError::InvalidChainIndex)?
-                    .entries_mut()
-                    .enumerate()
-                    .find(|(_, entry)| entry.name_eq_ignore_ascii_case(name))
-                    .ok_or(ChainLookupError::NotFound)
-                    .map(|(idx, entry)| (parent_index, idx, entry))
+                let chain = self.load(src, parent_index)?;
+                let idx = chain.position(name).ok_or(ChainLookupError::NotFound)?;
+                EntryHandle::new(chain, idx).ok_or(ChainLookupError::NotFound)
             })
     }
 
@@ -156,6 +600,7 @@ impl BlockManager {
     /// blockchain returning the index of the last blockchain.
     pub fn resolve_path_to_block_chain_index_at(
         &self,
+        src: ChainSource<'_>,
         current_chain: ChainIndex,
         path: &Path,
     ) -> ChainLookupResult<ChainIndex> {
@@ -164,10 +609,7 @@ impl BlockManager {
                 .as_os_str()
                 .to_str()
                 .ok_or(ChainLookupError::InvalidPath)?;
-            self.chains
-                .get(&idx)
-                .ok_or(ChainLookupError::InvalidChainIndex)?
-                .find_block_chain_index_of(comp)
+            self.load(src, idx)?.find_block_chain_index_of(comp)
         })
     }
 
@@ -177,6 +619,7 @@ impl BlockManager {
     /// A return value of Ok(None) means the entire path has been searched
     pub fn validate_dir_path_until<'p>(
         &self,
+        src: ChainSource<'_>,
         mut chain: ChainIndex,
         path: &'p Path,
     ) -> ChainLookupResult<Option<(ChainIndex, std::iter::Peekable<std::path::Components<'p>>)>>
@@ -187,12 +630,7 @@ impl BlockManager {
                 .as_os_str()
                 .to_str()
                 .ok_or(ChainLookupError::InvalidPath)?;
-            match self
-                .chains
-                .get(&chain)
-                .ok_or(ChainLookupError::InvalidChainIndex)?
-                .find_block_chain_index_of(name)
-            {
+            match self.load(src, chain)?.find_block_chain_index_of(name) {
                 Ok(i) => chain = i,
                 // lies outside of the archive
                 Err(ChainLookupError::NotFound) if component == &Component::ParentDir => {
@@ -209,7 +647,7 @@ impl BlockManager {
                         Err(ChainLookupError::ExpectedDirectory)
                     };
                 }
-                Err(_) => unreachable!(),
+                Err(e) => return Err(e),
             }
             let _ = components.next();
         }
@@ -221,38 +659,52 @@ impl BlockManager {
     }
 
     pub fn sort(&mut self) {
-        let scratch = &mut Vec::with_capacity(4 * PK2_FILE_BLOCK_ENTRY_COUNT);
-        for chain in self.chains.values_mut() {
-            chain.sort(scratch);
-            scratch.clear();
+        for cached in self.chains_mut().values_mut() {
+            Arc::make_mut(&mut cached.chain).sort();
         }
     }
-}
 
-#[derive(Default)]
-struct NoHashHasherBuilder;
-impl std::hash::BuildHasher for NoHashHasherBuilder {
-    type Hasher = NoHashHasher;
-    #[inline]
-    fn build_hasher(&self) -> Self::Hasher {
-        NoHashHasher(0)
+    fn read_chains(&self) -> RwLockReadGuard<'_, ChainMap> {
+        self.chains.read().unwrap_or_else(PoisonError::into_inner)
     }
-}
 
-struct NoHashHasher(u64);
-impl std::hash::Hasher for NoHashHasher {
-    #[inline]
-    fn finish(&self) -> u64 {
-        self.0
+    fn write_chains(&self) -> RwLockWriteGuard<'_, ChainMap> {
+        self.chains.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn chains_mut(&mut self) -> &mut ChainMap {
+        self.chains
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
     }
 
     #[inline]
-    fn write(&mut self, _: &[u8]) {
-        panic!("ChainIndex has been hashed wrong. This is a bug!");
+    fn stamp(&self) -> u64 {
+        self.lazy
+            .as_ref()
+            .map_or(0, |lazy| lazy.clock.fetch_add(1, Ordering::Relaxed))
     }
 
     #[inline]
-    fn write_u64(&mut self, chain: u64) {
-        self.0 = chain;
+    fn touch(&self, cached: &CachedChain) {
+        if let Some(LazyState {
+            max_resident: Some(_),
+            clock,
+        }) = &self.lazy
+        {
+            let stamp = clock.fetch_add(1, Ordering::Relaxed);
+            cached.last_access.store(stamp, Ordering::Relaxed);
+        }
     }
 }
+
+impl CachedChain {
+    fn new(chain: PackBlockChain, stamp: u64) -> Self {
+        CachedChain {
+            chain: Arc::new(chain),
+            last_access: AtomicU64::new(stamp),
+        }
+    }
+}
+
+type ChainMap = HashMap<ChainIndex, CachedChain, NoHashHasherBuilder>;
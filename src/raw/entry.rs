@@ -5,6 +5,7 @@ use std::mem;
 use std::num::NonZeroU64;
 use std::time::SystemTime;
 
+use super::arena::IndexArena;
 use super::{BlockOffset, ChainIndex, StreamOffset};
 use crate::constants::{PK2_CURRENT_DIR_IDENT, PK2_FILE_ENTRY_SIZE, PK2_PARENT_DIR_IDENT};
 use crate::io::RawIo;
@@ -83,6 +84,11 @@ impl DirectoryEntry {
         self.pos_children
     }
 
+    #[inline]
+    pub(crate) fn set_children_position(&mut self, pos_children: ChainIndex) {
+        self.pos_children = pos_children;
+    }
+
     #[inline]
     pub fn next_block(&self) -> Option<NonZeroU64> {
         self.next_block
@@ -102,6 +108,17 @@ impl DirectoryEntry {
     pub fn is_normal_link(&self) -> bool {
         !(self.is_current_link() || self.is_parent_link())
     }
+
+    /// Returns the children position if this entry is neither a `.` nor a
+    /// `..` link.
+    #[inline]
+    pub fn normal_link_children_position(&self) -> Option<ChainIndex> {
+        if self.is_normal_link() {
+            Some(self.pos_children)
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -185,7 +202,7 @@ impl FileEntry {
     }
 }
 
-/// An entry of a [`PackBlock`].
+/// An entry of a [`PackBlock`](crate::raw::block_chain::PackBlock).
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum PackEntry {
     Empty(EmptyEntry),
@@ -286,6 +303,15 @@ impl PackEntry {
         }
     }
 
+    /// Renames this entry, does nothing if the entry is empty.
+    pub fn set_name(&mut self, new_name: impl Into<Box<str>>) {
+        match self {
+            PackEntry::Empty(_) => (),
+            PackEntry::Directory(DirectoryEntry { name, .. })
+            | PackEntry::File(FileEntry { name, .. }) => *name = new_name.into(),
+        }
+    }
+
     pub fn name_eq_ignore_ascii_case(&self, other: &str) -> bool {
         self.name()
             .map(|this| this.eq_ignore_ascii_case(other))
@@ -308,6 +334,269 @@ impl PackEntry {
     }
 }
 
+/// The kind of an occupied entry.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub(crate) enum EntryKind {
+    Directory,
+    File,
+}
+
+/// The packed in-memory representation of an occupied entry of a
+/// [`PackBlockChain`](super::block_chain::PackBlockChain). The name and the
+/// timestamps are ids into the chain's [`IndexArena`] and `next_block` is
+/// derived from the chain.
+#[derive(Copy, Clone, Debug)]
+#[repr(C, packed)]
+pub(crate) struct EntryRecord {
+    // pos_children for directories, pos_data for files
+    pub(crate) position: u64,
+    pub(crate) size: u32,
+    // index of the entry in its chain
+    pub(crate) index: u32,
+    pub(crate) name: u32,
+    pub(crate) times: u32,
+    pub(crate) kind: EntryKind,
+}
+
+// records are kept for every occupied entry, so they should stay this small
+const _: () = assert!(mem::size_of::<EntryRecord>() == 25);
+
+impl EntryRecord {
+    /// Creates the record for the given entry, interning its name and times
+    /// in `arena`. Returns `None` for empty entries.
+    pub(crate) fn from_entry(arena: &IndexArena, index: usize, entry: &PackEntry) -> Option<Self> {
+        let (kind, name, times, position, size) = match entry {
+            PackEntry::Empty(_) => return None,
+            PackEntry::Directory(dir) => (
+                EntryKind::Directory,
+                &dir.name,
+                [dir.access_time, dir.create_time, dir.modify_time],
+                dir.pos_children.0,
+                0,
+            ),
+            PackEntry::File(file) => (
+                EntryKind::File,
+                &file.name,
+                [file.access_time, file.create_time, file.modify_time],
+                file.pos_data.0,
+                file.size,
+            ),
+        };
+        Some(EntryRecord {
+            position,
+            size,
+            index: index as u32,
+            name: arena.intern_name(name),
+            times: arena.intern_times(times),
+            kind,
+        })
+    }
+
+    pub(crate) fn to_entry(self, arena: &IndexArena, next_block: Option<NonZeroU64>) -> PackEntry {
+        let name = arena.name(self.name).into();
+        let [access_time, create_time, modify_time] = arena.times(self.times);
+        match self.kind {
+            EntryKind::Directory => PackEntry::Directory(DirectoryEntry {
+                name,
+                access_time,
+                create_time,
+                modify_time,
+                pos_children: ChainIndex(self.position),
+                next_block,
+            }),
+            EntryKind::File => PackEntry::File(FileEntry {
+                name,
+                access_time,
+                create_time,
+                modify_time,
+                pos_data: StreamOffset(self.position),
+                size: self.size,
+                next_block,
+            }),
+        }
+    }
+}
+
+/// A borrowed view of an occupied entry of a
+/// [`PackBlockChain`](super::block_chain::PackBlockChain).
+#[derive(Copy, Clone)]
+pub struct EntryRef<'a> {
+    arena: &'a IndexArena,
+    record: &'a EntryRecord,
+}
+
+impl<'a> EntryRef<'a> {
+    #[inline]
+    pub(crate) fn new(arena: &'a IndexArena, record: &'a EntryRecord) -> Self {
+        EntryRef { arena, record }
+    }
+
+    /// The index of this entry in its chain.
+    #[inline]
+    pub fn index(self) -> usize {
+        self.record.index as usize
+    }
+
+    #[inline]
+    pub fn name(self) -> &'a str {
+        self.arena.name(self.record.name)
+    }
+
+    pub fn name_eq_ignore_ascii_case(self, other: &str) -> bool {
+        self.name().eq_ignore_ascii_case(other)
+    }
+
+    /// The raw access, create and modify times of this entry.
+    #[inline]
+    pub(crate) fn filetimes(self) -> [FILETIME; 3] {
+        self.arena.times(self.record.times)
+    }
+
+    pub fn access_time(self) -> Option<SystemTime> {
+        self.filetimes()[0].into_systime()
+    }
+
+    pub fn create_time(self) -> Option<SystemTime> {
+        self.filetimes()[1].into_systime()
+    }
+
+    pub fn modify_time(self) -> Option<SystemTime> {
+        self.filetimes()[2].into_systime()
+    }
+
+    #[inline]
+    pub fn is_file(self) -> bool {
+        self.record.kind == EntryKind::File
+    }
+
+    #[inline]
+    pub fn is_dir(self) -> bool {
+        self.record.kind == EntryKind::Directory
+    }
+
+    #[inline]
+    pub fn as_directory(self) -> Option<DirectoryRef<'a>> {
+        if self.is_dir() {
+            Some(DirectoryRef(self))
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn as_file(self) -> Option<FileRef<'a>> {
+        if self.is_file() {
+            Some(FileRef(self))
+        } else {
+            None
+        }
+    }
+
+    /// Copies this entry out of its chain. The `next_block` field of the
+    /// returned entry is not set.
+    pub fn to_pack_entry(self) -> PackEntry {
+        self.record.to_entry(self.arena, None)
+    }
+}
+
+/// A borrowed view of a directory entry, see [`EntryRef`].
+#[derive(Copy, Clone)]
+pub struct DirectoryRef<'a>(EntryRef<'a>);
+
+impl<'a> DirectoryRef<'a> {
+    #[inline]
+    pub fn entry(self) -> EntryRef<'a> {
+        self.0
+    }
+
+    #[inline]
+    pub fn name(self) -> &'a str {
+        self.0.name()
+    }
+
+    pub fn access_time(self) -> Option<SystemTime> {
+        self.0.access_time()
+    }
+
+    pub fn create_time(self) -> Option<SystemTime> {
+        self.0.create_time()
+    }
+
+    pub fn modify_time(self) -> Option<SystemTime> {
+        self.0.modify_time()
+    }
+
+    #[inline]
+    pub fn children_position(self) -> ChainIndex {
+        ChainIndex(self.0.record.position)
+    }
+
+    #[inline]
+    pub fn is_current_link(self) -> bool {
+        self.name() == PK2_CURRENT_DIR_IDENT
+    }
+
+    #[inline]
+    pub fn is_parent_link(self) -> bool {
+        self.name() == PK2_PARENT_DIR_IDENT
+    }
+
+    #[inline]
+    pub fn is_normal_link(self) -> bool {
+        !(self.is_current_link() || self.is_parent_link())
+    }
+
+    /// Returns the children position if this entry is neither a `.` nor a
+    /// `..` link.
+    #[inline]
+    pub fn normal_link_children_position(self) -> Option<ChainIndex> {
+        if self.is_normal_link() {
+            Some(self.children_position())
+        } else {
+            None
+        }
+    }
+}
+
+/// A borrowed view of a file entry, see [`EntryRef`].
+#[derive(Copy, Clone)]
+pub struct FileRef<'a>(EntryRef<'a>);
+
+impl<'a> FileRef<'a> {
+    #[inline]
+    pub fn entry(self) -> EntryRef<'a> {
+        self.0
+    }
+
+    #[inline]
+    pub fn name(self) -> &'a str {
+        self.0.name()
+    }
+
+    pub fn access_time(self) -> Option<SystemTime> {
+        self.0.access_time()
+    }
+
+    pub fn create_time(self) -> Option<SystemTime> {
+        self.0.create_time()
+    }
+
+    pub fn modify_time(self) -> Option<SystemTime> {
+        self.0.modify_time()
+    }
+
+    #[inline]
+    pub fn pos_data(self) -> StreamOffset {
+        StreamOffset(self.0.record.position)
+    }
+
+    #[inline]
+    pub fn size(self) -> u32 {
+        self.0.record.size
+    }
+}
+
 impl RawIo for PackEntry {
     /// Reads an entry from the given Read instance always reading exactly
     /// PK2_FILE_ENTRY_SIZE bytes.
@@ -328,10 +617,7 @@ impl RawIo for PackEntry {
                 let name = {
                     let mut buf = [0; 81];
                     r.read_exact(&mut buf)?;
-                    let end = buf
-                        .iter()
-                        .position(|b| *b == 0)
-                        .unwrap_or_else(|| buf.len());
+                    let end = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
                     #[cfg(feature = "euc-kr")]
                     let name = encoding_rs::EUC_KR
                         .decode_without_bom_handling(&buf[..end])
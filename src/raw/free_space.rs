@@ -0,0 +1,179 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::StreamOffset;
+
+/// Keeps track of the regions in the stream that are not referenced by any
+/// entry or block, allowing them to be reused for new data.
+#[derive(Debug, Default)]
+pub struct FreeSpaceMap {
+    // offset -> len
+    by_offset: BTreeMap<u64, u64>,
+    // (len, offset), used for best-fit lookups
+    by_size: BTreeSet<(u64, u64)>,
+}
+
+impl FreeSpaceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the map of free regions of a stream of length `stream_len` from
+    /// the given used `(offset, len)` ranges. The ranges may overlap and don't
+    /// have to be sorted.
+    pub fn from_used_ranges<I>(stream_len: u64, used: I) -> Self
+    where
+        I: IntoIterator<Item = (StreamOffset, u64)>,
+    {
+        let mut used = used
+            .into_iter()
+            .filter(|&(_, len)| len != 0)
+            .map(|(StreamOffset(offset), len)| (offset, offset.saturating_add(len)))
+            .collect::<Vec<_>>();
+        used.sort_unstable();
+
+        let mut this = Self::new();
+        let mut pos = 0;
+        for (start, end) in used {
+            if start > pos {
+                this.insert(pos, start.min(stream_len) - pos);
+            }
+            pos = pos.max(end);
+            if pos >= stream_len {
+                break;
+            }
+        }
+        if pos < stream_len {
+            this.insert(pos, stream_len - pos);
+        }
+        this
+    }
+
+    /// Allocates a region of `len` bytes from the smallest free region that
+    /// is large enough, returning its offset.
+    pub fn allocate(&mut self, len: u64) -> Option<StreamOffset> {
+        if len == 0 {
+            return None;
+        }
+        let &(free_len, offset) = self.by_size.range((len, 0)..).next()?;
+        self.remove(offset, free_len);
+        if free_len > len {
+            self.insert(offset + len, free_len - len);
+        }
+        Some(StreamOffset(offset))
+    }
+
+    /// Marks the given region as free, merging it with adjacent free regions.
+    /// Parts of the region that already are free are left as is, as entries
+    /// of some archives share or overlap their data.
+    pub fn release(&mut self, StreamOffset(offset): StreamOffset, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let mut start = offset;
+        let mut end = offset.saturating_add(len);
+        if let Some((&prev_offset, &prev_len)) = self.by_offset.range(..offset).next_back() {
+            if prev_offset + prev_len >= offset {
+                start = prev_offset;
+            }
+        }
+        // every free region touching or overlapping the released one
+        let merged = self
+            .by_offset
+            .range(start..=end)
+            .map(|(&offset, &len)| (offset, len))
+            .collect::<Vec<_>>();
+        for (offset, len) in merged {
+            self.remove(offset, len);
+            end = end.max(offset + len);
+        }
+        self.insert(start, end - start);
+    }
+
+    /// The total amount of free bytes.
+    pub fn free_bytes(&self) -> u64 {
+        self.by_offset.values().sum()
+    }
+
+    /// An iterator over all free regions as `(offset, len)` pairs ordered by
+    /// their offset.
+    pub fn regions(&self) -> impl Iterator<Item = (StreamOffset, u64)> + '_ {
+        self.by_offset
+            .iter()
+            .map(|(&offset, &len)| (StreamOffset(offset), len))
+    }
+
+    fn insert(&mut self, offset: u64, len: u64) {
+        self.by_offset.insert(offset, len);
+        self.by_size.insert((len, offset));
+    }
+
+    fn remove(&mut self, offset: u64, len: u64) {
+        self.by_offset.remove(&offset);
+        self.by_size.remove(&(len, offset));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FreeSpaceMap;
+    use crate::raw::StreamOffset;
+
+    #[test]
+    fn from_used_ranges() {
+        let map = FreeSpaceMap::from_used_ranges(
+            100,
+            vec![
+                (StreamOffset(50), 10),
+                (StreamOffset(0), 20),
+                (StreamOffset(10), 20),
+                (StreamOffset(90), 20),
+            ],
+        );
+        assert_eq!(
+            map.regions().collect::<Vec<_>>(),
+            vec![(StreamOffset(30), 20), (StreamOffset(60), 30)]
+        );
+    }
+
+    #[test]
+    fn allocate_best_fit() {
+        let mut map = FreeSpaceMap::new();
+        map.release(StreamOffset(0), 100);
+        map.release(StreamOffset(200), 10);
+        map.release(StreamOffset(300), 20);
+        assert_eq!(map.allocate(15), Some(StreamOffset(300)));
+        assert_eq!(map.allocate(10), Some(StreamOffset(200)));
+        assert_eq!(map.allocate(101), None);
+        assert_eq!(map.allocate(5), Some(StreamOffset(315)));
+        assert_eq!(map.free_bytes(), 100);
+    }
+
+    #[test]
+    fn release_coalesces() {
+        let mut map = FreeSpaceMap::new();
+        map.release(StreamOffset(0), 10);
+        map.release(StreamOffset(20), 10);
+        map.release(StreamOffset(10), 10);
+        assert_eq!(
+            map.regions().collect::<Vec<_>>(),
+            vec![(StreamOffset(0), 30)]
+        );
+        assert_eq!(map.allocate(30), Some(StreamOffset(0)));
+        assert_eq!(map.regions().count(), 0);
+    }
+
+    #[test]
+    fn release_overlapping() {
+        let mut map = FreeSpaceMap::new();
+        map.release(StreamOffset(10), 10);
+        map.release(StreamOffset(10), 10);
+        map.release(StreamOffset(15), 10);
+        map.release(StreamOffset(40), 5);
+        map.release(StreamOffset(5), 50);
+        assert_eq!(
+            map.regions().collect::<Vec<_>>(),
+            vec![(StreamOffset(5), 50)]
+        );
+        assert_eq!(map.free_bytes(), 50);
+    }
+}
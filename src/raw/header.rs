@@ -4,9 +4,9 @@ use std::fmt;
 use std::io::{Read, Result as IoResult, Write};
 
 use crate::constants::*;
-use crate::error::{OpenError, OpenResult};
+use crate::error::{Error, Result};
 use crate::io::RawIo;
-use crate::Blowfish;
+use crate::IndexCipher;
 
 pub struct PackHeader {
     pub signature: [u8; 30],
@@ -29,11 +29,12 @@ impl Default for PackHeader {
 }
 
 impl PackHeader {
-    pub fn new_encrypted(bf: &Blowfish) -> Self {
-        let mut this = Self::default();
-        let _ = bf.encrypt(&mut this.verify);
-        this.encrypted = true;
-        this
+    pub fn new_encrypted<C: IndexCipher + ?Sized>(cipher: &C) -> Self {
+        PackHeader {
+            encrypted: true,
+            verify: cipher.checksum(),
+            ..Self::default()
+        }
     }
 
     pub fn new() -> Self {
@@ -42,11 +43,16 @@ impl PackHeader {
 
     /// Validate the signature of this header. Returns an error if the version
     /// or signature does not match.
-    pub fn validate_sig(&self) -> OpenResult<()> {
+    pub fn validate_sig(&self) -> Result<()> {
         if &self.signature != PK2_SIGNATURE {
-            Err(OpenError::CorruptedFile)
+            Err(Error::Corrupted {
+                offset: 0,
+                reason: "invalid signature",
+            })
         } else if self.version != PK2_VERSION {
-            Err(OpenError::UnsupportedVersion)
+            Err(Error::UnsupportedVersion {
+                version: self.version,
+            })
         } else {
             Ok(())
         }
@@ -54,9 +60,9 @@ impl PackHeader {
 
     /// Verifies the calculated checksum against this header returning an error
     /// if it doesn't match.
-    pub fn verify(&self, checksum: [u8; 16]) -> OpenResult<()> {
+    pub fn verify(&self, checksum: [u8; 16]) -> Result<()> {
         if checksum[..PK2_CHECKSUM_STORED] != self.verify[..PK2_CHECKSUM_STORED] {
-            Err(OpenError::InvalidKey)
+            Err(Error::Key { source: None })
         } else {
             Ok(())
         }
@@ -99,7 +105,7 @@ impl fmt::Debug for PackHeader {
             .signature
             .iter()
             .position(|&b| b == 0)
-            .unwrap_or_else(|| self.signature.len());
+            .unwrap_or(self.signature.len());
         f.debug_struct("PackHeader")
             .field(
                 "signature",
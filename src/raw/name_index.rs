@@ -0,0 +1,94 @@
+use std::collections::hash_map::{DefaultHasher, Entry, HashMap};
+use std::hash::Hasher;
+
+use super::NoHashHasherBuilder;
+
+/// Maps the hashes of the case-folded names of a chain's entries to the
+/// indices of the entries carrying them. The names themselves are not stored
+/// here, so hits have to be verified against the chain.
+#[derive(Clone, Default)]
+pub(crate) struct NameIndex {
+    // the first entry of every hash
+    slots: HashMap<u64, u32, NoHashHasherBuilder>,
+    // all entries, in ascending order, of the hashes that are shared by more
+    // than one entry, either because of a collision or because of duplicate
+    // names which original archives do contain occasionally
+    shared: HashMap<u64, Vec<u32>, NoHashHasherBuilder>,
+}
+
+impl NameIndex {
+    /// The indices of the entries that might be called `name`, in ascending
+    /// order.
+    #[inline]
+    pub fn get(&self, name: &str) -> &[u32] {
+        let hash = hash(name);
+        match self.shared.get(&hash) {
+            Some(shared) => shared,
+            None => self.slots.get(&hash).map_or(&[], std::slice::from_ref),
+        }
+    }
+
+    pub fn insert(&mut self, name: &str, idx: usize) {
+        let hash = hash(name);
+        let idx = idx as u32;
+        match self.slots.entry(hash) {
+            Entry::Occupied(mut first) => {
+                let shared = self
+                    .shared
+                    .entry(hash)
+                    .or_insert_with(|| vec![*first.get()]);
+                if let Err(pos) = shared.binary_search(&idx) {
+                    shared.insert(pos, idx);
+                }
+                *first.get_mut() = shared[0];
+            }
+            Entry::Vacant(slot) => {
+                slot.insert(idx);
+            }
+        }
+    }
+
+    /// Removes the entry `idx` called `name`.
+    pub fn remove(&mut self, name: &str, idx: usize) {
+        let hash = hash(name);
+        let idx = idx as u32;
+        if let Entry::Occupied(mut shared) = self.shared.entry(hash) {
+            if let Ok(pos) = shared.get().binary_search(&idx) {
+                shared.get_mut().remove(pos);
+            }
+            self.slots.insert(hash, shared.get()[0]);
+            if shared.get().len() == 1 {
+                shared.remove();
+            }
+        } else if let Entry::Occupied(slot) = self.slots.entry(hash) {
+            if *slot.get() == idx {
+                slot.remove();
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.clear();
+        self.shared.clear();
+    }
+
+    /// The approximate amount of heap memory used by the index.
+    pub fn memory_usage(&self) -> usize {
+        // a key, a value and a control byte per bucket
+        self.slots.capacity() * (std::mem::size_of::<(u64, u32)>() + 1)
+            + self.shared.capacity() * (std::mem::size_of::<(u64, Vec<u32>)>() + 1)
+            + self
+                .shared
+                .values()
+                .map(|shared| shared.capacity() * std::mem::size_of::<u32>())
+                .sum::<usize>()
+    }
+}
+
+fn hash(name: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for byte in name.bytes() {
+        hasher.write_u8(byte.to_ascii_lowercase());
+    }
+    hasher.finish()
+}